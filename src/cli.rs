@@ -0,0 +1,377 @@
+use nalgebra as na;
+
+// Headless mode: renders a single frame of the scene with the CPU tracer and
+// writes it to a file, without opening a window.
+
+pub struct Options {
+    // None runs the interactive window instead
+    pub output: Option<std::string::String>,
+    pub width: usize,
+    pub height: usize,
+    pub settings: crate::trace::Settings,
+    // lens radius, 0 for a pinhole
+    pub aperture: f32,
+    // None auto focuses on the screen center
+    pub focus: Option<f32>,
+    pub bokeh: crate::gfx::camera::Bokeh,
+    // how long the shutter stays open, in simulation seconds
+    pub shutter: f32,
+    // image to cover the cubes in, in the window as well
+    pub texture: Option<std::string::String>,
+    pub wrap: crate::gfx::texture::Wrap,
+    pub texture_filter: crate::gfx::texture::Filter,
+    // panorama or cube map directory to light the scene with and show behind it
+    pub environment: Option<std::string::String>,
+    pub environment_intensity: f32,
+    // how linear radiance becomes 8-bit pixels, also the window's starting point
+    pub tonemap: crate::gfx::tonemap::Tonemap,
+    // extra layers to write with the color
+    pub aovs: std::vec::Vec<crate::trace::aov::Kind>,
+    pub object_id: crate::trace::aov::Id,
+    // filter the noise out of the color, guided by the albedo, normal and depth
+    pub denoise: bool,
+    // scattering per unit of distance of fog filling the scene, 0 for none
+    pub fog: f32,
+    pub fog_absorption: f32,
+    // Henyey-Greenstein asymmetry of the fog and smoke
+    pub fog_g: f32,
+    // scattering per unit of distance in the densest smoke, 0 for none
+    pub smoke: f32,
+}
+
+// what the command line asks for
+pub enum Command {
+    Run(Options),
+    // print USAGE and exit
+    Help,
+}
+
+pub const USAGE: &str = "usage: raytrace [-o FILE] [options]
+
+without -o, opens the interactive window
+
+  -o, --output FILE   render one frame to FILE (.png, .ppm, .pfm or .exr) and exit
+  --mode MODE         flat, path or whitted, also for the preview (default path)
+  --width N           (default 800)
+  --height N          (default 600)
+  --samples N         per pixel (default 16)
+  --depth N           max bounces (default 8)
+  --spectral          trace one wavelength per sample instead of RGB, so glass splits light
+                      into rainbows (path mode only, also in the window); hangs glass
+                      prisms over the swarm to show it off
+  --sampler NAME      independent, stratified, halton or sobol (default sobol)
+  --filter NAME       box, tent, gaussian, mitchell or lanczos (default box)
+  --filter-radius R   in pixels (default depends on the filter)
+  --aperture R        lens radius for depth of field (default 0, a pinhole)
+  --focus D           focus distance (default: whatever is at the screen center)
+  --blades N          polygonal bokeh with N blades (default round)
+  --bokeh-rotation A  turn the polygonal bokeh by A degrees (default 0)
+  --shutter T         keep the shutter open for T seconds, blurring moving cubes (default 0)
+  --texture FILE      cover the cubes in an image (.png, .jpg or .hdr), also in the window
+  --wrap MODE         repeat, clamp or mirror (default repeat)
+  --texture-filter F  nearest or bilinear (default bilinear)
+  --environment PATH  HDR panorama, or a directory of px, nx, py, ny, pz and nz cube faces,
+                      lighting the scene from all around, also in the window
+  --environment-intensity X
+                      scale the environment by X (default 1)
+  --tonemap NAME      clamp, reinhard, hable or aces (default clamp)
+  --exposure EV       brighten by EV stops before tone mapping, negative darkens (default 0)
+  --gamma G           encode with a plain 1/G power instead of the sRGB curve
+  --aov LIST          also write depth, normal, albedo, id and/or motion (comma separated,
+                      or all); as layers of an .exr, or else next to FILE as FILE.depth.png etc
+  --denoise           smooth out the noise of low sample counts, keeping edges and textures
+  --object-id BY      number the id layer by cube group or instance (default group)
+  --fog S             fill the scene with fog scattering S per unit of distance, lighting up
+                      shafts between the cubes (default 0, path mode only, also in the window)
+  --fog-absorption A  fog absorbing A per unit of distance (default 0)
+  --fog-g G           scatter forward (0 < G < 1) or back (-1 < G < 0) (default 0.3)
+  --smoke S           a cloud of noisy smoke through the swarm, scattering up to S (default 0)
+  --seed N            scene layout and sampling (default 0)
+  -h, --help          show this message";
+
+fn number<T: std::str::FromStr>(flag: &str, value: Option<std::string::String>) -> Result<T, std::string::String> {
+    let value = value.ok_or_else(|| format!("{} needs a value\n\n{}", flag, USAGE))?;
+    value.parse().map_err(|_| format!("{}: not a number: {}\n\n{}", flag, value, USAGE))
+}
+
+pub fn parse<I: Iterator<Item = std::string::String>>(mut args: I) -> Result<Command, std::string::String> {
+    let mut output = None;
+    let mut width = 800;
+    let mut height = 600;
+    let mut settings = crate::trace::settings();
+    settings.mode = crate::trace::Mode::Path;
+    settings.samples = 16;
+    let mut radius = None;
+    let mut aperture = 0.0;
+    let mut focus = None;
+    let mut blades = None;
+    // degrees
+    let mut rotation: f32 = 0.0;
+    let mut shutter = 0.0;
+    let mut texture = None;
+    let mut wrap = crate::gfx::texture::Wrap::Repeat;
+    let mut texture_filter = crate::gfx::texture::Filter::Bilinear;
+    let mut environment = None;
+    let mut environment_intensity = 1.0;
+    let mut tonemap = crate::gfx::tonemap::new();
+    let mut aovs = vec![];
+    let mut object_id = crate::trace::aov::Id::Group;
+    let mut denoise = false;
+    let mut fog = 0.0;
+    let mut fog_absorption = 0.0;
+    let mut fog_g = 0.3;
+    let mut smoke = 0.0;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                output = Some(args.next().ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))?);
+            },
+            "--mode" => {
+                settings.mode = match args.next().as_deref() {
+                    Some("flat") => crate::trace::Mode::Flat,
+                    Some("path") => crate::trace::Mode::Path,
+                    Some("whitted") => crate::trace::Mode::Whitted,
+                    m => return Err(format!("--mode: expected flat, path or whitted, got {:?}\n\n{}", m, USAGE)),
+                };
+            },
+            "--sampler" => {
+                let name = args.next().unwrap_or_default();
+                settings.sampler = crate::trace::sampler::kind(&name)
+                    .ok_or_else(|| format!("--sampler: expected independent, stratified, halton or sobol, got {:?}\n\n{}", name, USAGE))?;
+            },
+            "--filter" => {
+                let name = args.next().unwrap_or_default();
+                settings.filter = crate::trace::film::filter(&name)
+                    .ok_or_else(|| format!("--filter: expected box, tent, gaussian, mitchell or lanczos, got {:?}\n\n{}", name, USAGE))?;
+            },
+            "--filter-radius" => radius = Some(number(&arg, args.next())?),
+            "--aperture" => aperture = number(&arg, args.next())?,
+            "--focus" => focus = Some(number(&arg, args.next())?),
+            "--blades" => {
+                let n: u32 = number(&arg, args.next())?;
+                if n < 3 {
+                    return Err(format!("--blades: need at least 3\n\n{}", USAGE));
+                }
+                blades = Some(n);
+            },
+            "--bokeh-rotation" => rotation = number(&arg, args.next())?,
+            "--shutter" => shutter = number(&arg, args.next())?,
+            "--texture" => {
+                texture = Some(args.next().ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))?);
+            },
+            "--wrap" => {
+                wrap = match args.next().as_deref() {
+                    Some("repeat") => crate::gfx::texture::Wrap::Repeat,
+                    Some("clamp") => crate::gfx::texture::Wrap::Clamp,
+                    Some("mirror") => crate::gfx::texture::Wrap::Mirror,
+                    m => return Err(format!("--wrap: expected repeat, clamp or mirror, got {:?}\n\n{}", m, USAGE)),
+                };
+            },
+            "--texture-filter" => {
+                texture_filter = match args.next().as_deref() {
+                    Some("nearest") => crate::gfx::texture::Filter::Nearest,
+                    Some("bilinear") => crate::gfx::texture::Filter::Bilinear,
+                    m => return Err(format!("--texture-filter: expected nearest or bilinear, got {:?}\n\n{}", m, USAGE)),
+                };
+            },
+            "--environment" => {
+                environment = Some(args.next().ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))?);
+            },
+            "--environment-intensity" => environment_intensity = number(&arg, args.next())?,
+            "--tonemap" => {
+                let name = args.next().unwrap_or_default();
+                tonemap.operator = crate::gfx::tonemap::operator(&name)
+                    .ok_or_else(|| format!("--tonemap: expected clamp, reinhard, hable or aces, got {:?}\n\n{}", name, USAGE))?;
+            },
+            "--exposure" => tonemap.exposure = number(&arg, args.next())?,
+            "--gamma" => {
+                let gamma: f32 = number(&arg, args.next())?;
+                if gamma <= 0.0 {
+                    return Err(format!("--gamma must be positive\n\n{}", USAGE));
+                }
+                tonemap.gamma = Some(gamma);
+            },
+            "--aov" => {
+                let list = args.next().unwrap_or_default();
+                for name in list.split(',') {
+                    if name == "all" {
+                        aovs = crate::trace::aov::KINDS.to_vec();
+                        continue;
+                    }
+                    let kind = crate::trace::aov::kind(name)
+                        .ok_or_else(|| format!("--aov: expected depth, normal, albedo, id, motion or all, got {:?}\n\n{}", name, USAGE))?;
+                    if !aovs.contains(&kind) {
+                        aovs.push(kind);
+                    }
+                }
+            },
+            "--object-id" => {
+                object_id = match args.next().as_deref() {
+                    Some("group") => crate::trace::aov::Id::Group,
+                    Some("instance") => crate::trace::aov::Id::Instance,
+                    m => return Err(format!("--object-id: expected group or instance, got {:?}\n\n{}", m, USAGE)),
+                };
+            },
+            "--denoise" => denoise = true,
+            "--fog" => fog = number(&arg, args.next())?,
+            "--fog-absorption" => fog_absorption = number(&arg, args.next())?,
+            "--fog-g" => fog_g = number(&arg, args.next())?,
+            "--smoke" => smoke = number(&arg, args.next())?,
+            "--width" => width = number(&arg, args.next())?,
+            "--height" => height = number(&arg, args.next())?,
+            "--samples" => settings.samples = number(&arg, args.next())?,
+            "--depth" => settings.max_depth = number(&arg, args.next())?,
+            "--seed" => settings.seed = number(&arg, args.next())?,
+            "--spectral" => settings.spectral = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => return Err(format!("unknown argument: {}\n\n{}", arg, USAGE)),
+        }
+    }
+
+    let bokeh = match blades {
+        Some(blades) => crate::gfx::camera::Bokeh::Polygon{ blades, rotation: rotation.to_radians() },
+        None => crate::gfx::camera::Bokeh::Circle,
+    };
+
+    settings.filter_radius = radius.unwrap_or_else(|| crate::trace::film::default_radius(settings.filter));
+    if settings.filter_radius <= 0.0 {
+        return Err(format!("--filter-radius must be positive\n\n{}", USAGE));
+    }
+
+    if width == 0 || height == 0 {
+        return Err(format!("--width and --height must be positive\n\n{}", USAGE));
+    }
+
+    if fog < 0.0 || fog_absorption < 0.0 || smoke < 0.0 {
+        return Err(format!("--fog, --fog-absorption and --smoke can't be negative\n\n{}", USAGE));
+    }
+    if fog_g <= -1.0 || fog_g >= 1.0 {
+        return Err(format!("--fog-g must be between -1 and 1\n\n{}", USAGE));
+    }
+
+    Ok(Command::Run(Options{
+        output,
+        width,
+        height,
+        settings,
+        aperture,
+        focus,
+        bokeh,
+        shutter,
+        texture,
+        wrap,
+        texture_filter,
+        environment,
+        environment_intensity,
+        tonemap,
+        aovs,
+        object_id,
+        denoise,
+        fog,
+        fog_absorption,
+        fog_g,
+        smoke,
+    }))
+}
+
+// loads --texture, if one was given
+pub fn texture(options: &Options) -> Result<Option<std::sync::Arc<crate::gfx::texture::Texture>>, std::string::String> {
+    let path = match &options.texture {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut texture = crate::gfx::texture::load(path)?;
+    texture.wrap = options.wrap;
+    texture.filter = options.texture_filter;
+    Ok(Some(std::sync::Arc::new(texture)))
+}
+
+// loads --environment, if one was given
+pub fn environment(options: &Options) -> Result<Option<std::sync::Arc<crate::gfx::environment::Environment>>, std::string::String> {
+    match &options.environment {
+        Some(path) => Ok(Some(std::sync::Arc::new(crate::gfx::environment::load(path, options.environment_intensity)?))),
+        None => Ok(None),
+    }
+}
+
+// --fog, in a box taking in the swarm and the camera, and --smoke, in one just
+// around the swarm
+pub fn media(options: &Options) -> std::vec::Vec<crate::trace::medium::Medium> {
+    let mut media = vec![];
+
+    if options.fog > 0.0 || options.fog_absorption > 0.0 {
+        let bounds = crate::trace::aabb::Aabb{
+            min: na::Point3::new(-250.0, -250.0, -250.0),
+            max: na::Point3::new(250.0, 250.0, 250.0),
+        };
+        media.push(crate::trace::medium::fog(bounds, options.fog_absorption, options.fog, options.fog_g));
+    }
+
+    if options.smoke > 0.0 {
+        let bounds = crate::trace::aabb::Aabb{
+            min: na::Point3::new(-60.0, -60.0, -60.0),
+            max: na::Point3::new(60.0, 60.0, 60.0),
+        };
+        // billows of fbm, thinning out towards the edges of the box
+        let noise = crate::gfx::procedural::Pattern::Fbm{ noise: crate::gfx::procedural::Noise::Simplex, frequency: 3.0, octaves: 4 };
+        let grid = crate::trace::medium::grid(64, 64, 64, |q| {
+            let edge = (q.coords - na::Vector3::new(0.5, 0.5, 0.5)).magnitude() * 2.0;
+            (noise.eval(q)[0] - 0.45) * 4.0 * (1.0 - edge).max(0.0)
+        });
+        media.push(crate::trace::medium::volume(bounds, grid, 0.0, options.smoke, options.fog_g));
+    }
+
+    media
+}
+
+// renders from where the window's camera starts out
+pub fn render(options: &Options, output: &str, registry: &crate::gfx::geometry::Registry, cubes: &[crate::shapes::cube::Cube], solids: &[crate::shapes::solid::Solid], lights: &[crate::gfx::light::Light], environment: Option<std::sync::Arc<crate::gfx::environment::Environment>>) -> Result<(), std::string::String> {
+    let aspect = options.width as f32 / options.height as f32;
+    let mut camera = crate::gfx::camera::new(0.0, 0.0, -150.0, aspect, std::f32::consts::PI / 4.0);
+    camera.aperture = options.aperture;
+    camera.bokeh = options.bokeh;
+    match options.focus {
+        Some(d) => camera.focus_distance = d,
+        None => camera.auto_focus = true,
+    }
+    camera.shutter_close = options.shutter;
+
+    let mut scene = crate::trace::scene::new(registry, cubes, solids)?;
+    scene.lights = lights.to_vec();
+    scene.set_environment(environment);
+    scene.media = media(options);
+    scene.set_shutter(camera.shutter_open, camera.shutter_close);
+
+    let start = std::time::Instant::now();
+    let progress = |p: &crate::trace::tiles::Progress| {
+        eprint!("\r{:3}% ({}/{} tiles)", p.done * 100 / p.total, p.done, p.total);
+    };
+    let mut fb = crate::trace::render_tiles(&camera, &scene, &options.settings, options.width, options.height, &crate::trace::tiles::cancel(), progress)
+        .ok_or("render cancelled")?;
+
+    // the denoiser's guides are traced in the same pass as the asked for aovs,
+    // but only those get written
+    let mut kinds = options.aovs.clone();
+    if options.denoise {
+        for k in crate::trace::denoise::GUIDES.iter() {
+            if !kinds.contains(k) {
+                kinds.push(*k);
+            }
+        }
+    }
+    let mut layers = crate::trace::aov::render(&camera, &scene, &options.settings, options.width, options.height, &kinds, options.object_id, &crate::trace::tiles::cancel())
+        .ok_or("render cancelled")?;
+
+    if options.denoise {
+        fb = crate::trace::denoise::denoise(&fb, &layers, &crate::trace::denoise::settings())?;
+        layers.retain(|l| options.aovs.contains(&l.kind));
+    }
+    eprintln!("\rrendered {}x{} in {:.2}s", options.width, options.height, start.elapsed().as_secs_f32());
+
+    for file in crate::trace::output::write_all(&fb, &layers, output, &options.tonemap)?.iter() {
+        eprintln!("wrote {}", file);
+    }
+    Ok(())
+}
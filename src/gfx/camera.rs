@@ -1,9 +1,52 @@
 use nalgebra as na;
 
+#[derive(Clone, Copy)]
 pub struct Camera {
     pub phys: crate::physics::Physics,
     pub perspective: na::Matrix4<f32>,
     pub orientation: na::Matrix4<f32>,
+    // thin lens, only used by the CPU tracer; an aperture of 0 is a pinhole
+    pub aperture: f32,
+    // distance along the view direction that's in focus
+    pub focus_distance: f32,
+    pub bokeh: Bokeh,
+    // refocus on whatever is under the screen center before each render, see
+    // trace::focus
+    pub auto_focus: bool,
+    // the tracer casts each ray at a time in this interval, with the camera
+    // and everything else moved along by their velocities (motion blur). Times
+    // are in the simulation's seconds from its current state
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+// shape of the aperture, which is the shape out of focus highlights take
+#[derive(Clone, Copy)]
+pub enum Bokeh {
+    Circle,
+    // regular polygon with this many blades, turned by rotation radians
+    Polygon{ blades: u32, rotation: f32 },
+}
+
+impl Bokeh {
+    // uniform point on the aperture, scaled to fit the unit circle
+    pub fn sample(&self, u: f32, v: f32) -> (f32, f32) {
+        match *self {
+            Bokeh::Circle => crate::trace::sampling::concentric_disk(u, v),
+            Bokeh::Polygon{ blades, rotation } => {
+                // every blade's triangle (center and two corners) has the same area
+                let n = blades.max(3);
+                let k = ((u * n as f32) as u32).min(n - 1);
+                let u = u * n as f32 - k as f32;
+
+                let step = 2.0 * std::f32::consts::PI / n as f32;
+                let a0 = rotation + step * k as f32;
+                let a1 = a0 + step;
+                let (b1, b2) = crate::trace::sampling::uniform_triangle(u, v);
+                (b1 * a0.cos() + b2 * a1.cos(), b1 * a0.sin() + b2 * a1.sin())
+            },
+        }
+    }
 }
 
 pub fn new(x: f32, y: f32, z: f32, aspect: f32, fov: f32) -> Camera {
@@ -11,6 +54,12 @@ pub fn new(x: f32, y: f32, z: f32, aspect: f32, fov: f32) -> Camera {
         phys: crate::physics::new(x, y, z),
         perspective: na::Matrix4::new_perspective(aspect, fov, 1.0, -1.0),
         orientation: na::Matrix4::<f32>::identity(),
+        aperture: 0.0,
+        focus_distance: 1.0,
+        bokeh: Bokeh::Circle,
+        auto_focus: false,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
     }
 }
 
@@ -33,4 +82,133 @@ impl Camera {
             &y,
         )
     }
+
+    // s and t are normalized device coordinates in [-1, 1], +t is up
+    pub fn ray(&self, s: f32, t: f32) -> crate::gfx::ray::Ray {
+        let (x, y, z,) = self.phys.direction();
+
+        // look_at_rh puts the camera's +x on the left hand side of the screen
+        let sx = 1.0 / self.perspective[(0, 0)];
+        let sy = 1.0 / self.perspective[(1, 1)];
+        let dir = z - x * (s * sx) + y * (t * sy);
+
+        crate::gfx::ray::new(na::Point3::from(self.phys.pos), dir.normalize())
+    }
+
+    // the inverse of ray: normalized device coordinates of a world space
+    // point, or None if it's behind the camera
+    pub fn project(&self, p: &na::Point3<f32>) -> Option<(f32, f32)> {
+        let (x, y, z,) = self.phys.direction();
+        let d = p.coords - self.phys.pos;
+        let depth = d.dot(&z);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let sx = 1.0 / self.perspective[(0, 0)];
+        let sy = 1.0 / self.perspective[(1, 1)];
+        Some((-d.dot(&x) / (depth * sx), d.dot(&y) / (depth * sy)))
+    }
+
+    // where the camera will be `time` from now, going by its velocities
+    pub fn at(&self, time: f32) -> Camera {
+        let mut camera = *self;
+        camera.phys.move_(time);
+        camera
+    }
+
+    // like ray, but leaving from the point (u, v) picks on the lens and passing
+    // through the plane of focus where the pinhole ray would have
+    pub fn lens_ray(&self, s: f32, t: f32, u: f32, v: f32) -> crate::gfx::ray::Ray {
+        let pinhole = self.ray(s, t);
+        if self.aperture <= 0.0 {
+            return pinhole;
+        }
+
+        let (x, y, z,) = self.phys.direction();
+        let focus = pinhole.at(self.focus_distance / pinhole.dir.dot(&z));
+
+        let (lx, ly) = self.bokeh.sample(u, v);
+        let origin = pinhole.origin + (x * lx + y * ly) * self.aperture;
+        crate::gfx::ray::new(origin, (focus - origin).normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    fn camera() -> Camera {
+        let mut camera = new(1.0, 2.0, -50.0, 4.0 / 3.0, std::f32::consts::PI / 4.0);
+        camera.phys.rot = na::Vector3::new(0.1, -0.2, 0.0);
+        camera
+    }
+
+    #[test]
+    fn pinhole_lens_rays_are_the_plain_rays() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+        let mut camera = camera();
+        camera.focus_distance = 30.0;
+        camera.bokeh = Bokeh::Polygon{ blades: 6, rotation: 0.3 };
+
+        for _ in 0..100 {
+            let (s, t) = (rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+            let lens = camera.lens_ray(s, t, rng.gen(), rng.gen());
+            let pinhole = camera.ray(s, t);
+            assert_eq!(lens.origin, pinhole.origin);
+            assert_eq!(lens.dir, pinhole.dir);
+        }
+    }
+
+    #[test]
+    fn lens_rays_meet_on_the_plane_of_focus() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(2);
+        let mut camera = camera();
+        camera.aperture = 2.0;
+        camera.focus_distance = 30.0;
+        let (_, _, z) = camera.phys.direction();
+
+        for _ in 0..100 {
+            let (s, t) = (rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+            let pinhole = camera.ray(s, t);
+            let focus = pinhole.at(30.0 / pinhole.dir.dot(&z));
+
+            let lens = camera.lens_ray(s, t, rng.gen(), rng.gen());
+            let along = (focus - lens.origin).dot(&z) / lens.dir.dot(&z);
+            assert!((lens.at(along) - focus).magnitude() < 1e-3);
+            assert!((lens.origin - pinhole.origin).magnitude() <= 2.0 + 1e-4);
+        }
+    }
+
+    #[test]
+    fn bokeh_samples_stay_inside_the_aperture() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+
+        for _ in 0..1000 {
+            let (x, y) = Bokeh::Circle.sample(rng.gen(), rng.gen());
+            assert!(x * x + y * y <= 1.0 + 1e-5);
+        }
+
+        for &(blades, rotation) in [(3, 0.0), (5, 0.4), (6, -1.0), (8, 2.0)].iter() {
+            let bokeh = Bokeh::Polygon{ blades, rotation };
+            let step = 2.0 * std::f32::consts::PI / blades as f32;
+            let corner = |k: u32| {
+                let a = rotation + step * k as f32;
+                (a.cos(), a.sin())
+            };
+
+            for _ in 0..1000 {
+                let (x, y) = bokeh.sample(rng.gen(), rng.gen());
+                // on the inner side of every edge, going round counterclockwise
+                for k in 0..blades {
+                    let (ax, ay) = corner(k);
+                    let (bx, by) = corner(k + 1);
+                    let cross = (bx - ax) * (y - ay) - (by - ay) * (x - ax);
+                    assert!(cross >= -1e-5, "{:?} is outside {} blades turned by {}", (x, y), blades, rotation);
+                }
+            }
+        }
+    }
 }
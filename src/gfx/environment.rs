@@ -0,0 +1,247 @@
+use nalgebra as na;
+use gl::types::{GLfloat, GLint, GLvoid};
+
+// Light arriving from infinitely far away, looked up by direction: an HDR
+// panorama or six cube faces. The CPU tracer reads it directly (and importance
+// samples it, see trace::environment); GL gets it resampled into a cube map
+// whose mip levels are prefiltered for increasing roughness, used for the
+// skybox and for reflections.
+
+pub enum Map {
+    // equirectangular: longitude along u, +y at the top (v = 1)
+    Equirect(crate::gfx::texture::Texture),
+    // +x, -x, +y, -y, +z, -z, laid out the way GL cube maps are
+    Cubemap(std::vec::Vec<crate::gfx::texture::Texture>),
+}
+
+pub struct Environment {
+    pub map: Map,
+    // scales everything the map gives off
+    pub intensity: f32,
+    // the GL cube map's texels, per level and face, worked out by prefilter
+    levels: std::sync::OnceLock<std::vec::Vec<std::vec::Vec<std::vec::Vec<GLfloat>>>>,
+    // GL cube map name, made the first time the environment is bound
+    cubemap: std::sync::OnceLock<u32>,
+}
+
+// size of the GL cube map's faces at mip level 0
+pub const CUBEMAP_SIZE: usize = 128;
+// roughness goes from 0 at level 0 to 1 at the last level
+pub const CUBEMAP_LEVELS: usize = 6;
+// GGX samples per texel when prefiltering
+const PREFILTER_SAMPLES: u32 = 64;
+
+const FACES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+pub fn new(map: Map, intensity: f32) -> Environment {
+    Environment{ map, intensity, levels: std::sync::OnceLock::new(), cubemap: std::sync::OnceLock::new() }
+}
+
+// prefilters the GL cube map on a thread of its own, which takes a while, so
+// the window doesn't stall on it. Until it's done the environment isn't ready
+// and GL goes without it
+pub fn prefilter(environment: &std::sync::Arc<Environment>) {
+    let e = environment.clone();
+    std::thread::spawn(move || {
+        let _ = e.levels.set(e.prefilter_levels());
+    });
+}
+
+// a panorama, or a directory holding px, nx, py, ny, pz and nz images (.hdr,
+// .png or .jpg) for the cube faces
+pub fn load(path: &str, intensity: f32) -> Result<Environment, std::string::String> {
+    if !std::path::Path::new(path).is_dir() {
+        let mut texture = crate::gfx::texture::load(path)?;
+        texture.wrap = crate::gfx::texture::Wrap::Repeat;
+        return Ok(new(Map::Equirect(texture), intensity));
+    }
+
+    let mut faces = vec![];
+    for face in FACES.iter() {
+        let file = ["hdr", "png", "jpg", "jpeg"].iter()
+            .map(|ext| std::path::Path::new(path).join(format!("{}.{}", face, ext)))
+            .find(|f| f.is_file())
+            .ok_or_else(|| format!("{}: no {} face (.hdr, .png or .jpg)", path, face))?;
+
+        let mut texture = crate::gfx::texture::load(&file.to_string_lossy())?;
+        texture.wrap = crate::gfx::texture::Wrap::Clamp;
+        faces.push(texture);
+    }
+
+    Ok(new(Map::Cubemap(faces), intensity))
+}
+
+// unit direction through (u, v) of an equirectangular map
+pub fn equirect_direction(u: f32, v: f32) -> na::Vector3<f32> {
+    let theta = (1.0 - v) * std::f32::consts::PI;
+    let phi = (u - 0.5) * 2.0 * std::f32::consts::PI;
+    na::Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+}
+
+pub fn equirect_uv(dir: &na::Vector3<f32>) -> na::Point2<f32> {
+    let d = dir.normalize();
+    let theta = d.y.clamp(-1.0, 1.0).acos();
+    let phi = d.z.atan2(d.x);
+    na::Point2::new(phi / (2.0 * std::f32::consts::PI) + 0.5, 1.0 - theta * std::f32::consts::FRAC_1_PI)
+}
+
+// direction through (s, t) of a cube face, from the table in the GL spec;
+// t = 0 is the first row GL is given
+pub fn face_direction(face: usize, s: f32, t: f32) -> na::Vector3<f32> {
+    let (sc, tc) = (2.0 * s - 1.0, 2.0 * t - 1.0);
+    let d = match face {
+        0 => na::Vector3::new(1.0, -tc, -sc),
+        1 => na::Vector3::new(-1.0, -tc, sc),
+        2 => na::Vector3::new(sc, 1.0, tc),
+        3 => na::Vector3::new(sc, -1.0, -tc),
+        4 => na::Vector3::new(sc, -tc, 1.0),
+        _ => na::Vector3::new(-sc, -tc, -1.0),
+    };
+    d.normalize()
+}
+
+// the face a direction points at, and (s, t) on it
+pub fn face_of(dir: &na::Vector3<f32>) -> (usize, f32, f32) {
+    let a = dir.abs();
+    let (face, sc, tc, ma) = if a.x >= a.y && a.x >= a.z {
+        if dir.x > 0.0 { (0, -dir.z, -dir.y, a.x) } else { (1, dir.z, -dir.y, a.x) }
+    } else if a.y >= a.z {
+        if dir.y > 0.0 { (2, dir.x, dir.z, a.y) } else { (3, dir.x, -dir.z, a.y) }
+    } else {
+        if dir.z > 0.0 { (4, dir.x, -dir.y, a.z) } else { (5, -dir.x, -dir.y, a.z) }
+    };
+    (face, 0.5 * (sc / ma + 1.0), 0.5 * (tc / ma + 1.0))
+}
+
+// GGX distributed half vector around +z, from Hammersley point i of n
+fn ggx_sample(i: u32, n: u32, alpha: f32) -> na::Vector3<f32> {
+    let u = (i as f32 + 0.5) / n as f32;
+    let v = i.reverse_bits() as f32 / 4_294_967_296.0;
+    let cos_theta = ((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * v;
+    na::Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+impl Environment {
+    pub fn radiance(&self, dir: &na::Vector3<f32>) -> crate::gfx::Color {
+        let c = match &self.map {
+            Map::Equirect(t) => {
+                // wraps around in longitude, but the poles mustn't wrap to each other
+                let uv = equirect_uv(dir);
+                let half = 0.5 / t.height as f32;
+                t.sample(&na::Point2::new(uv.x, uv.y.clamp(half, 1.0 - half)))
+            },
+            Map::Cubemap(faces) => {
+                // textures keep the bottom row first, GL's faces the top
+                let (face, s, t) = face_of(dir);
+                faces[face].sample(&na::Point2::new(s, 1.0 - t))
+            },
+        };
+        [c[0] * self.intensity, c[1] * self.intensity, c[2] * self.intensity]
+    }
+
+    // average radiance over a lobe of the given roughness around dir, the way
+    // a GGX reflection with the viewer along dir would see it (split sum, n = v = r)
+    pub fn prefiltered(&self, dir: &na::Vector3<f32>, roughness: f32) -> crate::gfx::Color {
+        if roughness <= 0.0 {
+            return self.radiance(dir);
+        }
+
+        let frame = crate::trace::sampling::frame(dir);
+        let alpha = roughness * roughness;
+        let mut sum = na::Vector3::zeros();
+        let mut weight = 0.0;
+
+        for i in 0..PREFILTER_SAMPLES {
+            let h = ggx_sample(i, PREFILTER_SAMPLES, alpha);
+            // reflect +z about h
+            let l = h * (2.0 * h.z) - na::Vector3::z();
+            if l.z <= 0.0 {
+                continue;
+            }
+            let c = self.radiance(&frame.to_world(&l));
+            sum += na::Vector3::new(c[0], c[1], c[2]) * l.z;
+            weight += l.z;
+        }
+
+        let c = sum / weight.max(1e-6);
+        [c.x, c.y, c.z]
+    }
+
+    // whether prefilter has finished, and the cube map can be bound
+    pub fn ready(&self) -> bool {
+        self.levels.get().is_some()
+    }
+
+    // binds the prefiltered cube map to the active texture unit, uploading it
+    // the first time; does nothing until the environment is ready
+    pub fn bind(&self) {
+        let levels = match self.levels.get() {
+            Some(levels) => levels,
+            None => return,
+        };
+        let name = *self.cubemap.get_or_init(|| upload(levels));
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, name);
+        }
+    }
+
+    // texels of every face of every level of the cube map, level 0 first;
+    // roughness goes up with the level
+    fn prefilter_levels(&self) -> std::vec::Vec<std::vec::Vec<std::vec::Vec<GLfloat>>> {
+        (0..CUBEMAP_LEVELS).map(|level| {
+            let size = (CUBEMAP_SIZE >> level).max(1);
+            let roughness = level as f32 / (CUBEMAP_LEVELS - 1) as f32;
+
+            (0..6).map(|face| {
+                let mut data: std::vec::Vec<GLfloat> = std::vec::Vec::with_capacity(size * size * 3);
+                for y in 0..size {
+                    for x in 0..size {
+                        let dir = face_direction(face, (x as f32 + 0.5) / size as f32, (y as f32 + 0.5) / size as f32);
+                        data.extend_from_slice(&self.prefiltered(&dir, roughness));
+                    }
+                }
+                data
+            }).collect()
+        }).collect()
+    }
+}
+
+fn upload(levels: &[std::vec::Vec<std::vec::Vec<GLfloat>>]) -> u32 {
+    let mut name = 0;
+    unsafe {
+        gl::GenTextures(1, &mut name);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, name);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAX_LEVEL, (CUBEMAP_LEVELS - 1) as GLint);
+        gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+    }
+
+    for (level, faces) in levels.iter().enumerate() {
+        let size = (CUBEMAP_SIZE >> level).max(1);
+
+        for (face, data) in faces.iter().enumerate() {
+            unsafe {
+                gl::TexImage2D(
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_X + face as u32,
+                    level as GLint,
+                    gl::RGB32F as GLint,
+                    size as GLint,
+                    size as GLint,
+                    0,
+                    gl::RGB,
+                    gl::FLOAT,
+                    data.as_ptr() as *const GLvoid,
+                );
+            }
+        }
+    }
+
+    name
+}
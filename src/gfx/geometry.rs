@@ -0,0 +1,134 @@
+use gl::types::{GLfloat, GLsizeiptr, GLvoid};
+
+// Geometry shared between every object drawn with it. Each distinct mesh is
+// stored once, under a name, and objects refer to it by handle with their own
+// transform. The GL vertex buffers are shared too: one per mesh and set of
+// vertex colors, which for textured, procedural and solid colored materials is
+// one per mesh and color.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Handle(usize);
+
+pub struct Geometry {
+    pub mesh: crate::gfx::Mesh,
+    // one per triangle in mesh
+    pub uvs: crate::gfx::Uvs,
+}
+
+// a vertex array ready to draw; both names are 0 without a GL context
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub vao: u32,
+    pub vbo: u32,
+    // vertices
+    pub count: i32,
+}
+
+pub struct Registry {
+    geometries: std::vec::Vec<Geometry>,
+    names: std::collections::HashMap<std::string::String, Handle>,
+    // keyed by the bits of every vertex's color
+    buffers: std::collections::HashMap<(Handle, std::vec::Vec<[u32; 3]>), Buffer>,
+}
+
+pub fn new() -> Registry {
+    Registry{
+        geometries: vec![],
+        names: std::collections::HashMap::new(),
+        buffers: std::collections::HashMap::new(),
+    }
+}
+
+impl Registry {
+    // the geometry registered under name, calling build to make it the first
+    // time; uvs must have one entry per triangle of the mesh
+    pub fn get_or_add<F>(&mut self, name: &str, build: F) -> Handle
+    where
+        F: FnOnce() -> (crate::gfx::Mesh, crate::gfx::Uvs),
+    {
+        if let Some(&handle) = self.names.get(name) {
+            return handle;
+        }
+
+        let (mesh, uvs) = build();
+        assert_eq!(mesh.len(), uvs.len(), "one set of uvs per triangle");
+
+        let handle = Handle(self.geometries.len());
+        self.geometries.push(Geometry{ mesh, uvs });
+        self.names.insert(name.to_string(), handle);
+        handle
+    }
+
+    pub fn get(&self, handle: Handle) -> &Geometry {
+        &self.geometries[handle.0]
+    }
+
+    // the vertex buffer for the geometry colored by albedo, uploading it the
+    // first time that combination is asked for
+    pub fn buffer(&mut self, handle: Handle, albedo: &crate::gfx::material::Albedo) -> Buffer {
+        let count = self.get(handle).mesh.len() as i32 * 3;
+        let colors: std::vec::Vec<crate::gfx::Color> = (0..count).map(|i| albedo.at(i)).collect();
+        let key = (handle, colors.iter().map(|c| [c[0].to_bits(), c[1].to_bits(), c[2].to_bits()]).collect());
+
+        if let Some(&b) = self.buffers.get(&key) {
+            return b;
+        }
+
+        let b = self.upload(handle, &colors);
+        self.buffers.insert(key, b);
+        b
+    }
+
+    fn upload(&self, handle: Handle, colors: &[crate::gfx::Color]) -> Buffer {
+        let g = self.get(handle);
+        let count = g.mesh.len() as i32 * 3;
+
+        // no GL context (ie, the CPU tracer on a headless machine), nothing to upload
+        if !gl::GenVertexArrays::is_loaded() || count == 0 {
+            return Buffer{ vao: 0, vbo: 0, count };
+        }
+
+        let v = vertices(g, colors);
+        let mut vao = 0;
+        let mut vbo = 0;
+
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::GenBuffers(1, &mut vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                (v.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
+                v.as_ptr() as *const GLvoid,
+                gl::STATIC_DRAW,
+            );
+        }
+
+        Buffer{ vao, vbo, count }
+    }
+}
+
+// interleaved position, color and uv, vertex by vertex
+fn vertices(g: &Geometry, colors: &[crate::gfx::Color]) -> std::vec::Vec<GLfloat> {
+    let mut v: std::vec::Vec<GLfloat> = std::vec::Vec::with_capacity(g.mesh.len() * 3 * 8);
+
+    let mut counter = 0;
+    for (t, uv) in g.mesh.iter().zip(g.uvs.iter()) {
+        for (p, uv) in t.iter().zip(uv.iter()) {
+            let color = colors[counter];
+            v.extend_from_slice(&[
+                p.x as GLfloat,
+                p.y as GLfloat,
+                p.z as GLfloat,
+                color[0] as GLfloat,
+                color[1] as GLfloat,
+                color[2] as GLfloat,
+                uv.x as GLfloat,
+                uv.y as GLfloat,
+            ]);
+            counter += 1;
+        }
+    }
+
+    v
+}
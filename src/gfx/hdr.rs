@@ -0,0 +1,102 @@
+use gl::types::{GLint, GLsizei};
+
+// Offscreen float framebuffer the window renders into, so lighting keeps its
+// full range until the tone mapping pass (gfx::quad with a gfx::tonemap).
+// Drawing goes into a multisampled buffer, which resolve() averages into a
+// plain texture the quad can sample.
+pub struct Target {
+    pub width: i32,
+    pub height: i32,
+    framebuffer: u32,
+    resolved: u32,
+    texture: u32,
+}
+
+// antialiasing samples per pixel, or as many as the driver allows if that's fewer
+pub const SAMPLES: i32 = 8;
+
+pub fn new(width: i32, height: i32) -> Result<Target, std::string::String> {
+    let mut framebuffer = 0;
+    let mut color = 0;
+    let mut depth = 0;
+    let mut resolved = 0;
+    let mut texture = 0;
+
+    unsafe {
+        let mut max_samples = 0;
+        gl::GetIntegerv(gl::MAX_SAMPLES, &mut max_samples);
+        let samples = SAMPLES.min(max_samples);
+
+        gl::GenFramebuffers(1, &mut framebuffer);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+
+        gl::GenRenderbuffers(1, &mut color);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, color);
+        gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples as GLsizei, gl::RGBA16F, width, height);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, color);
+
+        gl::GenRenderbuffers(1, &mut depth);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, depth);
+        gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples as GLsizei, gl::DEPTH_COMPONENT24, width, height);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth);
+
+        if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            return Err("multisampled HDR framebuffer is incomplete".to_string());
+        }
+
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA16F as GLint, width, height, 0, gl::RGBA, gl::FLOAT, std::ptr::null());
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        gl::GenFramebuffers(1, &mut resolved);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, resolved);
+        gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, texture, 0);
+
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err("resolved HDR framebuffer is incomplete".to_string());
+        }
+    }
+
+    Ok(Target{ width, height, framebuffer, resolved, texture })
+}
+
+impl Target {
+    // everything drawn after this lands in the target, until unbind
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+            gl::Viewport(0, 0, self.width, self.height);
+        }
+    }
+
+    // back to drawing on the window
+    pub fn unbind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // averages the samples into texture()
+    pub fn resolve(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.resolved);
+            gl::BlitFramebuffer(0, 0, self.width, self.height, 0, 0, self.width, self.height, gl::COLOR_BUFFER_BIT, gl::NEAREST);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // linear radiance as of the last resolve
+    pub fn texture(&self) -> u32 {
+        self.texture
+    }
+}
+
@@ -0,0 +1,317 @@
+use nalgebra as na;
+use std::ffi::CString;
+use gl::types::GLint;
+
+// Lights shared by the GL renderer (as uniforms, see fragment.glsl) and the
+// CPU tracer, which also casts shadows from them.
+
+// fragment.glsl has room for this many
+pub const MAX_LIGHTS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Point{ position: na::Point3<f32> },
+    // direction the light travels in
+    Directional{ direction: na::Vector3<f32> },
+    // inner and outer are cone half angles in radians, the light fades out between them
+    Spot{ position: na::Point3<f32>, direction: na::Vector3<f32>, inner: f32, outer: f32 },
+    // one sided parallelogram, emitting towards u x v
+    Area{ corner: na::Point3<f32>, u: na::Vector3<f32>, v: na::Vector3<f32> },
+}
+
+// color * intensity is radiant intensity for point and spot lights, irradiance
+// for directional lights, and radiance for area lights
+#[derive(Clone, Copy)]
+pub struct Light {
+    pub kind: Kind,
+    pub color: crate::gfx::Color,
+    pub intensity: f32,
+}
+
+pub fn point(x: f32, y: f32, z: f32, color: crate::gfx::Color, intensity: f32) -> Light {
+    Light{ kind: Kind::Point{ position: na::Point3::new(x, y, z) }, color, intensity }
+}
+
+pub fn directional(direction: na::Vector3<f32>, color: crate::gfx::Color, intensity: f32) -> Light {
+    Light{ kind: Kind::Directional{ direction: direction.normalize() }, color, intensity }
+}
+
+pub fn spot(position: na::Point3<f32>, direction: na::Vector3<f32>, inner: f32, outer: f32, color: crate::gfx::Color, intensity: f32) -> Light {
+    Light{
+        kind: Kind::Spot{ position, direction: direction.normalize(), inner, outer },
+        color,
+        intensity,
+    }
+}
+
+// the rectangle's front (+z before its rotation) emits
+pub fn area(rect: &crate::shapes::rectangle::Rectangle, color: crate::gfx::Color, intensity: f32) -> Light {
+    // rectangles are all the unit square, sized by their scale
+    let mat = rect.gfx.mat_model(&rect.phys);
+    let corner = mat.transform_point(&na::Point3::new(-0.5, -0.5, 0.0));
+    Light{
+        kind: Kind::Area{
+            corner,
+            u: mat.transform_point(&na::Point3::new(0.5, -0.5, 0.0)) - corner,
+            v: mat.transform_point(&na::Point3::new(-0.5, 0.5, 0.0)) - corner,
+        },
+        color,
+        intensity,
+    }
+}
+
+// light arriving at a point, as sampled by Light::sample
+pub struct Incident {
+    // towards the light
+    pub wi: na::Vector3<f32>,
+    pub dist: f32,
+    pub radiance: na::Vector3<f32>,
+    // solid angle density; 1 for delta lights
+    pub pdf: f32,
+    // point, directional and spot lights can't be hit by a ray
+    pub delta: bool,
+}
+
+fn smoothstep(lo: f32, hi: f32, x: f32) -> f32 {
+    if lo >= hi {
+        return if x >= hi { 1.0 } else { 0.0 };
+    }
+    let t = ((x - lo) / (hi - lo)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl Light {
+    pub fn power(&self) -> na::Vector3<f32> {
+        na::Vector3::new(self.color[0], self.color[1], self.color[2]) * self.intensity
+    }
+
+    pub fn is_delta(&self) -> bool {
+        !matches!(self.kind, Kind::Area{ .. })
+    }
+
+    pub fn area(&self) -> f32 {
+        match self.kind {
+            Kind::Area{ u, v, .. } => u.cross(&v).magnitude(),
+            _ => 0.0,
+        }
+    }
+
+    // u and v pick the point on area lights, they're ignored by the others
+    pub fn sample(&self, p: &na::Point3<f32>, su: f32, sv: f32) -> Option<Incident> {
+        match self.kind {
+            Kind::Point{ position } => {
+                let d = position - p;
+                let dist = d.magnitude();
+                Some(Incident{ wi: d / dist, dist, radiance: self.power() / (dist * dist), pdf: 1.0, delta: true })
+            },
+            Kind::Directional{ direction } => {
+                Some(Incident{ wi: -direction, dist: std::f32::INFINITY, radiance: self.power(), pdf: 1.0, delta: true })
+            },
+            Kind::Spot{ position, direction, inner, outer } => {
+                let d = position - p;
+                let dist = d.magnitude();
+                let wi = d / dist;
+                let falloff = smoothstep(outer.cos(), inner.cos(), (-wi).dot(&direction));
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(Incident{ wi, dist, radiance: self.power() * (falloff / (dist * dist)), pdf: 1.0, delta: true })
+            },
+            Kind::Area{ corner, u, v } => {
+                let q = corner + u * su + v * sv;
+                let d = q - p;
+                let dist = d.magnitude();
+                let wi = d / dist;
+                let pdf = self.pdf(p, &wi, dist);
+                if pdf <= 0.0 {
+                    return None;
+                }
+                Some(Incident{ wi, dist, radiance: self.power(), pdf, delta: false })
+            },
+        }
+    }
+
+    // solid angle density of Light::sample choosing the point dist along wi from p
+    pub fn pdf(&self, _p: &na::Point3<f32>, wi: &na::Vector3<f32>, dist: f32) -> f32 {
+        match self.kind {
+            Kind::Area{ u, v, .. } => {
+                let n = u.cross(&v);
+                let area = n.magnitude();
+                // wi points at the light, so its front faces back along -wi
+                let cos = -wi.dot(&n) / area;
+                if cos <= 0.0 || area <= 0.0 {
+                    return 0.0;
+                }
+                dist * dist / (cos * area)
+            },
+            _ => 0.0,
+        }
+    }
+
+    // area lights only; distance to where the ray crosses the front of the light
+    pub fn intersect(&self, ray: &crate::gfx::ray::Ray) -> Option<f32> {
+        match self.kind {
+            Kind::Area{ corner, u, v } => {
+                let n = u.cross(&v);
+                let denom = n.dot(&ray.dir);
+                // parallel, or arriving at the back
+                if denom >= 0.0 {
+                    return None;
+                }
+
+                let t = n.dot(&(corner - ray.origin)) / denom;
+                if t <= 0.0 || t >= ray.t_max {
+                    return None;
+                }
+
+                // coordinates of the crossing along u and v
+                let d = ray.at(t) - corner;
+                let a = d.dot(&u) / u.magnitude_squared();
+                let b = d.dot(&v) / v.magnitude_squared();
+                if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+                    return None;
+                }
+                Some(t)
+            },
+            _ => None,
+        }
+    }
+}
+
+// sets the light uniforms in fragment.glsl; lights past MAX_LIGHTS are dropped.
+// area lights are approximated as a point at their center, facing their front
+pub fn upload(program: crate::gfx::shader::Program, lights: &[Light]) {
+    let count = lights.len().min(MAX_LIGHTS);
+
+    unsafe {
+        let count_id = CString::new("lightCount").expect("CString::new failed");
+        gl::Uniform1i(gl::GetUniformLocation(program, count_id.as_ptr()), count as GLint);
+
+        for (i, l) in lights.iter().take(count).enumerate() {
+            let (kind, position, direction, cone) = match l.kind {
+                Kind::Point{ position } => (0, position, na::Vector3::zeros(), (0.0, 0.0)),
+                Kind::Directional{ direction } => (1, na::Point3::origin(), direction, (0.0, 0.0)),
+                Kind::Spot{ position, direction, inner, outer } => (2, position, direction, (inner.cos(), outer.cos())),
+                Kind::Area{ corner, u, v } => (3, corner + (u + v) * 0.5, u.cross(&v).normalize(), (0.0, 0.0)),
+            };
+
+            // area lights give off radiance * area in total, towards their front
+            let power = if kind == 3 { l.power() * l.area() } else { l.power() };
+
+            let kind_id = CString::new(format!("lightKind[{}]", i)).expect("CString::new failed");
+            gl::Uniform1i(gl::GetUniformLocation(program, kind_id.as_ptr()), kind);
+
+            let position_id = CString::new(format!("lightPosition[{}]", i)).expect("CString::new failed");
+            gl::Uniform3f(gl::GetUniformLocation(program, position_id.as_ptr()), position.x, position.y, position.z);
+
+            let direction_id = CString::new(format!("lightDirection[{}]", i)).expect("CString::new failed");
+            gl::Uniform3f(gl::GetUniformLocation(program, direction_id.as_ptr()), direction.x, direction.y, direction.z);
+
+            let color_id = CString::new(format!("lightColor[{}]", i)).expect("CString::new failed");
+            gl::Uniform3f(gl::GetUniformLocation(program, color_id.as_ptr()), power.x, power.y, power.z);
+
+            let cone_id = CString::new(format!("lightCone[{}]", i)).expect("CString::new failed");
+            gl::Uniform2f(gl::GetUniformLocation(program, cone_id.as_ptr()), cone.0, cone.1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_falls_off_with_the_square_of_distance() {
+        let light = point(0.0, 10.0, 0.0, [1.0, 0.5, 0.25], 8.0);
+        let near = light.sample(&na::Point3::new(0.0, 8.0, 0.0), 0.5, 0.5).unwrap();
+        let far = light.sample(&na::Point3::new(0.0, 6.0, 0.0), 0.5, 0.5).unwrap();
+
+        assert!(near.delta);
+        assert_eq!(near.pdf, 1.0);
+        assert!((near.wi - na::Vector3::y()).magnitude() < 1e-6);
+        assert!((near.dist - 2.0).abs() < 1e-6);
+        assert!((near.radiance - na::Vector3::new(2.0, 1.0, 0.5)).magnitude() < 1e-6);
+        assert!((far.radiance * 4.0 - near.radiance).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn spot_fades_between_its_cones() {
+        // pointing straight down from 10 up, full out to 20 degrees, dark past 30
+        let light = spot(na::Point3::new(0.0, 10.0, 0.0), -na::Vector3::y(), 20f32.to_radians(), 30f32.to_radians(), [1.0, 1.0, 1.0], 100.0);
+        // a point on the ground at the given angle off the spot's axis
+        let at = |degrees: f32| {
+            let p = na::Point3::new(10.0 * degrees.to_radians().tan(), 0.0, 0.0);
+            light.sample(&p, 0.5, 0.5).map(|i| i.radiance.x * i.dist * i.dist)
+        };
+
+        assert!((at(0.0).unwrap() - 100.0).abs() < 1e-3);
+        assert!((at(15.0).unwrap() - 100.0).abs() < 1e-3);
+        let (a, b) = (at(22.0).unwrap(), at(28.0).unwrap());
+        assert!(a < 100.0 && b < a && b > 0.0, "{} {}", a, b);
+        assert!(at(35.0).is_none());
+    }
+
+    // 4 x 2, 10 up, facing down
+    fn panel() -> Light {
+        let kind = Kind::Area{ corner: na::Point3::new(-2.0, 10.0, -1.0), u: na::Vector3::new(4.0, 0.0, 0.0), v: na::Vector3::new(0.0, 0.0, 2.0) };
+        Light{ kind, color: [1.0, 1.0, 1.0], intensity: 3.0 }
+    }
+
+    #[test]
+    fn area_samples_come_with_their_pdf() {
+        let light = panel();
+        assert!(!light.is_delta());
+        assert!((light.area() - 8.0).abs() < 1e-6);
+
+        let p = na::Point3::new(1.0, 0.0, 0.5);
+        for &(su, sv) in [(0.5, 0.5), (0.1, 0.9), (0.8, 0.3)].iter() {
+            let incident = light.sample(&p, su, sv).unwrap();
+            assert!(!incident.delta);
+            assert_eq!(incident.radiance, light.power());
+            assert!((incident.pdf - light.pdf(&p, &incident.wi, incident.dist)).abs() < 1e-6);
+
+            // the sampled point is on the light, and a ray towards it crosses there
+            let ray = crate::gfx::ray::new(p, incident.wi);
+            assert!((ray.at(incident.dist).y - 10.0).abs() < 1e-4);
+            assert!((light.intersect(&ray).unwrap() - incident.dist).abs() < 1e-4);
+        }
+
+        // straight below the center: dist^2 / (cos * area)
+        let below = light.sample(&na::Point3::new(0.0, 0.0, 0.0), 0.5, 0.5).unwrap();
+        assert!((below.pdf - 100.0 / 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn area_pdf_integrates_to_one_over_the_light() {
+        // summing the pdf over a grid of equal solid angle cells around the up
+        // axis, wherever a ray through the cell hits the light, comes out at 1
+        let light = panel();
+        let p = na::Point3::new(1.0, 0.0, 0.5);
+        // the light is well within 25 degrees of straight up from p
+        let (min_cos, rings, segments) = (0.9f32, 200, 1000);
+        let cell = 2.0 * std::f32::consts::PI * (1.0 - min_cos) / (rings * segments) as f32;
+
+        let mut total = 0.0;
+        for i in 0..rings {
+            let cos = min_cos + (1.0 - min_cos) * (i as f32 + 0.5) / rings as f32;
+            let sin = (1.0 - cos * cos).sqrt();
+            for j in 0..segments {
+                let phi = 2.0 * std::f32::consts::PI * (j as f32 + 0.5) / segments as f32;
+                let wi = na::Vector3::new(sin * phi.cos(), cos, sin * phi.sin());
+                if let Some(dist) = light.intersect(&crate::gfx::ray::new(p, wi)) {
+                    total += light.pdf(&p, &wi, dist) * cell;
+                }
+            }
+        }
+        assert!((total - 1.0).abs() < 0.01, "{}", total);
+    }
+
+    #[test]
+    fn area_lights_only_shine_from_their_front() {
+        let light = panel();
+        assert!(light.sample(&na::Point3::new(0.0, 20.0, 0.0), 0.5, 0.5).is_none());
+        assert!(light.intersect(&crate::gfx::ray::new(na::Point3::new(0.0, 20.0, 0.0), -na::Vector3::y())).is_none());
+        // outside the parallelogram
+        assert!(light.intersect(&crate::gfx::ray::new(na::Point3::new(5.0, 0.0, 0.0), na::Vector3::y())).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+// Surface description shared by the GL renderer (as uniforms) and the CPU
+// tracer (as a bsdf).
+
+#[derive(Clone)]
+pub enum Albedo {
+    Solid(crate::gfx::Color),
+    // the old per-vertex color function, indexed by vertex
+    Vertex(crate::gfx::ColorFn),
+    // looked up by the mesh's uvs; shared, since many shapes use the same image
+    Texture(std::sync::Arc<crate::gfx::texture::Texture>),
+    // evaluated at the hit point in the mesh's own space
+    Procedural(std::sync::Arc<crate::gfx::procedural::Pattern>),
+}
+
+impl Albedo {
+    // textures and patterns are white here, they're applied per pixel instead
+    pub fn at(&self, vertex: i32) -> crate::gfx::Color {
+        match self {
+            Albedo::Solid(c) => *c,
+            Albedo::Vertex(f) => f(vertex),
+            Albedo::Texture(_) | Albedo::Procedural(_) => [1.0, 1.0, 1.0],
+        }
+    }
+
+    pub fn texture(&self) -> Option<&crate::gfx::texture::Texture> {
+        match self {
+            Albedo::Texture(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn pattern(&self) -> Option<&crate::gfx::procedural::Pattern> {
+        match self {
+            Albedo::Procedural(p) => Some(p),
+            _ => None,
+        }
+    }
+}
+
+// index of refraction, which for real glass falls off from blue to red; only
+// the spectral tracer sees it change, everything else uses it at the d line
+#[derive(Clone, Copy, Debug)]
+pub enum Ior {
+    Constant(f32),
+    // n = a + b / λ², with λ in micrometers
+    Cauchy{ a: f32, b: f32 },
+    // n² = 1 + Σ b λ² / (λ² - c), with λ in micrometers
+    Sellmeier{ b: [f32; 3], c: [f32; 3] },
+}
+
+// Schott's coefficients for borosilicate crown glass, n = 1.517
+pub const BK7: Ior = Ior::Sellmeier{
+    b: [1.039_612, 0.231_792_34, 1.010_469_4],
+    c: [0.006_000_699, 0.020_017_914, 103.560_65],
+};
+
+// and for dense flint, n = 1.785, spreading colors nearly four times as far
+pub const SF11: Ior = Ior::Sellmeier{
+    b: [1.737_597, 0.313_747_35, 1.898_781],
+    c: [0.013_188_707, 0.062_306_814, 155.236_3],
+};
+
+// the yellow helium line glass catalogs quote n at
+pub const D_LINE: f32 = 587.6;
+
+impl Ior {
+    // at a wavelength in nanometers
+    pub fn at(&self, wavelength: f32) -> f32 {
+        let l = wavelength / 1000.0;
+        let l2 = l * l;
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy{ a, b } => a + b / l2,
+            Ior::Sellmeier{ b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            },
+        }
+    }
+
+    pub fn d(&self) -> f32 {
+        self.at(D_LINE)
+    }
+}
+
+#[derive(Clone)]
+pub enum Material {
+    Lambertian{ albedo: Albedo },
+    // roughness 0 is a perfect mirror, 1 is close to diffuse
+    Metal{ albedo: Albedo, roughness: f32 },
+    Dielectric{ ior: Ior },
+    Emissive{ color: crate::gfx::Color, strength: f32 },
+}
+
+// what every shape got before materials existed
+pub fn from_color_fn(f: crate::gfx::ColorFn) -> Material {
+    Material::Lambertian{ albedo: Albedo::Vertex(f) }
+}
+
+impl From<crate::gfx::ColorFn> for Material {
+    fn from(f: crate::gfx::ColorFn) -> Material {
+        from_color_fn(f)
+    }
+}
+
+impl Material {
+    // base color, used for the vertex colors
+    pub fn albedo(&self) -> Albedo {
+        match self {
+            Material::Lambertian{ albedo } => albedo.clone(),
+            Material::Metal{ albedo, .. } => albedo.clone(),
+            Material::Dielectric{ .. } => Albedo::Solid([1.0, 1.0, 1.0]),
+            Material::Emissive{ color, .. } => Albedo::Solid(*color),
+        }
+    }
+
+    pub fn emission(&self) -> crate::gfx::Color {
+        match self {
+            Material::Emissive{ color, strength } => [color[0] * strength, color[1] * strength, color[2] * strength],
+            _ => [0.0, 0.0, 0.0],
+        }
+    }
+
+    // matches the `material` switch in fragment.glsl
+    pub fn kind(&self) -> i32 {
+        match self {
+            Material::Lambertian{ .. } => 0,
+            Material::Metal{ .. } => 1,
+            Material::Dielectric{ .. } => 2,
+            Material::Emissive{ .. } => 3,
+        }
+    }
+
+    // roughness for metals, ior for dielectrics, strength for emitters
+    pub fn parameter(&self) -> f32 {
+        match self {
+            Material::Lambertian{ .. } => 0.0,
+            Material::Metal{ roughness, .. } => *roughness,
+            Material::Dielectric{ ior } => ior.d(),
+            Material::Emissive{ strength, .. } => *strength,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the hydrogen F and C lines either side of the d line
+    const F_LINE: f32 = 486.1;
+    const C_LINE: f32 = 656.3;
+
+    fn abbe(ior: &Ior) -> f32 {
+        (ior.d() - 1.0) / (ior.at(F_LINE) - ior.at(C_LINE))
+    }
+
+    #[test]
+    fn sellmeier_matches_the_catalog() {
+        // Schott's n_F, n_d, n_C and Abbe numbers
+        for (ior, n, v) in [(BK7, [1.522_38, 1.516_80, 1.514_32], 64.17), (SF11, [1.806_44, 1.784_72, 1.775_99], 25.68)].iter() {
+            assert!((ior.at(F_LINE) - n[0]).abs() < 1e-4, "{:?}: n_F {}", ior, ior.at(F_LINE));
+            assert!((ior.d() - n[1]).abs() < 1e-4, "{:?}: n_d {}", ior, ior.d());
+            assert!((ior.at(C_LINE) - n[2]).abs() < 1e-4, "{:?}: n_C {}", ior, ior.at(C_LINE));
+            assert!((abbe(ior) - v).abs() < 0.5, "{:?}: abbe {}", ior, abbe(ior));
+        }
+    }
+
+    #[test]
+    fn cauchy_fit_follows_bk7() {
+        let cauchy = Ior::Cauchy{ a: 1.5046, b: 0.004_20 };
+        assert!((cauchy.at(500.0) - (1.5046 + 0.004_20 / 0.25)).abs() < 1e-6);
+        for l in [F_LINE, D_LINE, C_LINE].iter() {
+            assert!((cauchy.at(*l) - BK7.at(*l)).abs() < 5e-4, "{}nm: {} against {}", l, cauchy.at(*l), BK7.at(*l));
+        }
+        // bluer light bends more
+        assert!(cauchy.at(400.0) > cauchy.at(700.0));
+    }
+
+    #[test]
+    fn constant_ignores_wavelength() {
+        let ior = Ior::Constant(1.33);
+        assert_eq!(ior.at(400.0), 1.33);
+        assert_eq!(ior.at(700.0), 1.33);
+        assert_eq!(ior.d(), 1.33);
+    }
+}
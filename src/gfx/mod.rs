@@ -5,10 +5,25 @@ use std::ffi::CString;
 pub mod camera;
 pub mod shader;
 pub mod render;
+pub mod ray;
+pub mod material;
+pub mod light;
+pub mod quad;
+pub mod texture;
+pub mod procedural;
+pub mod environment;
+pub mod skybox;
+pub mod tonemap;
+pub mod hdr;
+pub mod primitive;
+pub mod geometry;
 
 
 pub type Triangle = [na::Point3<f32>; 3];
 pub type Mesh = std::vec::Vec<Triangle>;
+// texture coordinates of a triangle's vertices, in the same order
+pub type TriangleUv = [na::Point2<f32>; 3];
+pub type Uvs = std::vec::Vec<TriangleUv>;
 pub type Color = [f32; 3];
 pub type ColorFn = fn(i32) -> Color;
 
@@ -0,0 +1,500 @@
+use nalgebra as na;
+
+// Analytic shapes, intersected exactly by the tracer and tessellated into a
+// Mesh for the GL renderer. Every primitive sits in its own space, centered on
+// the origin with +y as its axis; instances place them in the world.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Primitive {
+    Sphere{ radius: f32 },
+    // finite, so it can be bounded; lies in the xz plane facing +y
+    Plane{ width: f32, depth: f32 },
+    // in the xz plane facing +y
+    Disc{ radius: f32 },
+    // open ended tube from -height / 2 to height / 2
+    Cylinder{ radius: f32, height: f32 },
+    // open base of the given radius at -height / 2, apex at height / 2
+    Cone{ radius: f32, height: f32 },
+    // ring around the y axis; major is the distance from the axis to the
+    // center of the tube, minor the tube's radius
+    Torus{ major: f32, minor: f32 },
+}
+
+pub fn sphere(radius: f32) -> Primitive {
+    Primitive::Sphere{ radius }
+}
+
+pub fn plane(width: f32, depth: f32) -> Primitive {
+    Primitive::Plane{ width, depth }
+}
+
+pub fn disc(radius: f32) -> Primitive {
+    Primitive::Disc{ radius }
+}
+
+pub fn cylinder(radius: f32, height: f32) -> Primitive {
+    Primitive::Cylinder{ radius, height }
+}
+
+pub fn cone(radius: f32, height: f32) -> Primitive {
+    Primitive::Cone{ radius, height }
+}
+
+pub fn torus(major: f32, minor: f32) -> Primitive {
+    Primitive::Torus{ major, minor }
+}
+
+const TAU: f32 = 2.0 * std::f32::consts::PI;
+
+// angle around the y axis as a fraction of a turn in [0, 1), zero along +z
+fn turn(x: f32, z: f32) -> f32 {
+    let a = x.atan2(z) / TAU;
+    if a < 0.0 { a + 1.0 } else { a }
+}
+
+// real roots of a t^2 + b t + c in increasing order; in double precision so
+// grazing hits don't flicker
+fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let (a, b, c) = (a as f64, b as f64, c as f64);
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        let t = (-c / b) as f32;
+        return Some((t, t));
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    // avoids the cancellation in (-b + sqrt) when b is large
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 { -0.5 * (b - root) } else { -0.5 * (b + root) };
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+
+    let (t0, t1) = ((q / a) as f32, (c / q) as f32);
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+// c[0] + c[1] x + c[2] x^2 + ...
+fn eval(c: &[f64], x: f64) -> f64 {
+    c.iter().rev().fold(0.0, |acc, &k| acc * x + k)
+}
+
+// Real roots of the polynomial within [lo, hi], in increasing order. The
+// derivative's roots split the interval into pieces the polynomial is monotonic
+// over, each holding at most one root, which is then found by bisection.
+fn roots(c: &[f64], lo: f64, hi: f64) -> std::vec::Vec<f64> {
+    if c.len() == 2 {
+        if c[1] == 0.0 {
+            return vec![];
+        }
+        let x = -c[0] / c[1];
+        return if x >= lo && x <= hi { vec![x] } else { vec![] };
+    }
+
+    let derivative: std::vec::Vec<f64> = (1..c.len()).map(|i| c[i] * i as f64).collect();
+    let mut edges = vec![lo];
+    edges.extend(roots(&derivative, lo, hi));
+    edges.push(hi);
+
+    let mut found = vec![];
+    for w in edges.windows(2) {
+        let (mut a, mut b) = (w[0], w[1]);
+        let (fa, fb) = (eval(c, a), eval(c, b));
+        if fa == 0.0 {
+            if found.last() != Some(&a) {
+                found.push(a);
+            }
+            continue;
+        }
+        if fa * fb > 0.0 || fb == 0.0 {
+            continue;
+        }
+
+        // the sign of f(a) never changes, so only the midpoint needs evaluating
+        for _ in 0..64 {
+            let mid = 0.5 * (a + b);
+            if mid <= a || mid >= b {
+                break;
+            }
+            if (eval(c, mid) > 0.0) == (fa > 0.0) { a = mid } else { b = mid }
+        }
+        found.push(0.5 * (a + b));
+    }
+
+    if eval(c, hi) == 0.0 && found.last() != Some(&hi) {
+        found.push(hi);
+    }
+    found
+}
+
+impl Primitive {
+    pub fn bounds(&self) -> crate::trace::aabb::Aabb {
+        let (x, y, z) = match *self {
+            Primitive::Sphere{ radius } => (radius, radius, radius),
+            Primitive::Plane{ width, depth } => (width / 2.0, 0.0, depth / 2.0),
+            Primitive::Disc{ radius } => (radius, 0.0, radius),
+            Primitive::Cylinder{ radius, height } | Primitive::Cone{ radius, height } => (radius, height / 2.0, radius),
+            Primitive::Torus{ major, minor } => (major + minor, minor, major + minor),
+        };
+        crate::trace::aabb::Aabb{
+            min: na::Point3::new(-x, -y, -z),
+            max: na::Point3::new(x, y, z),
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        let pi = std::f32::consts::PI;
+        match *self {
+            Primitive::Sphere{ radius } => 4.0 * pi * radius * radius,
+            Primitive::Plane{ width, depth } => width * depth,
+            Primitive::Disc{ radius } => pi * radius * radius,
+            Primitive::Cylinder{ radius, height } => TAU * radius * height,
+            Primitive::Cone{ radius, height } => pi * radius * (radius * radius + height * height).sqrt(),
+            Primitive::Torus{ major, minor } => TAU * TAU * major * minor,
+        }
+    }
+
+    // nearest distance in (0, ray.t_max) at which the ray crosses the surface
+    fn distance(&self, ray: &crate::gfx::ray::Ray) -> Option<f32> {
+        let o = ray.origin;
+        let d = ray.dir;
+        let in_range = |t: f32| t > 0.0 && t < ray.t_max;
+
+        match *self {
+            Primitive::Sphere{ radius } => {
+                let (t0, t1) = quadratic(d.dot(&d), 2.0 * o.coords.dot(&d), o.coords.dot(&o.coords) - radius * radius)?;
+                [t0, t1].iter().cloned().find(|&t| in_range(t))
+            },
+            Primitive::Plane{ width, depth } => {
+                if d.y == 0.0 {
+                    return None;
+                }
+                let t = -o.y / d.y;
+                let p = ray.at(t);
+                Some(t).filter(|&t| in_range(t) && p.x.abs() <= width / 2.0 && p.z.abs() <= depth / 2.0)
+            },
+            Primitive::Disc{ radius } => {
+                if d.y == 0.0 {
+                    return None;
+                }
+                let t = -o.y / d.y;
+                let p = ray.at(t);
+                Some(t).filter(|&t| in_range(t) && p.x * p.x + p.z * p.z <= radius * radius)
+            },
+            Primitive::Cylinder{ radius, height } => {
+                let a = d.x * d.x + d.z * d.z;
+                let b = 2.0 * (o.x * d.x + o.z * d.z);
+                let c = o.x * o.x + o.z * o.z - radius * radius;
+                let (t0, t1) = quadratic(a, b, c)?;
+                [t0, t1].iter().cloned().find(|&t| in_range(t) && (o.y + t * d.y).abs() <= height / 2.0)
+            },
+            Primitive::Cone{ radius, height } => {
+                // x^2 + z^2 = (k (apex - y))^2, which also has a mirrored nappe
+                // above the apex that the height check throws away
+                let k2 = (radius / height) * (radius / height);
+                let apex = height / 2.0;
+                let below = apex - o.y;
+                let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+                let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * below * d.y);
+                let c = o.x * o.x + o.z * o.z - k2 * below * below;
+                let (t0, t1) = quadratic(a, b, c)?;
+                [t0, t1].iter().cloned().find(|&t| in_range(t) && (o.y + t * d.y).abs() <= apex)
+            },
+            Primitive::Torus{ major, minor } => {
+                // the roots are only searched for where the ray is inside the bounding sphere
+                let reach = major + minor;
+                let (enter, exit) = quadratic(d.dot(&d), 2.0 * o.coords.dot(&d), o.coords.dot(&o.coords) - reach * reach)?;
+                let lo = enter.max(0.0);
+                let hi = exit.min(ray.t_max);
+                if lo > hi {
+                    return None;
+                }
+
+                // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2), expanded in t
+                let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+                let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+                let (r_major, r_minor) = (major as f64, minor as f64);
+                let alpha = dx * dx + dy * dy + dz * dz;
+                let beta = 2.0 * (ox * dx + oy * dy + oz * dz);
+                let gamma = ox * ox + oy * oy + oz * oz + r_major * r_major - r_minor * r_minor;
+                let four_r2 = 4.0 * r_major * r_major;
+                let c = [
+                    gamma * gamma - four_r2 * (ox * ox + oz * oz),
+                    2.0 * beta * gamma - 2.0 * four_r2 * (ox * dx + oz * dz),
+                    beta * beta + 2.0 * alpha * gamma - four_r2 * (dx * dx + dz * dz),
+                    2.0 * alpha * beta,
+                    alpha * alpha,
+                ];
+
+                roots(&c, lo as f64, hi as f64).into_iter().map(|t| t as f32).find(|&t| in_range(t))
+            },
+        }
+    }
+
+    // outward unit normal at a point on the surface
+    pub fn normal(&self, p: &na::Point3<f32>) -> na::Vector3<f32> {
+        let n = match *self {
+            Primitive::Sphere{ .. } => p.coords,
+            Primitive::Plane{ .. } | Primitive::Disc{ .. } => na::Vector3::y(),
+            Primitive::Cylinder{ .. } => na::Vector3::new(p.x, 0.0, p.z),
+            Primitive::Cone{ radius, height } => {
+                let k2 = (radius / height) * (radius / height);
+                na::Vector3::new(p.x, k2 * (height / 2.0 - p.y), p.z)
+            },
+            Primitive::Torus{ major, .. } => {
+                let ring = na::Vector3::new(p.x, 0.0, p.z);
+                let m = ring.magnitude();
+                if m == 0.0 { na::Vector3::y() } else { p.coords - ring * (major / m) }
+            },
+        };
+        // the apex, the poles and so on have no normal of their own
+        if n == na::Vector3::zeros() { na::Vector3::y() } else { n.normalize() }
+    }
+
+    // texture coordinates of a point on the surface; u goes around the y axis
+    // and v up it, except for the plane and disc, which are mapped flat with v
+    // towards -z
+    pub fn uv(&self, p: &na::Point3<f32>) -> na::Point2<f32> {
+        match *self {
+            Primitive::Sphere{ radius } => {
+                na::Point2::new(turn(p.x, p.z), (-p.y / radius).clamp(-1.0, 1.0).acos() / std::f32::consts::PI)
+            },
+            Primitive::Plane{ width, depth } => na::Point2::new(p.x / width + 0.5, 0.5 - p.z / depth),
+            Primitive::Disc{ radius } => na::Point2::new(p.x / (2.0 * radius) + 0.5, 0.5 - p.z / (2.0 * radius)),
+            Primitive::Cylinder{ height, .. } | Primitive::Cone{ height, .. } => {
+                na::Point2::new(turn(p.x, p.z), p.y / height + 0.5)
+            },
+            Primitive::Torus{ major, .. } => {
+                let tube = p.y.atan2((p.x * p.x + p.z * p.z).sqrt() - major) / TAU;
+                na::Point2::new(turn(p.x, p.z), if tube < 0.0 { tube + 1.0 } else { tube })
+            },
+        }
+    }
+
+    // the ray must already be in the primitive's space, the hit is left there
+    pub fn intersect(&self, ray: &crate::gfx::ray::Ray) -> Option<crate::gfx::ray::Hit> {
+        let t = self.distance(ray)?;
+        let p = ray.at(t);
+        Some(crate::gfx::ray::Hit{
+            t,
+            u: 0.0,
+            v: 0.0,
+            normal: self.normal(&p),
+            triangle: 0,
+            local: p,
+            uv: Some(self.uv(&p)),
+        })
+    }
+
+    // uniform point by area and its normal, in the primitive's space
+    pub fn sample(&self, u: f32, v: f32) -> (na::Point3<f32>, na::Vector3<f32>) {
+        let p = match *self {
+            Primitive::Sphere{ radius } => na::Point3::from(crate::trace::sampling::uniform_sphere(u, v) * radius),
+            Primitive::Plane{ width, depth } => na::Point3::new(width * (u - 0.5), 0.0, depth * (0.5 - v)),
+            Primitive::Disc{ radius } => {
+                let (x, z) = crate::trace::sampling::concentric_disk(u, v);
+                na::Point3::new(x * radius, 0.0, z * radius)
+            },
+            Primitive::Cylinder{ radius, height } => {
+                let phi = TAU * u;
+                na::Point3::new(radius * phi.sin(), height * (v - 0.5), radius * phi.cos())
+            },
+            Primitive::Cone{ radius, height } => {
+                // area grows linearly with the distance from the apex
+                let s = v.sqrt();
+                let phi = TAU * u;
+                na::Point3::new(radius * s * phi.sin(), height * (0.5 - s), radius * s * phi.cos())
+            },
+            Primitive::Torus{ major, minor } => {
+                // the outside of the ring has more area than the inside; invert
+                // the cdf (major psi + minor sin psi) / (2 pi major) with newton's
+                // method, which can't overshoot far since it's monotonic
+                let target = v * TAU * major;
+                let mut psi = TAU * v;
+                for _ in 0..8 {
+                    let f = major * psi + minor * psi.sin() - target;
+                    psi = (psi - f / (major + minor * psi.cos()).max(1e-6)).clamp(0.0, TAU);
+                }
+                let phi = TAU * u;
+                let ring = major + minor * psi.cos();
+                na::Point3::new(ring * phi.sin(), minor * psi.sin(), ring * phi.cos())
+            },
+        };
+        (p, self.normal(&p))
+    }
+
+    // point and texture coordinates at (u, v) across the surface's
+    // parameterization, each in [0, 1]
+    fn vertex(&self, u: f32, v: f32) -> (na::Point3<f32>, na::Point2<f32>) {
+        let phi = TAU * u;
+        let (s, c) = (phi.sin(), phi.cos());
+        match *self {
+            Primitive::Sphere{ radius } => {
+                let y = -(std::f32::consts::PI * v).cos();
+                let ring = (1.0 - y * y).max(0.0).sqrt();
+                (na::Point3::new(radius * ring * s, radius * y, radius * ring * c), na::Point2::new(u, v))
+            },
+            Primitive::Plane{ width, depth } => {
+                (na::Point3::new(width * (u - 0.5), 0.0, depth * (0.5 - v)), na::Point2::new(u, v))
+            },
+            Primitive::Disc{ radius } => {
+                let r = radius * (1.0 - v);
+                let p = na::Point3::new(r * s, 0.0, r * c);
+                (p, self.uv(&p))
+            },
+            Primitive::Cylinder{ radius, height } => {
+                (na::Point3::new(radius * s, height * (v - 0.5), radius * c), na::Point2::new(u, v))
+            },
+            Primitive::Cone{ radius, height } => {
+                let r = radius * (1.0 - v);
+                (na::Point3::new(r * s, height * (v - 0.5), r * c), na::Point2::new(u, v))
+            },
+            Primitive::Torus{ major, minor } => {
+                let psi = TAU * v;
+                let ring = major + minor * psi.cos();
+                (na::Point3::new(ring * s, minor * psi.sin(), ring * c), na::Point2::new(u, v))
+            },
+        }
+    }
+
+    // triangles approximating the surface, with segments around the y axis (or
+    // the tube); both wind counter clockwise seen from outside, like the
+    // rectangle's, and the uvs match the ones the tracer computes
+    pub fn tessellate(&self, segments: usize) -> (crate::gfx::Mesh, crate::gfx::Uvs) {
+        let segments = segments.max(3);
+        let (columns, rows) = match self {
+            Primitive::Sphere{ .. } | Primitive::Torus{ .. } => (segments, (segments / 2).max(2)),
+            Primitive::Plane{ .. } => (1, 1),
+            Primitive::Disc{ .. } | Primitive::Cylinder{ .. } | Primitive::Cone{ .. } => (segments, 1),
+        };
+
+        let mut mesh: crate::gfx::Mesh = vec![];
+        let mut uvs: crate::gfx::Uvs = vec![];
+
+        for i in 0..columns {
+            for j in 0..rows {
+                let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+                let quad: std::vec::Vec<(na::Point3<f32>, na::Point2<f32>)> = corners.iter().map(|&(a, b)| {
+                    self.vertex(a as f32 / columns as f32, b as f32 / rows as f32)
+                }).collect();
+
+                for &[a, b, c] in [[0, 1, 2], [0, 2, 3]].iter() {
+                    let triangle = [quad[a].0, quad[b].0, quad[c].0];
+                    // quads touching a pole or apex collapse to one triangle
+                    if crate::gfx::ray::face_normal(&triangle) == na::Vector3::zeros() {
+                        continue;
+                    }
+                    mesh.push(triangle);
+                    uvs.push([quad[a].1, quad[b].1, quad[c].1]);
+                }
+            }
+        }
+
+        (mesh, uvs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    // hits p with a unit ray from origin along dir, checking where, which way
+    // the surface faces there, and its uvs
+    fn check_hit(p: Primitive, origin: [f32; 3], dir: [f32; 3], t: f32, normal: [f32; 3], uv: [f32; 2]) {
+        let ray = crate::gfx::ray::new(na::Point3::new(origin[0], origin[1], origin[2]), na::Vector3::new(dir[0], dir[1], dir[2]));
+        let hit = p.intersect(&ray).unwrap_or_else(|| panic!("{:?} missed", p));
+        let n = na::Vector3::new(normal[0], normal[1], normal[2]).normalize();
+        let hit_uv = hit.uv.unwrap();
+
+        assert!(close(hit.t, t), "{:?}: t {} instead of {}", p, hit.t, t);
+        assert!((hit.normal - n).magnitude() < 1e-4, "{:?}: normal {:?}", p, hit.normal);
+        assert!(close(hit_uv.x, uv[0]) && close(hit_uv.y, uv[1]), "{:?}: uv {:?}", p, hit_uv);
+    }
+
+    #[test]
+    fn sphere_hit() {
+        check_hit(sphere(2.0), [0.0, 0.0, -5.0], [0.0, 0.0, 1.0], 3.0, [0.0, 0.0, -1.0], [0.5, 0.5]);
+    }
+
+    #[test]
+    fn plane_hit() {
+        check_hit(plane(4.0, 6.0), [1.0, 5.0, -2.0], [0.0, -1.0, 0.0], 5.0, [0.0, 1.0, 0.0], [0.75, 0.5 + 2.0 / 6.0]);
+        let past_edge = crate::gfx::ray::new(na::Point3::new(2.5, 5.0, 0.0), -na::Vector3::y());
+        assert!(plane(4.0, 6.0).intersect(&past_edge).is_none());
+    }
+
+    #[test]
+    fn disc_hit() {
+        check_hit(disc(2.0), [1.0, 3.0, 0.0], [0.0, -1.0, 0.0], 3.0, [0.0, 1.0, 0.0], [0.75, 0.5]);
+        let past_edge = crate::gfx::ray::new(na::Point3::new(1.5, 3.0, 1.5), -na::Vector3::y());
+        assert!(disc(2.0).intersect(&past_edge).is_none());
+    }
+
+    #[test]
+    fn cylinder_hit() {
+        check_hit(cylinder(2.0, 4.0), [-5.0, 1.0, 0.0], [1.0, 0.0, 0.0], 3.0, [-1.0, 0.0, 0.0], [0.75, 0.75]);
+        // open ended, so a ray down the axis goes straight through
+        let down_the_axis = crate::gfx::ray::new(na::Point3::new(0.0, 5.0, 0.0), -na::Vector3::y());
+        assert!(cylinder(2.0, 4.0).intersect(&down_the_axis).is_none());
+    }
+
+    #[test]
+    fn cone_hit() {
+        // halfway up, the radius is halved; the side leans in by radius / height
+        check_hit(cone(2.0, 4.0), [-5.0, 0.0, 0.0], [1.0, 0.0, 0.0], 4.0, [-1.0, 0.5, 0.0], [0.75, 0.5]);
+        // the mirrored nappe above the apex isn't part of the cone
+        let above = crate::gfx::ray::new(na::Point3::new(-5.0, 3.0, 0.0), na::Vector3::x());
+        assert!(cone(2.0, 4.0).intersect(&above).is_none());
+    }
+
+    #[test]
+    fn torus_hit() {
+        check_hit(torus(3.0, 1.0), [0.0, 0.0, -10.0], [0.0, 0.0, 1.0], 6.0, [0.0, 0.0, -1.0], [0.5, 0.0]);
+        check_hit(torus(3.0, 1.0), [3.0, 5.0, 0.0], [0.0, -1.0, 0.0], 4.0, [0.0, 1.0, 0.0], [0.25, 0.25]);
+        let through_the_hole = crate::gfx::ray::new(na::Point3::new(0.0, 5.0, 0.0), -na::Vector3::y());
+        assert!(torus(3.0, 1.0).intersect(&through_the_hole).is_none());
+    }
+
+    // how far p is off the surface, or None if it's past the surface's edge
+    fn off_surface(p: &Primitive, q: &na::Point3<f32>) -> Option<f32> {
+        let ring = (q.x * q.x + q.z * q.z).sqrt();
+        match *p {
+            Primitive::Sphere{ radius } => Some(q.coords.magnitude() - radius),
+            Primitive::Plane{ width, depth } => {
+                Some(q.y).filter(|_| q.x.abs() <= width / 2.0 + 1e-4 && q.z.abs() <= depth / 2.0 + 1e-4)
+            },
+            Primitive::Disc{ radius } => Some(q.y).filter(|_| ring <= radius + 1e-4),
+            Primitive::Cylinder{ radius, height } => Some(ring - radius).filter(|_| q.y.abs() <= height / 2.0 + 1e-4),
+            Primitive::Cone{ radius, height } => {
+                Some(ring - radius * (height / 2.0 - q.y) / height).filter(|_| q.y.abs() <= height / 2.0 + 1e-4)
+            },
+            Primitive::Torus{ major, minor } => Some(((ring - major).powi(2) + q.y * q.y).sqrt() - minor),
+        }
+    }
+
+    #[test]
+    fn samples_land_on_the_surface() {
+        let primitives = [sphere(2.0), plane(4.0, 6.0), disc(2.0), cylinder(2.0, 4.0), cone(2.0, 4.0), torus(3.0, 1.0)];
+        for p in primitives.iter() {
+            for i in 0..16 {
+                for j in 0..16 {
+                    let (u, v) = ((i as f32 + 0.5) / 16.0, (j as f32 + 0.5) / 16.0);
+                    let (q, n) = p.sample(u, v);
+                    let off = off_surface(p, &q).unwrap_or_else(|| panic!("{:?}: {:?} past the edge", p, q));
+                    assert!(off.abs() < 1e-4, "{:?}: {:?} is {} off", p, q, off);
+                    assert!((n - p.normal(&q)).magnitude() < 1e-6 && close(n.magnitude(), 1.0));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use nalgebra as na;
+use std::ffi::CString;
+use gl::types::GLint;
+
+// Procedural textures, evaluated at a point in the mesh's own space so they
+// stick to the surface as it moves. A texture is a small expression tree: noise
+// and pattern leaves, combined by mixing, adding and multiplying. The CPU
+// tracer walks the tree; for GL it's flattened into postfix and run by a stack
+// machine in fragment.glsl, which has the same noise functions, hash for hash.
+
+// fragment.glsl has room for this many nodes, and this deep a stack
+pub const MAX_NODES: usize = 32;
+pub const MAX_STACK: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum Noise {
+    // gradient noise on a cubic lattice
+    Perlin,
+    // gradient noise on a simplex lattice, fewer directional artifacts
+    Simplex,
+}
+
+// Scalar patterns (noise, worley) come out gray, and Mix uses the red channel of
+// t. Frequencies are in cycles per unit of mesh space.
+pub enum Pattern {
+    Constant(crate::gfx::Color),
+    // in [0, 1]
+    Noise{ noise: Noise, frequency: f32 },
+    // octaves at doubling frequency and halving amplitude, in [0, 1]
+    Fbm{ noise: Noise, frequency: f32, octaves: u32 },
+    // like fbm but summing absolute values, for billowy, creased looks
+    Turbulence{ noise: Noise, frequency: f32, octaves: u32 },
+    // distance to the nearest of one random point per cell, clamped to [0, 1]
+    Worley{ frequency: f32 },
+    Checker{ frequency: f32, a: Box<Pattern>, b: Box<Pattern> },
+    // veins along x, bent by turbulence
+    Marble{ frequency: f32, turbulence: f32, a: Box<Pattern>, b: Box<Pattern> },
+    // rings around the y axis, bent by turbulence
+    Wood{ frequency: f32, turbulence: f32, a: Box<Pattern>, b: Box<Pattern> },
+    Mix{ t: Box<Pattern>, a: Box<Pattern>, b: Box<Pattern> },
+    Add(Box<Pattern>, Box<Pattern>),
+    Multiply(Box<Pattern>, Box<Pattern>),
+}
+
+pub fn constant(color: crate::gfx::Color) -> Box<Pattern> {
+    Box::new(Pattern::Constant(color))
+}
+
+pub fn checker(frequency: f32, a: crate::gfx::Color, b: crate::gfx::Color) -> Pattern {
+    Pattern::Checker{ frequency, a: constant(a), b: constant(b) }
+}
+
+pub fn marble(frequency: f32, turbulence: f32, a: crate::gfx::Color, b: crate::gfx::Color) -> Pattern {
+    Pattern::Marble{ frequency, turbulence, a: constant(a), b: constant(b) }
+}
+
+pub fn wood(frequency: f32, turbulence: f32, a: crate::gfx::Color, b: crate::gfx::Color) -> Pattern {
+    Pattern::Wood{ frequency, turbulence, a: constant(a), b: constant(b) }
+}
+
+// a scalar pattern used to blend between two colors
+pub fn ramp(t: Pattern, a: crate::gfx::Color, b: crate::gfx::Color) -> Pattern {
+    Pattern::Mix{ t: Box::new(t), a: constant(a), b: constant(b) }
+}
+
+// the ops fragment.glsl's stack machine understands; see pattern() there
+const OP_CONSTANT: i32 = 0;
+const OP_NOISE: i32 = 1;
+const OP_FBM: i32 = 2;
+const OP_TURBULENCE: i32 = 3;
+const OP_WORLEY: i32 = 4;
+const OP_CHECKER: i32 = 5;
+const OP_MARBLE: i32 = 6;
+const OP_WOOD: i32 = 7;
+const OP_MIX: i32 = 8;
+const OP_ADD: i32 = 9;
+const OP_MULTIPLY: i32 = 10;
+
+pub fn hash(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^ (h >> 16)
+}
+
+fn hash3(x: i32, y: i32, z: i32) -> u32 {
+    hash((x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ (z as u32).wrapping_mul(0xcb1ab31f))
+}
+
+fn to_unit(h: u32) -> f32 {
+    (h >> 8) as f32 / 16_777_216.0
+}
+
+// one of Perlin's 12 cube edge directions (4 doubled up), dotted with d
+fn grad(h: u32, d: &na::Vector3<f32>) -> f32 {
+    let h = h & 15;
+    let u = if h < 8 { d.x } else { d.y };
+    let v = if h < 4 { d.y } else if h == 12 || h == 14 { d.x } else { d.z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// roughly in [-1, 1]
+pub fn perlin(p: &na::Point3<f32>) -> f32 {
+    let cell = p.coords.map(|c| c.floor());
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let f = p.coords - cell;
+    let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+    let corner = |i: i32, j: i32, k: i32| {
+        grad(hash3(x + i, y + j, z + k), &(f - na::Vector3::new(i as f32, j as f32, k as f32)))
+    };
+
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w,
+    )
+}
+
+// Gustavson's 3d simplex noise, roughly in [-1, 1]
+pub fn simplex(p: &na::Point3<f32>) -> f32 {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    // skew into the lattice of cubes, each split into six simplices
+    let s = (p.x + p.y + p.z) * F3;
+    let cell = (p.coords + na::Vector3::repeat(s)).map(|c| c.floor());
+    let t = (cell.x + cell.y + cell.z) * G3;
+    let d0 = p.coords - (cell - na::Vector3::repeat(t));
+
+    // which simplex: walk the axes from the largest offset to the smallest
+    let (o1, o2) = if d0.x >= d0.y {
+        if d0.y >= d0.z {
+            (na::Vector3::new(1.0, 0.0, 0.0), na::Vector3::new(1.0, 1.0, 0.0))
+        } else if d0.x >= d0.z {
+            (na::Vector3::new(1.0, 0.0, 0.0), na::Vector3::new(1.0, 0.0, 1.0))
+        } else {
+            (na::Vector3::new(0.0, 0.0, 1.0), na::Vector3::new(1.0, 0.0, 1.0))
+        }
+    } else if d0.y < d0.z {
+        (na::Vector3::new(0.0, 0.0, 1.0), na::Vector3::new(0.0, 1.0, 1.0))
+    } else if d0.x < d0.z {
+        (na::Vector3::new(0.0, 1.0, 0.0), na::Vector3::new(0.0, 1.0, 1.0))
+    } else {
+        (na::Vector3::new(0.0, 1.0, 0.0), na::Vector3::new(1.0, 1.0, 0.0))
+    };
+    let o3 = na::Vector3::repeat(1.0);
+
+    let corner = |o: &na::Vector3<f32>, k: f32| {
+        let d = d0 - o + na::Vector3::repeat(k * G3);
+        let falloff = 0.6 - d.magnitude_squared();
+        if falloff <= 0.0 {
+            return 0.0;
+        }
+        let h = hash3(cell.x as i32 + o.x as i32, cell.y as i32 + o.y as i32, cell.z as i32 + o.z as i32);
+        falloff * falloff * falloff * falloff * grad(h, &d)
+    };
+
+    32.0 * (corner(&na::Vector3::zeros(), 0.0) + corner(&o1, 1.0) + corner(&o2, 2.0) + corner(&o3, 3.0))
+}
+
+fn noise(kind: Noise, p: &na::Point3<f32>) -> f32 {
+    match kind {
+        Noise::Perlin => perlin(p),
+        Noise::Simplex => simplex(p),
+    }
+}
+
+// signed fbm in roughly [-1, 1]; absolute sums the magnitudes instead, in [0, 1]
+fn octaves(kind: Noise, p: &na::Point3<f32>, octaves: u32, absolute: bool) -> f32 {
+    let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+    let mut q = *p;
+    for _ in 0..octaves.max(1) {
+        let n = noise(kind, &q);
+        sum += amplitude * if absolute { n.abs() } else { n };
+        total += amplitude;
+        amplitude *= 0.5;
+        q = na::Point3::from(q.coords * 2.0);
+    }
+    sum / total
+}
+
+// distance to the nearest feature point, one per unit cell
+pub fn worley(p: &na::Point3<f32>) -> f32 {
+    let cell = p.coords.map(|c| c.floor());
+    let mut nearest = std::f32::INFINITY;
+
+    for k in -1..=1 {
+        for j in -1..=1 {
+            for i in -1..=1 {
+                let h = hash3(cell.x as i32 + i, cell.y as i32 + j, cell.z as i32 + k);
+                let offset = na::Vector3::new(to_unit(h), to_unit(hash(h)), to_unit(hash(hash(h))));
+                let feature = cell + na::Vector3::new(i as f32, j as f32, k as f32) + offset;
+                nearest = nearest.min((feature - p.coords).magnitude_squared());
+            }
+        }
+    }
+
+    nearest.sqrt()
+}
+
+fn scaled(p: &na::Point3<f32>, frequency: f32) -> na::Point3<f32> {
+    na::Point3::from(p.coords * frequency)
+}
+
+fn mix(a: crate::gfx::Color, b: crate::gfx::Color, t: f32) -> crate::gfx::Color {
+    [lerp(a[0], b[0], t), lerp(a[1], b[1], t), lerp(a[2], b[2], t)]
+}
+
+fn gray(v: f32) -> crate::gfx::Color {
+    [v, v, v]
+}
+
+// faces sitting exactly on a cell boundary would flicker between squares
+const CHECKER_OFFSET: f32 = 1e-3;
+
+impl Pattern {
+    pub fn eval(&self, p: &na::Point3<f32>) -> crate::gfx::Color {
+        match self {
+            Pattern::Constant(c) => *c,
+            Pattern::Noise{ noise: kind, frequency } => gray(0.5 + 0.5 * noise(*kind, &scaled(p, *frequency)).clamp(-1.0, 1.0)),
+            Pattern::Fbm{ noise, frequency, octaves: n } => {
+                gray(0.5 + 0.5 * octaves(*noise, &scaled(p, *frequency), *n, false).clamp(-1.0, 1.0))
+            },
+            Pattern::Turbulence{ noise, frequency, octaves: n } => {
+                gray(octaves(*noise, &scaled(p, *frequency), *n, true).min(1.0))
+            },
+            Pattern::Worley{ frequency } => gray(worley(&scaled(p, *frequency)).min(1.0)),
+            Pattern::Checker{ frequency, a, b } => {
+                let q = p.coords.map(|c| (c * frequency + CHECKER_OFFSET).floor());
+                if (q.x + q.y + q.z).rem_euclid(2.0) < 1.0 { a.eval(p) } else { b.eval(p) }
+            },
+            Pattern::Marble{ frequency, turbulence, a, b } => {
+                let q = scaled(p, *frequency);
+                let bend = turbulence * octaves(Noise::Perlin, &q, 4, true);
+                let t = 0.5 + 0.5 * ((q.x + bend) * std::f32::consts::PI).sin();
+                mix(a.eval(p), b.eval(p), t)
+            },
+            Pattern::Wood{ frequency, turbulence, a, b } => {
+                let q = scaled(p, *frequency);
+                let bend = turbulence * octaves(Noise::Perlin, &q, 2, false);
+                let t = (q.x * q.x + q.z * q.z).sqrt() + bend;
+                mix(a.eval(p), b.eval(p), t - t.floor())
+            },
+            Pattern::Mix{ t, a, b } => mix(a.eval(p), b.eval(p), t.eval(p)[0]),
+            Pattern::Add(a, b) => {
+                let (a, b) = (a.eval(p), b.eval(p));
+                [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+            },
+            Pattern::Multiply(a, b) => {
+                let (a, b) = (a.eval(p), b.eval(p));
+                [a[0] * b[0], a[1] * b[1], a[2] * b[2]]
+            },
+        }
+    }
+
+    // children first, as (op, parameters) pairs for the shader
+    fn flatten(&self, out: &mut std::vec::Vec<(i32, [f32; 4])>) {
+        let kind = |n: &Noise| match n {
+            Noise::Perlin => 0.0,
+            Noise::Simplex => 1.0,
+        };
+
+        match self {
+            Pattern::Constant(c) => out.push((OP_CONSTANT, [c[0], c[1], c[2], 0.0])),
+            Pattern::Noise{ noise, frequency } => out.push((OP_NOISE, [*frequency, 0.0, kind(noise), 0.0])),
+            Pattern::Fbm{ noise, frequency, octaves } => out.push((OP_FBM, [*frequency, *octaves as f32, kind(noise), 0.0])),
+            Pattern::Turbulence{ noise, frequency, octaves } => out.push((OP_TURBULENCE, [*frequency, *octaves as f32, kind(noise), 0.0])),
+            Pattern::Worley{ frequency } => out.push((OP_WORLEY, [*frequency, 0.0, 0.0, 0.0])),
+            Pattern::Checker{ frequency, a, b } => {
+                a.flatten(out);
+                b.flatten(out);
+                out.push((OP_CHECKER, [*frequency, 0.0, 0.0, 0.0]));
+            },
+            Pattern::Marble{ frequency, turbulence, a, b } => {
+                a.flatten(out);
+                b.flatten(out);
+                out.push((OP_MARBLE, [*frequency, *turbulence, 0.0, 0.0]));
+            },
+            Pattern::Wood{ frequency, turbulence, a, b } => {
+                a.flatten(out);
+                b.flatten(out);
+                out.push((OP_WOOD, [*frequency, *turbulence, 0.0, 0.0]));
+            },
+            Pattern::Mix{ t, a, b } => {
+                a.flatten(out);
+                b.flatten(out);
+                t.flatten(out);
+                out.push((OP_MIX, [0.0; 4]));
+            },
+            Pattern::Add(a, b) => {
+                a.flatten(out);
+                b.flatten(out);
+                out.push((OP_ADD, [0.0; 4]));
+            },
+            Pattern::Multiply(a, b) => {
+                a.flatten(out);
+                b.flatten(out);
+                out.push((OP_MULTIPLY, [0.0; 4]));
+            },
+        }
+    }
+
+    // deepest the shader's stack gets evaluating this tree
+    fn depth(&self) -> usize {
+        match self {
+            Pattern::Constant(_) | Pattern::Noise{ .. } | Pattern::Fbm{ .. } | Pattern::Turbulence{ .. } | Pattern::Worley{ .. } => 1,
+            Pattern::Checker{ a, b, .. } | Pattern::Marble{ a, b, .. } | Pattern::Wood{ a, b, .. } | Pattern::Add(a, b) | Pattern::Multiply(a, b) => {
+                a.depth().max(1 + b.depth())
+            },
+            Pattern::Mix{ t, a, b } => a.depth().max(1 + b.depth()).max(2 + t.depth()),
+        }
+    }
+}
+
+// where a program's pattern uniforms are, looked up the first time it's used,
+// and the ops it last got, so objects sharing a pattern don't upload it again
+struct Uniforms {
+    program: crate::gfx::shader::Program,
+    count: GLint,
+    ops: std::vec::Vec<GLint>,
+    params: std::vec::Vec<GLint>,
+    uploaded: Option<std::vec::Vec<(i32, [f32; 4])>>,
+}
+
+fn location(program: crate::gfx::shader::Program, name: &str) -> GLint {
+    let id = CString::new(name).expect("CString::new failed");
+    unsafe { gl::GetUniformLocation(program, id.as_ptr()) }
+}
+
+fn uniforms(program: crate::gfx::shader::Program) -> Uniforms {
+    Uniforms{
+        program,
+        count: location(program, "patternCount"),
+        ops: (0..MAX_NODES).map(|i| location(program, &format!("patternOp[{}]", i))).collect(),
+        params: (0..MAX_NODES).map(|i| location(program, &format!("patternParams[{}]", i))).collect(),
+        uploaded: None,
+    }
+}
+
+thread_local! {
+    // only the thread with the GL context draws
+    static UNIFORMS: std::cell::RefCell<std::vec::Vec<Uniforms>> = const { std::cell::RefCell::new(std::vec::Vec::new()) };
+}
+
+// sets the pattern uniforms in fragment.glsl; None turns the pattern off. The
+// program must be in use
+pub fn upload(program: crate::gfx::shader::Program, pattern: Option<&Pattern>) -> Result<(), std::string::String> {
+    let mut ops = vec![];
+    if let Some(p) = pattern {
+        if p.depth() > MAX_STACK {
+            return Err(format!("procedural texture nests deeper than {}", MAX_STACK));
+        }
+        p.flatten(&mut ops);
+        if ops.len() > MAX_NODES {
+            return Err(format!("procedural texture has {} nodes, the shader fits {}", ops.len(), MAX_NODES));
+        }
+    }
+
+    UNIFORMS.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let u = match cache.iter().position(|u| u.program == program) {
+            Some(i) => &mut cache[i],
+            None => {
+                cache.push(uniforms(program));
+                cache.last_mut().unwrap()
+            },
+        };
+        if u.uploaded.as_ref() == Some(&ops) {
+            return;
+        }
+
+        unsafe {
+            gl::Uniform1i(u.count, ops.len() as GLint);
+            for (i, (op, params)) in ops.iter().enumerate() {
+                gl::Uniform1i(u.ops[i], *op);
+                gl::Uniform4f(u.params[i], params[0], params[1], params[2], params[3]);
+            }
+        }
+        u.uploaded = Some(ops);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: crate::gfx::Color, b: crate::gfx::Color) {
+        assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-6), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn combinators_evaluate_their_children() {
+        let p = na::Point3::new(0.3, 1.7, -2.2);
+        let a = [0.2, 0.4, 0.6];
+        let b = [0.5, 0.25, 1.0];
+
+        assert_close(Pattern::Add(constant(a), constant(b)).eval(&p), [0.7, 0.65, 1.6]);
+        assert_close(Pattern::Multiply(constant(a), constant(b)).eval(&p), [0.1, 0.1, 0.6]);
+        assert_close(Pattern::Mix{ t: constant([0.5, 0.0, 0.0]), a: constant(a), b: constant(b) }.eval(&p), [0.35, 0.325, 0.8]);
+    }
+
+    #[test]
+    fn checker_alternates_between_cells() {
+        let c = checker(1.0, [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+        assert_eq!(c.eval(&na::Point3::new(0.5, 0.5, 0.5)), [1.0, 0.0, 0.0]);
+        assert_eq!(c.eval(&na::Point3::new(1.5, 0.5, 0.5)), [0.0, 0.0, 1.0]);
+        assert_eq!(c.eval(&na::Point3::new(1.5, 1.5, 0.5)), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn scalar_patterns_stay_in_range() {
+        let patterns = [
+            Pattern::Noise{ noise: Noise::Perlin, frequency: 1.3 },
+            Pattern::Noise{ noise: Noise::Simplex, frequency: 1.3 },
+            Pattern::Fbm{ noise: Noise::Simplex, frequency: 0.7, octaves: 5 },
+            Pattern::Turbulence{ noise: Noise::Perlin, frequency: 0.7, octaves: 5 },
+            Pattern::Worley{ frequency: 2.0 },
+        ];
+        for pattern in patterns.iter() {
+            for i in 0..500 {
+                let x = i as f32 * 0.137;
+                let v = pattern.eval(&na::Point3::new(x, (x * 1.7).sin() * 3.0, x * 0.31 - 5.0));
+                assert!(v[0] >= 0.0 && v[0] <= 1.0 && v[0] == v[1] && v[1] == v[2], "{:?}", v);
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::ffi::CString;
+use gl::types::{GLfloat, GLsizeiptr, GLuint, GLint, GLboolean, GLvoid};
+
+// A texture stretched over the whole window, for showing images made on the
+// CPU (the tracer's progressive preview) inside the GL window loop, and the
+// GL renderer's own HDR target. Either way it's where tone mapping happens.
+pub struct Quad {
+    program: crate::gfx::shader::Program,
+    vao: u32,
+    texture: u32,
+}
+
+pub fn new() -> Result<Quad, std::string::String> {
+    let vs = crate::gfx::shader::compile_shader(include_str!("../shaders/quad_vertex.glsl"), crate::gfx::shader::Type::Vertex)?;
+    let fs = crate::gfx::shader::compile_shader(include_str!("../shaders/quad_fragment.glsl"), crate::gfx::shader::Type::Fragment)?;
+    let program = crate::gfx::shader::link_program(vs, fs)?;
+
+    // two triangles covering clip space, interleaved position and uv. Framebuffer
+    // rows start at the top, textures at the bottom, so v is flipped
+    let v: [GLfloat; 24] = [
+        -1.0, -1.0, 0.0, 1.0,
+         1.0, -1.0, 1.0, 1.0,
+         1.0,  1.0, 1.0, 0.0,
+        -1.0, -1.0, 0.0, 1.0,
+         1.0,  1.0, 1.0, 0.0,
+        -1.0,  1.0, 0.0, 0.0,
+    ];
+
+    let mut vao = 0;
+    let mut vbo = 0;
+    let mut texture = 0;
+
+    unsafe {
+        gl::GenVertexArrays(1, &mut vao);
+        gl::GenBuffers(1, &mut vbo);
+        gl::BindVertexArray(vao);
+        gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            (v.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
+            v.as_ptr() as *const GLvoid,
+            gl::STATIC_DRAW,
+        );
+
+        let attrib_position_id = CString::new("attribPosition").expect("CString:new failed");
+        let attrib_uv_id = CString::new("attribUv").expect("CString:new failed");
+
+        let attrib_position = gl::GetAttribLocation(program, attrib_position_id.as_ptr());
+        let attrib_uv = gl::GetAttribLocation(program, attrib_uv_id.as_ptr());
+
+        gl::VertexAttribPointer(
+            attrib_position as GLuint,
+            2,
+            gl::FLOAT,
+            gl::FALSE as GLboolean,
+            (4 * std::mem::size_of::<GLfloat>()) as GLint,
+            std::ptr::null(),
+        );
+
+        gl::VertexAttribPointer(
+            attrib_uv as GLuint,
+            2,
+            gl::FLOAT,
+            gl::FALSE as GLboolean,
+            (4 * std::mem::size_of::<GLfloat>()) as GLint,
+            (2 * std::mem::size_of::<GLfloat>()) as *const GLvoid,
+        );
+
+        gl::EnableVertexAttribArray(attrib_position as GLuint);
+        gl::EnableVertexAttribArray(attrib_uv as GLuint);
+        gl::BindVertexArray(0);
+
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        // the preview is usually smaller than the window
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+
+    Ok(Quad{ program, vao, texture })
+}
+
+impl Quad {
+    // replaces the texture with the framebuffer's pixels, kept as floats
+    pub fn upload(&self, fb: &crate::trace::Framebuffer) {
+        let mut data: std::vec::Vec<GLfloat> = std::vec::Vec::with_capacity(fb.pixels.len() * 3);
+        for c in fb.pixels.iter() {
+            data.extend_from_slice(c);
+        }
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            // rows of rgb floats aren't always 4 byte multiples
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGB32F as GLint,
+                fb.width as GLint,
+                fb.height as GLint,
+                0,
+                gl::RGB,
+                gl::FLOAT,
+                data.as_ptr() as *const GLvoid,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+    }
+
+    // shows the last uploaded framebuffer
+    pub fn render(&self, tonemap: &crate::gfx::tonemap::Tonemap) {
+        self.draw(self.texture, tonemap);
+    }
+
+    // covers the window in any texture of linear radiance, tone mapped
+    pub fn draw(&self, texture: u32, tonemap: &crate::gfx::tonemap::Tonemap) {
+        unsafe {
+            gl::Disable(gl::DEPTH_TEST);
+            gl::UseProgram(self.program);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            let image_id = CString::new("image").expect("CString::new failed");
+            gl::Uniform1i(gl::GetUniformLocation(self.program, image_id.as_ptr()), 0);
+
+            tonemap.upload(self.program);
+
+            let frag_data_id = CString::new("FragColor").expect("CString:new failed");
+            gl::BindFragDataLocation(self.program, 0, frag_data_id.as_ptr());
+
+            gl::BindVertexArray(self.vao);
+            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+
+            gl::BindVertexArray(0);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::UseProgram(0);
+            gl::Enable(gl::DEPTH_TEST);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use nalgebra as na;
+
+#[derive(Clone, Copy)]
+pub struct Ray {
+    pub origin: na::Point3<f32>,
+    pub dir: na::Vector3<f32>,
+    // hits further along the ray than this are ignored
+    pub t_max: f32,
+    // when the ray was cast, relative to the simulation's current state; moving
+    // objects are wherever they'd be at that time
+    pub time: f32,
+}
+
+pub fn new(origin: na::Point3<f32>, dir: na::Vector3<f32>) -> Ray {
+    Ray{
+        origin,
+        dir,
+        t_max: std::f32::INFINITY,
+        time: 0.0,
+    }
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> na::Point3<f32> {
+        self.origin + self.dir * t
+    }
+
+    // a bounce or shadow ray, cast at the same time as this one
+    pub fn secondary(&self, origin: na::Point3<f32>, dir: na::Vector3<f32>) -> Ray {
+        Ray{ time: self.time, ..new(origin, dir) }
+    }
+}
+
+pub struct Hit {
+    // distance along the ray, in units of ray.dir
+    pub t: f32,
+    // barycentric weights of the triangle's second and third vertices
+    pub u: f32,
+    pub v: f32,
+    // geometric (face) normal in world space, normalized
+    pub normal: na::Vector3<f32>,
+    // index of the triangle within its mesh
+    pub triangle: usize,
+    // where the ray hit, in the mesh's own space
+    pub local: na::Point3<f32>,
+    // texture coordinates, for geometry that has its own (see
+    // gfx::primitive); a mesh's are looked up by triangle instead
+    pub uv: Option<na::Point2<f32>>,
+}
+
+// a model matrix along with its inverse, so a mesh can be queried in its own space
+pub struct Transform {
+    pub model: na::Matrix4<f32>,
+    pub inverse: na::Matrix4<f32>,
+}
+
+pub fn transform(model: na::Matrix4<f32>) -> Transform {
+    Transform{
+        model,
+        inverse: model.try_inverse().unwrap_or_else(na::Matrix4::identity),
+    }
+}
+
+impl Transform {
+    // the direction is not renormalized, so t is the same in both spaces
+    pub fn ray_to_local(&self, ray: &Ray) -> Ray {
+        Ray{
+            origin: self.inverse.transform_point(&ray.origin),
+            dir: self.inverse.transform_vector(&ray.dir),
+            t_max: ray.t_max,
+            time: ray.time,
+        }
+    }
+
+    pub fn point_to_world(&self, p: &na::Point3<f32>) -> na::Point3<f32> {
+        self.model.transform_point(p)
+    }
+
+    // normals go through the inverse transpose so non-uniform scale keeps them
+    // perpendicular; as a direction (w = 0), since the transpose moves the
+    // translation into the bottom row, where transform_vector would divide by it
+    pub fn normal_to_world(&self, n: &na::Vector3<f32>) -> na::Vector3<f32> {
+        (self.inverse.transpose() * n.to_homogeneous()).xyz().normalize()
+    }
+}
+
+// Per-ray setup for the watertight test (Woop, Benthin, Wald 2013): the ray is
+// sheared so it points down +z, and triangles are tested in 2d. Edges shared by
+// two triangles are never missed, so rays can't slip between a cube's faces.
+pub struct Shear {
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    sx: f32,
+    sy: f32,
+    sz: f32,
+}
+
+pub fn shear(ray: &Ray) -> Shear {
+    let d = ray.dir;
+
+    let mut kz = 0;
+    if d[1].abs() > d[kz].abs() { kz = 1; }
+    if d[2].abs() > d[kz].abs() { kz = 2; }
+
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+
+    // keep the winding of the triangle when looking down -z
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    Shear{
+        kx,
+        ky,
+        kz,
+        sx: d[kx] / d[kz],
+        sy: d[ky] / d[kz],
+        sz: 1.0 / d[kz],
+    }
+}
+
+// returns (t, u, v); acceleration structures call this directly so the shear is
+// only computed once per ray
+pub fn intersect_sheared(ray: &Ray, s: &Shear, triangle: &crate::gfx::Triangle) -> Option<(f32, f32, f32)> {
+    let a = triangle[0] - ray.origin;
+    let b = triangle[1] - ray.origin;
+    let c = triangle[2] - ray.origin;
+
+    let ax = a[s.kx] - s.sx * a[s.kz];
+    let ay = a[s.ky] - s.sy * a[s.kz];
+    let bx = b[s.kx] - s.sx * b[s.kz];
+    let by = b[s.ky] - s.sy * b[s.kz];
+    let cx = c[s.kx] - s.sx * c[s.kz];
+    let cy = c[s.ky] - s.sy * c[s.kz];
+
+    let mut e0 = bx * cy - by * cx;
+    let mut e1 = cx * ay - cy * ax;
+    let mut e2 = ax * by - ay * bx;
+
+    // exactly on an edge in single precision, redo the edge tests in double
+    if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+        e0 = (bx as f64 * cy as f64 - by as f64 * cx as f64) as f32;
+        e1 = (cx as f64 * ay as f64 - cy as f64 * ax as f64) as f32;
+        e2 = (ax as f64 * by as f64 - ay as f64 * bx as f64) as f32;
+    }
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None;
+    }
+
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = s.sz * a[s.kz];
+    let bz = s.sz * b[s.kz];
+    let cz = s.sz * c[s.kz];
+    let t_scaled = e0 * az + e1 * bz + e2 * cz;
+
+    // compare against the range without dividing by det first
+    if det < 0.0 && (t_scaled >= 0.0 || t_scaled < ray.t_max * det) {
+        return None;
+    }
+    if det > 0.0 && (t_scaled <= 0.0 || t_scaled > ray.t_max * det) {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    Some((t_scaled * inv_det, e1 * inv_det, e2 * inv_det))
+}
+
+pub fn face_normal(triangle: &crate::gfx::Triangle) -> na::Vector3<f32> {
+    (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(ray: &Ray, mesh: &crate::gfx::Mesh) -> usize {
+        let s = shear(ray);
+        mesh.iter().filter(|t| intersect_sheared(ray, &s, t).is_some()).count()
+    }
+
+    #[test]
+    fn hit_has_distance_and_barycentrics() {
+        let triangle = [na::Point3::new(0.0, 0.0, 2.0), na::Point3::new(4.0, 0.0, 2.0), na::Point3::new(0.0, 4.0, 2.0)];
+        let ray = new(na::Point3::new(1.0, 2.0, 0.0), na::Vector3::z());
+        let (t, u, v) = intersect_sheared(&ray, &shear(&ray), &triangle).unwrap();
+
+        assert!((t - 2.0).abs() < 1e-6);
+        assert!((u - 0.25).abs() < 1e-6 && (v - 0.5).abs() < 1e-6);
+        assert!(intersect_sheared(&Ray{ t_max: 1.5, ..ray }, &shear(&ray), &triangle).is_none());
+    }
+
+    #[test]
+    fn shared_edges_are_never_missed() {
+        // a unit square split along its diagonal
+        let (a, b, c, d) = (na::Point3::new(0.0, 0.0, 0.0), na::Point3::new(1.0, 0.0, 0.0), na::Point3::new(1.0, 1.0, 0.0), na::Point3::new(0.0, 1.0, 0.0));
+        let square = vec![[a, b, c], [a, c, d]];
+
+        for dir in [na::Vector3::z(), na::Vector3::new(0.3, -0.2, 1.0), na::Vector3::new(-0.7, 0.4, 0.5)].iter() {
+            for i in 1..64 {
+                let x = i as f32 / 64.0 + 0.1 / 7.0;
+                let on_diagonal = na::Point3::new(x, x, 0.0);
+                let ray = new(on_diagonal - dir, *dir);
+                assert!(hits(&ray, &square) >= 1, "missed the diagonal at {} going {:?}", x, dir);
+            }
+        }
+
+        // the outer edges count too
+        let ray = new(na::Point3::new(0.0, 0.5, -1.0), na::Vector3::z());
+        assert_eq!(hits(&ray, &square), 1);
+    }
+
+    #[test]
+    fn shared_vertices_are_never_missed() {
+        // a hexagon fanned around the origin
+        let corner = |i: usize| {
+            let a = i as f32 * std::f32::consts::PI / 3.0;
+            na::Point3::new(a.cos(), a.sin(), 0.0)
+        };
+        let fan: crate::gfx::Mesh = (0..6).map(|i| [na::Point3::origin(), corner(i), corner(i + 1)]).collect();
+
+        for dir in [na::Vector3::z(), -na::Vector3::z(), na::Vector3::new(0.3, -0.2, 1.0), na::Vector3::new(1.0, 1.0, 0.1)].iter() {
+            let ray = new(na::Point3::origin() - dir, *dir);
+            assert!(hits(&ray, &fan) >= 1, "missed the center going {:?}", dir);
+        }
+        for i in 0..6 {
+            let ray = new(corner(i) - na::Vector3::z(), na::Vector3::z());
+            assert!(hits(&ray, &fan) >= 1, "missed corner {}", i);
+        }
+    }
+
+    #[test]
+    fn transform_keeps_distances_and_turns_normals() {
+        // moved 5 along z and stretched 2 along it
+        let model = na::Matrix4::new_translation(&na::Vector3::new(0.0, 0.0, 5.0)) * na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(1.0, 1.0, 2.0));
+        let t = transform(model);
+
+        // world z = 7 is local z = 1, at the same t
+        let ray = new(na::Point3::new(1.0, 1.0, 0.0), na::Vector3::z());
+        let local = t.ray_to_local(&ray);
+        assert!((local.at(7.0) - na::Point3::new(1.0, 1.0, 1.0)).magnitude() < 1e-5);
+        assert!((t.point_to_world(&local.at(7.0)) - ray.at(7.0)).magnitude() < 1e-5);
+
+        // a 45 degree slope in local space is half as steep once stretched
+        assert!((t.normal_to_world(&na::Vector3::z()) - na::Vector3::z()).magnitude() < 1e-5);
+        let n = t.normal_to_world(&na::Vector3::new(1.0, 0.0, 1.0));
+        assert!((n - na::Vector3::new(1.0, 0.0, 0.5).normalize()).magnitude() < 1e-5);
+    }
+}
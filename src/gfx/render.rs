@@ -1,22 +1,27 @@
 use std::ffi::CString;
 use nalgebra as na;
-use gl::types::{GLfloat, GLsizeiptr, GLuint, GLint, GLboolean, GLvoid};
+use gl::types::{GLfloat, GLuint, GLint, GLboolean, GLvoid};
 use std::convert::TryFrom;
 
 pub struct Params {
     pub program: crate::gfx::shader::Program,
     pub camera: na::Matrix4<f32>,
+    // camera position in world space
+    pub eye: na::Vector3<f32>,
+    pub lights: std::vec::Vec<crate::gfx::light::Light>,
+    // reflected and used for ambient light when set
+    pub environment: Option<std::sync::Arc<crate::gfx::environment::Environment>>,
     pub clock: f32,
     pub width: i32,
     pub height: i32,
 }
 
 pub struct Renderer {
-    pub scale: f32,
-    pub mesh: crate::gfx::Mesh,
-    pub color: crate::gfx::ColorFn,
-    vao: u32,
-    vbo: u32,
+    // along each of the geometry's axes, before the object's rotation
+    pub scale: na::Vector3<f32>,
+    pub geometry: crate::gfx::geometry::Handle,
+    pub material: crate::gfx::material::Material,
+    buffer: crate::gfx::geometry::Buffer,
 }
 
 impl Renderer {
@@ -24,13 +29,17 @@ impl Renderer {
         unsafe {
             gl::UseProgram(params.program);
 
-            gl::BindVertexArray(self.vao);
-            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            gl::BindVertexArray(self.buffer.vao);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.buffer.vbo);
 
             let uniform_model_id = CString::new("model").expect("CString::new failed");
             let uniform_model = gl::GetUniformLocation(params.program, uniform_model_id.as_ptr());
             gl::UniformMatrix4fv(uniform_model, 1, gl::FALSE, std::mem::transmute(&self.transformation(phys)[0]));
 
+            let uniform_scale_id = CString::new("scale").expect("CString::new failed");
+            let uniform_scale = gl::GetUniformLocation(params.program, uniform_scale_id.as_ptr());
+            gl::Uniform3f(uniform_scale, self.scale.x, self.scale.y, self.scale.z);
+
             let uniform_camera_id = CString::new("camera").expect("CString::new failed");
             let uniform_camera = gl::GetUniformLocation(params.program, uniform_camera_id.as_ptr());
             gl::UniformMatrix4fv(uniform_camera, 1, gl::FALSE, std::mem::transmute(&params.camera[0]));
@@ -40,6 +49,61 @@ impl Renderer {
             let uniform_clock = gl::GetUniformLocation(params.program, uniform_clock_id.as_ptr());
             gl::Uniform1f(uniform_clock, params.clock);
 
+            let uniform_eye_id = CString::new("eye").expect("CString::new failed");
+            let uniform_eye = gl::GetUniformLocation(params.program, uniform_eye_id.as_ptr());
+            gl::Uniform3f(uniform_eye, params.eye.x, params.eye.y, params.eye.z);
+
+            let uniform_material_id = CString::new("material").expect("CString::new failed");
+            let uniform_material = gl::GetUniformLocation(params.program, uniform_material_id.as_ptr());
+            gl::Uniform1i(uniform_material, self.material.kind());
+
+            let uniform_parameter_id = CString::new("parameter").expect("CString::new failed");
+            let uniform_parameter = gl::GetUniformLocation(params.program, uniform_parameter_id.as_ptr());
+            gl::Uniform1f(uniform_parameter, self.material.parameter());
+
+            let emission = self.material.emission();
+            let uniform_emission_id = CString::new("emission").expect("CString::new failed");
+            let uniform_emission = gl::GetUniformLocation(params.program, uniform_emission_id.as_ptr());
+            gl::Uniform3f(uniform_emission, emission[0], emission[1], emission[2]);
+
+            let albedo = self.material.albedo();
+            let texture = albedo.texture();
+            let uniform_textured_id = CString::new("textured").expect("CString::new failed");
+            let uniform_textured = gl::GetUniformLocation(params.program, uniform_textured_id.as_ptr());
+            gl::Uniform1i(uniform_textured, texture.is_some() as GLint);
+
+            if let Some(t) = texture {
+                gl::ActiveTexture(gl::TEXTURE0);
+                t.bind();
+
+                let uniform_albedo_map_id = CString::new("albedoMap").expect("CString::new failed");
+                let uniform_albedo_map = gl::GetUniformLocation(params.program, uniform_albedo_map_id.as_ptr());
+                gl::Uniform1i(uniform_albedo_map, 0);
+            }
+
+            crate::gfx::procedural::upload(params.program, albedo.pattern())?;
+
+            let uniform_has_environment_id = CString::new("hasEnvironment").expect("CString::new failed");
+            let uniform_has_environment = gl::GetUniformLocation(params.program, uniform_has_environment_id.as_ptr());
+            // reflections go without the environment until it's ready
+            let environment = params.environment.as_ref().filter(|e| e.ready());
+            gl::Uniform1i(uniform_has_environment, environment.is_some() as GLint);
+
+            if let Some(e) = environment {
+                gl::ActiveTexture(gl::TEXTURE1);
+                e.bind();
+
+                let uniform_environment_map_id = CString::new("environmentMap").expect("CString::new failed");
+                let uniform_environment_map = gl::GetUniformLocation(params.program, uniform_environment_map_id.as_ptr());
+                gl::Uniform1i(uniform_environment_map, 1);
+
+                let uniform_environment_levels_id = CString::new("environmentLevels").expect("CString::new failed");
+                let uniform_environment_levels = gl::GetUniformLocation(params.program, uniform_environment_levels_id.as_ptr());
+                gl::Uniform1i(uniform_environment_levels, crate::gfx::environment::CUBEMAP_LEVELS as GLint);
+            }
+
+            crate::gfx::light::upload(params.program, &params.lights);
+
             let uniform_dimensions_id = CString::new("dimensions").expect("CString::new failed");
             let uniform_dimensions = gl::GetUniformLocation(params.program, uniform_dimensions_id.as_ptr());
             gl::Uniform2i(uniform_dimensions, params.width as GLint, params.height as GLint);
@@ -47,16 +111,18 @@ impl Renderer {
             // Specify the layout of the vertex data
             let attrib_position_id = CString::new("attribPosition").expect("CString:new failed");
             let attrib_color_id = CString::new("attribColor").expect("CString:new failed");
+            let attrib_uv_id = CString::new("attribUv").expect("CString:new failed");
 
             let attrib_position = gl::GetAttribLocation(params.program, attrib_position_id.as_ptr());
             let attrib_color = gl::GetAttribLocation(params.program, attrib_color_id.as_ptr());
+            let attrib_uv = gl::GetAttribLocation(params.program, attrib_uv_id.as_ptr());
 
             gl::VertexAttribPointer(
                 attrib_position as GLuint,
                 3,
                 gl::FLOAT,
                 gl::FALSE as GLboolean,
-                (6 * std::mem::size_of::<GLfloat>()) as GLint,
+                (8 * std::mem::size_of::<GLfloat>()) as GLint,
                 (0 * std::mem::size_of::<GLfloat>()) as *const GLvoid,
             );
 
@@ -65,17 +131,27 @@ impl Renderer {
                 3,
                 gl::FLOAT,
                 gl::FALSE as GLboolean,
-                (6 * std::mem::size_of::<GLfloat>()) as GLint,
+                (8 * std::mem::size_of::<GLfloat>()) as GLint,
                 (3 * std::mem::size_of::<GLfloat>()) as *const GLvoid,
             );
 
+            gl::VertexAttribPointer(
+                attrib_uv as GLuint,
+                2,
+                gl::FLOAT,
+                gl::FALSE as GLboolean,
+                (8 * std::mem::size_of::<GLfloat>()) as GLint,
+                (6 * std::mem::size_of::<GLfloat>()) as *const GLvoid,
+            );
+
             gl::EnableVertexAttribArray(attrib_position as GLuint);
             gl::EnableVertexAttribArray(attrib_color as GLuint);
+            gl::EnableVertexAttribArray(attrib_uv as GLuint);
 
             let frag_data_id = CString::new("FragColor").expect("CString:new failed");
             gl::BindFragDataLocation(params.program, 0, frag_data_id.as_ptr());
 
-            gl::DrawArrays(gl::TRIANGLES, 0, self.mesh.len() as i32 * 3);
+            gl::DrawArrays(gl::TRIANGLES, 0, self.buffer.count);
 
             gl::UseProgram(0);
         }
@@ -89,86 +165,22 @@ impl Renderer {
         std::vec::Vec::from(m.as_slice())
     }
 
-    fn mat_model(&self, phys: &crate::physics::Physics) -> na::Matrix4<f32> {
+    pub fn mat_model(&self, phys: &crate::physics::Physics) -> na::Matrix4<f32> {
         phys.mat_translation() * phys.mat_rotation() * self.mat_scale()
     }
 
-    fn vertices(&self) -> std::vec::Vec<GLfloat> {
-        let mut v: Vec<GLfloat> = Vec::with_capacity(self.mesh.len());
-
-        let color_fn = &self.color;
-
-        // iterate over triangles
-        let mut counter: i32 = 0;
-        for  &t in self.mesh.iter() {
-
-            // iterate over points in triangles
-            for &p in &t {
-                v.extend(vec![
-                    p.x as GLfloat,
-                    p.y as GLfloat,
-                    p.z as GLfloat,
-                ]);
-
-                let color = color_fn(counter);
-                // interleaved vertex and color
-                v.extend(vec![
-                    color[0] as GLfloat,
-                    color[1] as GLfloat,
-                    color[2] as GLfloat,
-                ]);
-
-                counter += 1;
-            }
-        }
-
-        v
-    }
-
-    fn mat_scale(&self) -> na::Matrix4<f32> {
-        let s = self.scale;
-
-        // column major format
-        na::Matrix4::from_vec(vec![
-            s, 0.0, 0.0, 0.0,
-            0.0,   s, 0.0, 0.0,
-            0.0, 0.0,   s, 0.0,
-            0.0, 0.0, 0.0, 1.0,
-        ])
-    }
-
-    fn init(&mut self) {
-        let v = self.vertices();
-        let mut vao = 0;
-        let mut vbo = 0;
-
-        unsafe {
-            gl::GenVertexArrays(1, &mut vao);
-            gl::GenBuffers(1, &mut vbo);
-            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
-            gl::BufferData(
-                gl::ARRAY_BUFFER,
-                (v.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
-                std::mem::transmute(&v[0]),
-                gl::STATIC_DRAW,
-            );
-        }
-
-        self.vao = vao;
-        self.vbo = vbo;
+    pub fn mat_scale(&self) -> na::Matrix4<f32> {
+        na::Matrix4::new_nonuniform_scaling(&self.scale)
     }
 }
 
-pub fn new(scale: f32, mesh: crate::gfx::Mesh, color: crate::gfx::ColorFn) -> Renderer {
-    let mut r = Renderer{
+// draws the registered geometry; the buffer is shared with everything else
+// drawing the same geometry in the same vertex colors
+pub fn new(registry: &mut crate::gfx::geometry::Registry, geometry: crate::gfx::geometry::Handle, scale: na::Vector3<f32>, material: crate::gfx::material::Material) -> Renderer {
+    Renderer{
         scale,
-        mesh,
-        color,
-        vao: 0,
-        vbo: 0,
-    };
-
-    r.init();
-
-    r
+        geometry,
+        buffer: registry.buffer(geometry, &material.albedo()),
+        material,
+    }
 }
@@ -0,0 +1,98 @@
+use std::ffi::CString;
+use gl::types::{GLfloat, GLsizeiptr, GLuint, GLint, GLboolean, GLvoid};
+
+// Draws the environment behind everything else: a triangle covering the
+// screen, with each pixel's view direction recovered from the inverse of the
+// camera matrix.
+pub struct Skybox {
+    program: crate::gfx::shader::Program,
+    vao: u32,
+}
+
+pub fn new() -> Result<Skybox, std::string::String> {
+    let vs = crate::gfx::shader::compile_shader(include_str!("../shaders/skybox_vertex.glsl"), crate::gfx::shader::Type::Vertex)?;
+    let fs = crate::gfx::shader::compile_shader(include_str!("../shaders/skybox_fragment.glsl"), crate::gfx::shader::Type::Fragment)?;
+    let program = crate::gfx::shader::link_program(vs, fs)?;
+
+    // one triangle whose inside covers all of clip space
+    let v: [GLfloat; 6] = [
+        -1.0, -1.0,
+         3.0, -1.0,
+        -1.0,  3.0,
+    ];
+
+    let mut vao = 0;
+    let mut vbo = 0;
+
+    unsafe {
+        gl::GenVertexArrays(1, &mut vao);
+        gl::GenBuffers(1, &mut vbo);
+        gl::BindVertexArray(vao);
+        gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            (v.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
+            v.as_ptr() as *const GLvoid,
+            gl::STATIC_DRAW,
+        );
+
+        let attrib_position_id = CString::new("attribPosition").expect("CString:new failed");
+        let attrib_position = gl::GetAttribLocation(program, attrib_position_id.as_ptr());
+
+        gl::VertexAttribPointer(
+            attrib_position as GLuint,
+            2,
+            gl::FLOAT,
+            gl::FALSE as GLboolean,
+            (2 * std::mem::size_of::<GLfloat>()) as GLint,
+            std::ptr::null(),
+        );
+
+        gl::EnableVertexAttribArray(attrib_position as GLuint);
+        gl::BindVertexArray(0);
+    }
+
+    Ok(Skybox{ program, vao })
+}
+
+impl Skybox {
+    // call first thing in a frame; it doesn't write depth, so the scene draws over it
+    pub fn render(&self, environment: &crate::gfx::environment::Environment, camera: &crate::gfx::camera::Camera) {
+        // the clear color stands in until the cube map is ready
+        if !environment.ready() {
+            return;
+        }
+
+        let inverse = camera.transformation().try_inverse().unwrap_or_else(nalgebra::Matrix4::identity);
+
+        unsafe {
+            gl::Disable(gl::DEPTH_TEST);
+            gl::DepthMask(gl::FALSE);
+            gl::UseProgram(self.program);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            environment.bind();
+            let environment_id = CString::new("environmentMap").expect("CString::new failed");
+            gl::Uniform1i(gl::GetUniformLocation(self.program, environment_id.as_ptr()), 0);
+
+            let inverse_id = CString::new("inverseCamera").expect("CString::new failed");
+            gl::UniformMatrix4fv(gl::GetUniformLocation(self.program, inverse_id.as_ptr()), 1, gl::FALSE, inverse.as_ptr());
+
+            let eye = camera.phys.pos;
+            let eye_id = CString::new("eye").expect("CString::new failed");
+            gl::Uniform3f(gl::GetUniformLocation(self.program, eye_id.as_ptr()), eye.x, eye.y, eye.z);
+
+            let frag_data_id = CString::new("FragColor").expect("CString:new failed");
+            gl::BindFragDataLocation(self.program, 0, frag_data_id.as_ptr());
+
+            gl::BindVertexArray(self.vao);
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+
+            gl::BindVertexArray(0);
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+            gl::UseProgram(0);
+            gl::DepthMask(gl::TRUE);
+            gl::Enable(gl::DEPTH_TEST);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use nalgebra as na;
+use gl::types::{GLfloat, GLint, GLvoid};
+
+// Image textures, sampled the same way by the GL shaders and the CPU tracer.
+// Texels are kept as linear floats with the bottom row first, the way GL
+// lays them out, so uv (0, 0) is the bottom left corner of the image.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    // the edge texels continue forever
+    Clamp,
+    // every other repeat is flipped, so there's no seam
+    Mirror,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub wrap: Wrap,
+    pub filter: Filter,
+    pixels: std::vec::Vec<crate::gfx::Color>,
+    // GL texture name, made the first time the texture is bound, and the
+    // thread it was made on, whose context it belongs to
+    name: std::sync::OnceLock<(u32, std::thread::ThreadId)>,
+}
+
+// pixels start at the bottom left corner, row by row
+pub fn new(width: usize, height: usize, pixels: std::vec::Vec<crate::gfx::Color>) -> Texture {
+    Texture{
+        width,
+        height,
+        wrap: Wrap::Repeat,
+        filter: Filter::Bilinear,
+        pixels,
+        name: std::sync::OnceLock::new(),
+    }
+}
+
+// 8-bit images are taken to be sRGB encoded and converted to linear
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// png, jpeg, or radiance hdr
+pub fn load(path: &str) -> Result<Texture, std::string::String> {
+    let hdr = path.to_lowercase().ends_with(".hdr");
+
+    let (width, height, rows) = if hdr {
+        let f = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let decoder = image::hdr::HdrDecoder::new(std::io::BufReader::new(f)).map_err(|e| format!("{}: {}", path, e))?;
+        let meta = decoder.metadata();
+        let texels = decoder.read_image_hdr().map_err(|e| format!("{}: {}", path, e))?;
+        let pixels: std::vec::Vec<crate::gfx::Color> = texels.iter().map(|p| [p[0], p[1], p[2]]).collect();
+        (meta.width as usize, meta.height as usize, pixels)
+    } else {
+        let img = image::open(path).map_err(|e| format!("{}: {}", path, e))?.into_rgb8();
+        let pixels = img.pixels().map(|p| [srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2])]).collect();
+        (img.width() as usize, img.height() as usize, pixels)
+    };
+
+    if width == 0 || height == 0 {
+        return Err(format!("{}: empty image", path));
+    }
+
+    // images are stored top row first
+    let mut flipped = std::vec::Vec::with_capacity(rows.len());
+    for y in (0..height).rev() {
+        flipped.extend_from_slice(&rows[y * width..(y + 1) * width]);
+    }
+
+    Ok(new(width, height, flipped))
+}
+
+// i into 0..n according to the wrap mode
+fn wrap(i: i64, n: usize, mode: Wrap) -> usize {
+    let n = n as i64;
+    match mode {
+        Wrap::Repeat => i.rem_euclid(n) as usize,
+        Wrap::Clamp => i.clamp(0, n - 1) as usize,
+        Wrap::Mirror => {
+            let k = i.rem_euclid(2 * n);
+            (if k < n { k } else { 2 * n - 1 - k }) as usize
+        },
+    }
+}
+
+impl Texture {
+    pub fn texel(&self, x: i64, y: i64) -> crate::gfx::Color {
+        self.pixels[wrap(y, self.height, self.wrap) * self.width + wrap(x, self.width, self.wrap)]
+    }
+
+    // texel centers are at half integers, as in GL
+    pub fn sample(&self, uv: &na::Point2<f32>) -> crate::gfx::Color {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+
+        match self.filter {
+            Filter::Nearest => self.texel((x + 0.5).floor() as i64, (y + 0.5).floor() as i64),
+            Filter::Bilinear => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+
+                let a = self.texel(x0, y0);
+                let b = self.texel(x0 + 1, y0);
+                let c = self.texel(x0, y0 + 1);
+                let d = self.texel(x0 + 1, y0 + 1);
+
+                let mut out = [0.0; 3];
+                for k in 0..3 {
+                    let bottom = a[k] * (1.0 - fx) + b[k] * fx;
+                    let top = c[k] * (1.0 - fx) + d[k] * fx;
+                    out[k] = bottom * (1.0 - fy) + top * fy;
+                }
+                out
+            },
+        }
+    }
+
+    // binds the texture to the active texture unit, uploading it the first time
+    pub fn bind(&self) {
+        let (name, _) = *self.name.get_or_init(|| (self.upload(), std::thread::current().id()));
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, name);
+        }
+    }
+
+    fn upload(&self) -> u32 {
+        let mut data: std::vec::Vec<GLfloat> = std::vec::Vec::with_capacity(self.pixels.len() * 3);
+        for c in self.pixels.iter() {
+            data.extend_from_slice(c);
+        }
+
+        let wrap = match self.wrap {
+            Wrap::Repeat => gl::REPEAT,
+            Wrap::Clamp => gl::CLAMP_TO_EDGE,
+            Wrap::Mirror => gl::MIRRORED_REPEAT,
+        };
+        let filter = match self.filter {
+            Filter::Nearest => gl::NEAREST,
+            Filter::Bilinear => gl::LINEAR,
+        };
+
+        let mut name = 0;
+        unsafe {
+            gl::GenTextures(1, &mut name);
+            gl::BindTexture(gl::TEXTURE_2D, name);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, filter as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, filter as GLint);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGB32F as GLint,
+                self.width as GLint,
+                self.height as GLint,
+                0,
+                gl::RGB,
+                gl::FLOAT,
+                data.as_ptr() as *const GLvoid,
+            );
+        }
+        name
+    }
+}
+
+// deletes the GL texture, if there is one. A texture shared with the tracer can
+// be dropped last on one of its threads, with no context to delete it from; it
+// goes when the window's context does
+impl Drop for Texture {
+    fn drop(&mut self) {
+        if let Some((name, thread)) = self.name.get() {
+            if *thread == std::thread::current().id() {
+                unsafe {
+                    gl::DeleteTextures(1, name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 x 2, each texel's red is its x and green its y
+    fn ramp() -> Texture {
+        let pixels = (0..8).map(|i| [(i % 4) as f32, (i / 4) as f32, 1.0]).collect();
+        new(4, 2, pixels)
+    }
+
+    #[test]
+    fn wrap_modes_pick_the_right_texel() {
+        let mut t = ramp();
+        let xs = |t: &Texture| (-5..9).map(|x| t.texel(x, 0)[0] as i64).collect::<std::vec::Vec<_>>();
+
+        t.wrap = Wrap::Repeat;
+        assert_eq!(xs(&t), vec![3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0]);
+        t.wrap = Wrap::Clamp;
+        assert_eq!(xs(&t), vec![0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3]);
+        t.wrap = Wrap::Mirror;
+        assert_eq!(xs(&t), vec![3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0]);
+
+        // rows wrap the same way
+        t.wrap = Wrap::Repeat;
+        assert_eq!(t.texel(0, 3)[1], 1.0);
+        t.wrap = Wrap::Clamp;
+        assert_eq!(t.texel(0, -3)[1], 0.0);
+        t.wrap = Wrap::Mirror;
+        assert_eq!(t.texel(0, 2)[1], 1.0);
+    }
+
+    #[test]
+    fn bilinear_blends_between_texel_centers() {
+        let mut t = ramp();
+        t.wrap = Wrap::Clamp;
+        let at = |t: &Texture, u: f32, v: f32| t.sample(&na::Point2::new(u, v));
+
+        // on a texel center it's exactly that texel
+        assert_eq!(at(&t, 0.375, 0.25), [1.0, 0.0, 1.0]);
+        // halfway between centers, and a quarter of the way up between the rows
+        let c = at(&t, 0.5, 0.375);
+        assert!((c[0] - 1.5).abs() < 1e-5 && (c[1] - 0.25).abs() < 1e-5, "{:?}", c);
+        // a ramp stays linear in between
+        for i in 0..=10 {
+            let u = 0.125 + 0.75 * i as f32 / 10.0;
+            assert!((at(&t, u, 0.25)[0] - 3.0 * i as f32 / 10.0).abs() < 1e-4);
+        }
+
+        t.filter = Filter::Nearest;
+        assert_eq!(at(&t, 0.49, 0.1), [1.0, 0.0, 1.0]);
+        assert_eq!(at(&t, 0.51, 0.9), [2.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn bilinear_wraps_at_the_edges() {
+        let mut t = ramp();
+        // between the last and first texel of a row
+        t.wrap = Wrap::Repeat;
+        assert!((t.sample(&na::Point2::new(0.0, 0.25))[0] - 1.5).abs() < 1e-5);
+        t.wrap = Wrap::Clamp;
+        assert!((t.sample(&na::Point2::new(0.0, 0.25))[0] - 0.0).abs() < 1e-5);
+        t.wrap = Wrap::Mirror;
+        assert!((t.sample(&na::Point2::new(1.0, 0.25))[0] - 3.0).abs() < 1e-5);
+    }
+}
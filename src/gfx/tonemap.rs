@@ -0,0 +1,202 @@
+use std::ffi::CString;
+use gl::types::GLint;
+
+// Turns linear HDR radiance into display values: scale by the exposure,
+// compress with a tone mapping curve, then encode for the screen. The CPU
+// tracer applies it when writing 8-bit images, quad_fragment.glsl when
+// showing float framebuffers in the window; the two must stay in step.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operator {
+    // anything above 1 is cut off
+    Clamp,
+    // x / (1 + x)
+    Reinhard,
+    // Hable's Uncharted 2 filmic curve
+    Hable,
+    // Narkowicz's fit of the ACES reference rendering transform
+    Aces,
+}
+
+pub const OPERATORS: [Operator; 4] = [Operator::Clamp, Operator::Reinhard, Operator::Hable, Operator::Aces];
+
+#[derive(Clone, Copy)]
+pub struct Tonemap {
+    pub operator: Operator,
+    // in stops; each one doubles the brightness
+    pub exposure: f32,
+    // None encodes with the sRGB curve, otherwise a plain 1 / gamma power
+    pub gamma: Option<f32>,
+}
+
+pub fn new() -> Tonemap {
+    Tonemap{ operator: Operator::Clamp, exposure: 0.0, gamma: None }
+}
+
+pub fn operator(name: &str) -> Option<Operator> {
+    match name {
+        "clamp" => Some(Operator::Clamp),
+        "reinhard" => Some(Operator::Reinhard),
+        "hable" => Some(Operator::Hable),
+        "aces" => Some(Operator::Aces),
+        _ => None,
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn hable_curve(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// linear white point the curve maps to 1
+const HABLE_WHITE: f32 = 11.2;
+// the curve is dark at 1, so it's fed twice the exposure
+const HABLE_BIAS: f32 = 2.0;
+
+impl Operator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operator::Clamp => "clamp",
+            Operator::Reinhard => "reinhard",
+            Operator::Hable => "hable",
+            Operator::Aces => "aces",
+        }
+    }
+
+    // the operator after this one, wrapping around
+    pub fn next(&self) -> Operator {
+        let i = OPERATORS.iter().position(|o| o == self).unwrap_or(0);
+        OPERATORS[(i + 1) % OPERATORS.len()]
+    }
+
+    // index into quad_fragment.glsl's operators
+    pub fn id(&self) -> i32 {
+        match self {
+            Operator::Clamp => 0,
+            Operator::Reinhard => 1,
+            Operator::Hable => 2,
+            Operator::Aces => 3,
+        }
+    }
+
+    // one channel of exposed linear radiance to [0, 1]
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        let y = match self {
+            Operator::Clamp => x,
+            Operator::Reinhard => x / (1.0 + x),
+            Operator::Hable => hable_curve(x * HABLE_BIAS) / hable_curve(HABLE_WHITE),
+            Operator::Aces => {
+                let x = x * 0.6;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            },
+        };
+        y.clamp(0.0, 1.0)
+    }
+}
+
+impl Tonemap {
+    // display encoded color, each channel in [0, 1]
+    pub fn apply(&self, c: crate::gfx::Color) -> crate::gfx::Color {
+        let scale = self.exposure.exp2();
+        let mut out = [0.0; 3];
+        for i in 0..3 {
+            let y = self.operator.apply(c[i] * scale);
+            out[i] = match self.gamma {
+                Some(g) => y.powf(1.0 / g),
+                None => linear_to_srgb(y),
+            };
+        }
+        out
+    }
+
+    // sets quad_fragment.glsl's uniforms; the program has to be in use
+    pub fn upload(&self, program: crate::gfx::shader::Program) {
+        unsafe {
+            let operator_id = CString::new("tonemap").expect("CString::new failed");
+            gl::Uniform1i(gl::GetUniformLocation(program, operator_id.as_ptr()), self.operator.id() as GLint);
+
+            let exposure_id = CString::new("exposure").expect("CString::new failed");
+            gl::Uniform1f(gl::GetUniformLocation(program, exposure_id.as_ptr()), self.exposure.exp2());
+
+            // 0 picks the sRGB curve
+            let gamma_id = CString::new("gamma").expect("CString::new failed");
+            gl::Uniform1f(gl::GetUniformLocation(program, gamma_id.as_ptr()), self.gamma.unwrap_or(0.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    // every curve starts at black, never goes down, and stays in [0, 1]
+    fn check_curve(o: Operator) {
+        assert!(close(o.apply(0.0), 0.0));
+        assert!(close(o.apply(-1.0), 0.0));
+        let mut last = 0.0;
+        for i in 0..1000 {
+            let y = o.apply(i as f32 * 0.05);
+            assert!(y >= last && y <= 1.0, "{:?} at {}: {}", o, i as f32 * 0.05, y);
+            last = y;
+        }
+    }
+
+    #[test]
+    fn reinhard_halves_one() {
+        check_curve(Operator::Reinhard);
+        assert!(close(Operator::Reinhard.apply(1.0), 0.5));
+        assert!(close(Operator::Reinhard.apply(3.0), 0.75));
+        assert!(Operator::Reinhard.apply(1e6) < 1.0);
+    }
+
+    #[test]
+    fn hable_reaches_white_at_its_white_point() {
+        check_curve(Operator::Hable);
+        assert!(close(Operator::Hable.apply(HABLE_WHITE / HABLE_BIAS), 1.0));
+        assert!(Operator::Hable.apply(1.0) < 1.0);
+    }
+
+    #[test]
+    fn aces_matches_the_fit() {
+        check_curve(Operator::Aces);
+        // 0.6 * (2.51 * 0.6 + 0.03) / (0.6 * (2.43 * 0.6 + 0.59) + 0.14)
+        assert!(close(Operator::Aces.apply(1.0), 0.67329));
+        assert_eq!(Operator::Aces.apply(100.0), 1.0);
+    }
+
+    #[test]
+    fn clamp_cuts_off_above_one() {
+        check_curve(Operator::Clamp);
+        assert_eq!(Operator::Clamp.apply(0.25), 0.25);
+        assert_eq!(Operator::Clamp.apply(4.0), 1.0);
+    }
+
+    #[test]
+    fn srgb_encoding() {
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!(close(linear_to_srgb(1.0), 1.0));
+        assert!(close(linear_to_srgb(0.5), 0.735357));
+        // the linear toe meets the power curve
+        assert!(close(linear_to_srgb(0.0031308), 0.04045));
+        assert!(close(linear_to_srgb(0.0031309), 0.04045));
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let mut t = new();
+        t.exposure = 1.0;
+        assert!(close(t.apply([0.25, 0.0, 0.0])[0], linear_to_srgb(0.5)));
+        t.exposure = -2.0;
+        t.gamma = Some(2.0);
+        assert!(close(t.apply([0.64, 0.0, 0.0])[0], 0.4));
+    }
+}
@@ -23,6 +23,12 @@ pub enum Action {
     TimeStop,
     BoxFaster,
     BoxSlower,
+    ToggleTrace,
+    NextTraceMode,
+    ToggleDenoise,
+    NextTonemap,
+    ExposureUp,
+    ExposureDown,
 }
 
 pub type Events = std::vec::Vec<Action>;
@@ -62,6 +68,12 @@ pub fn handle_events(events: &mut sdl2::EventPump) -> Events {
                     Keycode::Space => Action::TimeStop,
                     Keycode::Semicolon => Action::BoxFaster,
                     Keycode::Quote => Action::BoxSlower,
+                    Keycode::T => Action::ToggleTrace,
+                    Keycode::R => Action::NextTraceMode,
+                    Keycode::N => Action::ToggleDenoise,
+                    Keycode::M => Action::NextTonemap,
+                    Keycode::Equals => Action::ExposureUp,
+                    Keycode::Minus => Action::ExposureDown,
 
 
                     _ => Action::Continue,
@@ -1,5 +1,6 @@
 use sdl2::keyboard::Keycode;
 use rand::Rng;
+use rand::SeedableRng;
 use sdl2::video::GLProfile;
 use std::ffi::CString;
 use gl;
@@ -10,7 +11,9 @@ use nalgebra as na;
 mod gfx;
 mod physics;
 mod input;
+mod cli;
 mod shapes;
+mod trace;
 
 // traits
 use std::string::ToString;
@@ -18,7 +21,161 @@ use std::string::ToString;
 const WIDTH: i16 = 800;
 const HEIGHT: i16 = 600;
 
+// the ray traced preview renders at 1 / PREVIEW_SCALE of the window size
+const PREVIEW_SCALE: i32 = 2;
+
+// group of the glass prisms, which hang still instead of swarming
+const PRISMS: i32 = 4;
+
+// the cube swarm, the glass prisms (when spectral) and solids it circles, the
+// light panel above it, and the lights, with their geometry in the registry;
+// with a texture, every cube is covered in it instead of its group's pattern
+fn build<R: Rng>(rng: &mut R, registry: &mut gfx::geometry::Registry, texture: Option<std::sync::Arc<gfx::texture::Texture>>, spectral: bool) -> (std::vec::Vec<shapes::cube::Cube>, std::vec::Vec<shapes::solid::Solid>, shapes::rectangle::Rectangle, std::vec::Vec<gfx::light::Light>) {
+    // red marble mottled by noise, green wood with a turbulent grain, and blue
+    // cells shaded in a checkerboard
+    let red = gfx::procedural::Pattern::Multiply(
+        Box::new(gfx::procedural::marble(1.0, 2.0, [1.0, 0.2, 0.2], [1.0, 0.75, 0.7])),
+        Box::new(gfx::procedural::ramp(gfx::procedural::Pattern::Noise{ noise: gfx::procedural::Noise::Perlin, frequency: 3.0 }, [0.8, 0.8, 0.8], [1.0, 1.0, 1.0])),
+    );
+    let green = gfx::procedural::Pattern::Add(
+        Box::new(gfx::procedural::wood(2.0, 0.5, [0.3, 0.9, 0.4], [0.15, 0.45, 0.2])),
+        Box::new(gfx::procedural::Pattern::Multiply(
+            Box::new(gfx::procedural::Pattern::Turbulence{ noise: gfx::procedural::Noise::Simplex, frequency: 6.0, octaves: 3 }),
+            gfx::procedural::constant([0.1, 0.1, 0.05]),
+        )),
+    );
+    let blue = gfx::procedural::Pattern::Multiply(
+        Box::new(gfx::procedural::ramp(gfx::procedural::Pattern::Worley{ frequency: 1.5 }, [0.2, 1.0, 1.0], [0.0, 0.3, 0.45])),
+        Box::new(gfx::procedural::checker(0.5, [1.0, 1.0, 1.0], [0.6, 0.6, 0.75])),
+    );
+    let patterns = [std::sync::Arc::new(red), std::sync::Arc::new(green), std::sync::Arc::new(blue)];
+
+    let mut cubes: std::vec::Vec<shapes::cube::Cube> = vec![];
+    for i in 0..3 {
+        let albedo = match &texture {
+            Some(t) => gfx::material::Albedo::Texture(t.clone()),
+            None => gfx::material::Albedo::Procedural(patterns[i as usize].clone()),
+        };
+        let c = gfx::material::Material::Lambertian{ albedo };
+        for _ in 1..300 {
+            let mut c = shapes::cube::new(
+                registry,
+                i,
+                rng.gen_range(-50.0, 50.0),
+                rng.gen_range(-50.0, 50.0),
+                rng.gen_range(-50.0, 50.0),
+                rng.gen_range(0.60, 4.5),
+                rng.gen_range(0.60, 4.5),
+                rng.gen_range(0.60, 4.5),
+                c.clone(),
+            );
+
+            c.phys.vel += na::Vector3::new(
+                rng.gen_range(-1.0, 1.0),
+                rng.gen_range(-1.0,1.0),
+                rng.gen_range(-1.0,1.0),
+            );
+            c.phys.ang = na::Vector3::new(
+                rng.gen_range(-0.5, 0.5),
+                rng.gen_range(-0.5, 0.5),
+                rng.gen_range(-0.5, 0.5),
+            );
+
+            cubes.push(c);
+        }
+    }
+
+    // flint glass cubes hanging under the panel, tipped onto an edge so they
+    // act as prisms and throw rainbows onto the swarm; without spectral
+    // rendering there's no dispersion for them to show off
+    let prisms: &[(f32, f32)] = if spectral { &[(-15.0, -15.0), (15.0, -15.0), (-15.0, 15.0), (15.0, 15.0)] } else { &[] };
+    for &(x, z) in prisms.iter() {
+        let mut prism = shapes::cube::new(
+            registry,
+            PRISMS,
+            x,
+            60.0,
+            z,
+            8.0,
+            8.0,
+            8.0,
+            gfx::material::Material::Dielectric{ ior: gfx::material::SF11 },
+        );
+        prism.phys.rot = na::Vector3::new(std::f32::consts::FRAC_PI_4, 0.0, std::f32::consts::FRAC_PI_4);
+        cubes.push(prism);
+    }
+
+    // a glass ball inside a gold ring, facing the camera, in a group of their own
+    let ball = shapes::solid::new(
+        registry,
+        3,
+        0.0,
+        0.0,
+        0.0,
+        gfx::primitive::sphere(6.0),
+        gfx::material::Material::Dielectric{ ior: gfx::material::BK7 },
+    );
+    let mut ring = shapes::solid::new(
+        registry,
+        3,
+        0.0,
+        0.0,
+        0.0,
+        gfx::primitive::torus(12.0, 1.0),
+        gfx::material::Material::Metal{ albedo: gfx::material::Albedo::Solid([1.0, 0.8, 0.35]), roughness: 0.2 },
+    );
+    ring.phys.rot = na::Vector3::x() * std::f32::consts::FRAC_PI_2;
+
+    // a stone pillar holding them up from the floor: a flared foot, the
+    // column, and a disc capping it
+    let stone = || gfx::material::Material::Lambertian{ albedo: gfx::material::Albedo::Solid([0.6, 0.58, 0.55]) };
+    let floor = shapes::solid::new(registry, 3, 0.0, -80.0, 0.0, gfx::primitive::plane(200.0, 200.0), stone());
+    let foot = shapes::solid::new(registry, 3, 0.0, -75.0, 0.0, gfx::primitive::cone(10.0, 10.0), stone());
+    let column = shapes::solid::new(registry, 3, 0.0, -50.0, 0.0, gfx::primitive::cylinder(3.0, 60.0), stone());
+    let cap = shapes::solid::new(registry, 3, 0.0, -20.0, 0.0, gfx::primitive::disc(3.0), stone());
+    let solids = vec![ball, ring, floor, foot, column, cap];
+
+    // panel overhead, lighting the swarm from above
+    let mut panel = shapes::rectangle::new(
+        registry,
+        0.0,
+        80.0,
+        0.0,
+        40.0,
+        40.0,
+        gfx::material::Material::Emissive{ color: [1.0, 0.95, 0.85], strength: 1.0 },
+    );
+    panel.phys.rot = na::Vector3::x() * std::f32::consts::FRAC_PI_2;
+
+    let lights = vec![
+        gfx::light::directional(na::Vector3::new(-0.3, -1.0, 0.2), [1.0, 0.95, 0.9], 1.5),
+        gfx::light::area(&panel, [1.0, 0.95, 0.85], 4.0),
+        // warm fill from the left, and a spot from behind the camera's right shoulder
+        gfx::light::point(-90.0, 10.0, -60.0, [1.0, 0.7, 0.4], 4000.0),
+        gfx::light::spot(na::Point3::new(60.0, 60.0, -140.0), na::Vector3::new(-60.0, -60.0, 140.0), 10f32.to_radians(), 20f32.to_radians(), [0.8, 0.9, 1.0], 15000.0),
+    ];
+
+    (cubes, solids, panel, lights)
+}
+
 fn main() -> Result<(), String> {
+    let options = match cli::parse(std::env::args().skip(1))? {
+        cli::Command::Run(options) => options,
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        },
+    };
+    let texture = cli::texture(&options)?;
+    let environment = cli::environment(&options)?;
+
+    if let Some(output) = &options.output {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(options.settings.seed);
+        let mut registry = gfx::geometry::new();
+        let (cubes, solids, _, lights) = build(&mut rng, &mut registry, texture, options.settings.spectral);
+        return cli::render(&options, output, &registry, &cubes, &solids, &lights, environment);
+    }
+
     let sdl_context = sdl2::init()?;
 
     let video_subsys = sdl_context.video()?;
@@ -26,8 +183,6 @@ fn main() -> Result<(), String> {
     let gl_attr = video_subsys.gl_attr();
     gl_attr.set_context_profile(GLProfile::Core);
     gl_attr.set_context_version(4, 1);
-    gl_attr.set_multisample_buffers(1);
-    gl_attr.set_multisample_samples(8);
 
     let mut window = video_subsys
         .window("gfx", WIDTH as u32, HEIGHT as u32)
@@ -45,39 +200,10 @@ fn main() -> Result<(), String> {
 
     let mut _rng = rand::thread_rng();
 
-    let red: fn(i32) -> gfx::Color = |i| { if (i % 2) == 0 { [1.0, 0.2, 0.2] } else { [1.0, 0.4, 0.4] } };
-    let green: fn(i32) -> gfx::Color = |i| { if (i % 2) == 0 { [0.3, 0.9, 0.4] } else { [0.5, 1.0, 0.6] } };
-    let blue: fn(i32) -> gfx::Color = |i| { if (i % 2) == 0 { [0.0, 0.89, 0.91] } else { [0.2, 1.0, 1.0] } };
-
-    let mut cubes: std::vec::Vec<shapes::cube::Cube> = vec![];
-    for i in 0..3 {
-        let c = if (i == 0) { red } else if (i == 1) { green } else { blue };
-        for _ in 1..300 {
-            let mut c = shapes::cube::new(
-                i,
-                _rng.gen_range(-50.0, 50.0),
-                _rng.gen_range(-50.0, 50.0),
-                _rng.gen_range(-50.0, 50.0),
-                _rng.gen_range(0.60, 4.5),
-                _rng.gen_range(0.60, 4.5),
-                _rng.gen_range(0.60, 4.5),
-                c,
-            );
-
-            c.phys.vel += na::Vector3::new(
-                _rng.gen_range(-1.0, 1.0),
-                _rng.gen_range(-1.0,1.0),
-                _rng.gen_range(-1.0,1.0),
-            );
-            c.phys.ang = na::Vector3::new(
-                _rng.gen_range(-0.5, 0.5),
-                _rng.gen_range(-0.5, 0.5),
-                _rng.gen_range(-0.5, 0.5),
-            );
-
-            cubes.push(c);
-        }
-    }
+    // every cube draws the same unit cube, so there's one vertex buffer per
+    // distinct geometry and color rather than one per object
+    let mut registry = gfx::geometry::new();
+    let (mut cubes, mut solids, mut panel, lights) = build(&mut _rng, &mut registry, texture, options.settings.spectral);
 
     let vs_src = include_str!("shaders/vertex.glsl");
     let fs_src = include_str!("shaders/fragment.glsl");
@@ -102,7 +228,36 @@ fn main() -> Result<(), String> {
     mouse.set_relative_mouse_mode(true);
     window.set_grab(true);
 
-    let axes = shapes::axes::new();
+    let axes = shapes::axes::new(&mut registry);
+
+    // the scene is drawn in linear HDR, then tone mapped onto the window;
+    // M cycles the operator, - and = change the exposure
+    let hdr = gfx::hdr::new(width, height)?;
+    let mut tonemap = options.tonemap;
+
+    // ray traced preview, toggled with T; starts in --mode, R switches between
+    // the tracer's modes
+    let quad = gfx::quad::new()?;
+    let skybox = gfx::skybox::new()?;
+    if let Some(e) = &environment {
+        gfx::environment::prefilter(e);
+    }
+    let mut scene = trace::scene::new(&registry, &cubes, &solids)?;
+    scene.lights = lights.clone();
+    scene.set_environment(environment.clone());
+    scene.media = cli::media(&options);
+    // shared with the thread rendering the current pass
+    let mut scene = std::sync::Arc::new(scene);
+    let mut trace_settings = trace::settings();
+    trace_settings.mode = options.settings.mode;
+    trace_settings.spectral = options.settings.spectral;
+    let mut preview = trace::progressive::new((width / PREVIEW_SCALE) as usize, (height / PREVIEW_SCALE) as usize, &trace_settings);
+    let mut tracing = false;
+    // the preview is denoised while N is toggled on
+    let mut denoising = false;
+    let denoise_settings = trace::denoise::settings();
+    // whether the preview shown is out of date with the passes or the toggle
+    let mut stale = false;
 
     unsafe {
         gl::Enable(gl::DEPTH_TEST);
@@ -117,6 +272,8 @@ fn main() -> Result<(), String> {
     let delta_m = 0.001 * std::f32::consts::PI;
     let delta_b = 0.2;
     let delta_g = 0.25;
+    // stops
+    let delta_e = 0.5;
 
     let mut t = 0.0;
 
@@ -132,7 +289,18 @@ fn main() -> Result<(), String> {
 
         let (dir_x, dir_y, dir_z,) = camera.phys.direction();
 
+        // anything that changes the view throws away the accumulated samples
+        let mut moved = false;
+
         for event in input::handle_events(&mut events).iter() {
+            moved |= match event {
+                input::Action::Continue => false,
+                // only changes how the same picture is shown
+                input::Action::NextTonemap | input::Action::ExposureUp | input::Action::ExposureDown => false,
+                input::Action::ToggleDenoise => false,
+                _ => true,
+            };
+
             match event {
                 input::Action::Quit => break 'main,
 
@@ -180,12 +348,24 @@ fn main() -> Result<(), String> {
                     );
                 },
 
+                input::Action::ToggleTrace => tracing = !tracing,
+                input::Action::NextTraceMode => trace_settings.mode = trace_settings.mode.next(),
+                input::Action::ToggleDenoise => {
+                    denoising = !denoising;
+                    stale = true;
+                },
+
+                input::Action::NextTonemap => tonemap.operator = tonemap.operator.next(),
+                input::Action::ExposureUp => tonemap.exposure += delta_e,
+                input::Action::ExposureDown => tonemap.exposure -= delta_e,
+
                 _ => {}
 
             }
         }
 
         unsafe {
+            hdr.bind();
             gl::ClearColor(0.05, 0.05, 0.1, 1.0);
             //gl::ClearColor(1.0, 1.0, 1.0, 1.0);
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
@@ -194,15 +374,29 @@ fn main() -> Result<(), String> {
 
             camera.phys.move_(clock);
 
+            if let Some(e) = &environment {
+                skybox.render(e, &camera);
+            }
+
             let params = gfx::render::Params{
                 program,
                 camera: camera.transformation(),
+                eye: camera.phys.pos,
+                lights: lights.clone(),
+                environment: environment.clone(),
                 width,
                 height,
                 clock,
             };
 
             //axes.render(&params);
+            panel.render(&params)?;
+
+            if !tracing {
+                for s in solids.iter_mut() {
+                    s.render(&params)?;
+                }
+            }
 
             let mut centroid0: na::Vector3<f32> = na::Vector3::zeros();
             let mut centroid1: na::Vector3<f32> = na::Vector3::zeros();
@@ -267,16 +461,64 @@ fn main() -> Result<(), String> {
                 let mut m = d.magnitude() as f32;
                 if (m < 5.0) { m = 5.0 };
                 let mut a = d * delta_g * 1.0 / (m * m);
-                c.phys.vel += a * clock * t_factor;
+                // the prisms hang still, only the three swarms are pulled around
+                if c.id != PRISMS {
+                    c.phys.vel += a * clock * t_factor;
+                }
 
                 c.phys.vel -= c.phys.vel * speed_adjust * delta_b;
 
                 c.phys.move_(clock * t_factor);
-                c.render(&params);
+                if !tracing {
+                    c.render(&params)?;
+                }
             }
             //axes.render(&params);
+
+            hdr.unbind();
+
+            if tracing {
+                // input cancels the pass in flight, the view it was rendering is stale
+                if moved {
+                    preview.reset();
+                }
+
+                stale |= preview.poll();
+                if stale {
+                    let fb = if denoising { preview.denoised(&denoise_settings) } else { preview.framebuffer() };
+                    quad.upload(&fb);
+                    stale = false;
+                }
+
+                if !preview.busy() {
+                    // a drifting camera or running simulation changes the picture
+                    // every pass, so only the latest one is shown
+                    let still = t_factor == 0.0
+                        && camera.phys.vel == na::Vector3::zeros()
+                        && camera.phys.ang == na::Vector3::zeros();
+                    if !still {
+                        preview.reset();
+                    }
+
+                    std::sync::Arc::get_mut(&mut scene).expect("scene still shared with a pass").update(&cubes);
+                    preview.start(camera, scene.clone(), trace_settings);
+                }
+
+                quad.render(&tonemap);
+            } else {
+                hdr.resolve();
+                quad.draw(hdr.texture(), &tonemap);
+            }
         }
 
+        let exposure = format!("{} {:+.1} EV", tonemap.operator.name(), tonemap.exposure);
+        let title = if tracing {
+            let denoised = if denoising { ", denoised" } else { "" };
+            format!("gfx - {}, {} samples{}, {:.0}%", exposure, preview.passes, denoised, preview.progress() * 100.0)
+        } else {
+            format!("gfx - {}", exposure)
+        };
+        window.set_title(&title).map_err(|e| e.to_string())?;
 
         speed_adjust = 0.0;
 
@@ -3,6 +3,7 @@ use gl::types::GLfloat;
 
 mod environment;
 
+#[derive(Clone, Copy)]
 pub struct Physics {
     pub pos: na::Vector3<f32>,
     pub vel: na::Vector3<f32>,
@@ -2,13 +2,301 @@
 
 out vec4 FragColor;
 
-in vec3 color;
+in vec3 vertexColor;
+in vec2 uv;
+in vec3 local;
+in vec3 position;
 in float distance;
 in vec4 gl_FragCoord;
 
 uniform float clock;
+uniform vec3 eye;
+
+// see gfx::material::Material::kind
+uniform int material;
+// roughness for metals, ior for dielectrics, strength for emitters
+uniform float parameter;
+uniform vec3 emission;
+
+// when set, the albedo comes from albedoMap instead of the vertex colors
+uniform int textured;
+uniform sampler2D albedoMap;
+
+// see gfx::procedural::upload; overrides the albedo when patternCount > 0
+#define MAX_NODES 32
+#define MAX_STACK 8
+uniform int patternCount;
+uniform int patternOp[MAX_NODES];
+uniform vec4 patternParams[MAX_NODES];
+
+// see gfx::environment::Environment::bind; mip level i is prefiltered for
+// roughness i / (environmentLevels - 1)
+uniform int hasEnvironment;
+uniform samplerCube environmentMap;
+uniform int environmentLevels;
+
+// see gfx::light::upload
+#define MAX_LIGHTS 8
+uniform int lightCount;
+uniform int lightKind[MAX_LIGHTS];
+uniform vec3 lightPosition[MAX_LIGHTS];
+uniform vec3 lightDirection[MAX_LIGHTS];
+uniform vec3 lightColor[MAX_LIGHTS];
+uniform vec2 lightCone[MAX_LIGHTS];
+
+const float PI = 3.14159265;
+const float G3 = 1.0 / 6.0;
+
+// the surroundings as seen by a lobe of the given roughness; without an
+// environment map, a gradient stands in
+vec3 environment(vec3 dir, float roughness) {
+    if (hasEnvironment == 1) {
+        return textureLod(environmentMap, dir, roughness * float(environmentLevels - 1)).rgb;
+    }
+    return mix(vec3(0.05, 0.05, 0.1), vec3(0.6, 0.65, 0.8), dir.y * 0.5 + 0.5);
+}
+
+// the noise below matches gfx::procedural hash for hash
+uint hash(uint h) {
+    h ^= h >> 16u;
+    h *= 0x7feb352du;
+    h ^= h >> 15u;
+    h *= 0x846ca68bu;
+    return h ^ (h >> 16u);
+}
+
+uint hash3(ivec3 c) {
+    return hash(uint(c.x) * 0x8da6b343u ^ uint(c.y) * 0xd8163841u ^ uint(c.z) * 0xcb1ab31fu);
+}
+
+float toUnit(uint h) {
+    return float(h >> 8u) / 16777216.0;
+}
+
+float grad(uint h, vec3 d) {
+    h &= 15u;
+    float u = h < 8u ? d.x : d.y;
+    float v = h < 4u ? d.y : (h == 12u || h == 14u ? d.x : d.z);
+    return ((h & 1u) == 0u ? u : -u) + ((h & 2u) == 0u ? v : -v);
+}
+
+float perlinCorner(ivec3 c, vec3 f, ivec3 o) {
+    return grad(hash3(c + o), f - vec3(o));
+}
+
+float perlin(vec3 p) {
+    vec3 cell = floor(p);
+    ivec3 c = ivec3(cell);
+    vec3 f = p - cell;
+    vec3 w = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+
+    return mix(
+        mix(mix(perlinCorner(c, f, ivec3(0, 0, 0)), perlinCorner(c, f, ivec3(1, 0, 0)), w.x),
+            mix(perlinCorner(c, f, ivec3(0, 1, 0)), perlinCorner(c, f, ivec3(1, 1, 0)), w.x), w.y),
+        mix(mix(perlinCorner(c, f, ivec3(0, 0, 1)), perlinCorner(c, f, ivec3(1, 0, 1)), w.x),
+            mix(perlinCorner(c, f, ivec3(0, 1, 1)), perlinCorner(c, f, ivec3(1, 1, 1)), w.x), w.y),
+        w.z);
+}
+
+float simplexCorner(vec3 cell, vec3 d0, vec3 o, float k) {
+    vec3 d = d0 - o + k * G3;
+    float falloff = 0.6 - dot(d, d);
+    if (falloff <= 0.0) {
+        return 0.0;
+    }
+    return falloff * falloff * falloff * falloff * grad(hash3(ivec3(cell + o)), d);
+}
+
+float simplex(vec3 p) {
+    vec3 cell = floor(p + (p.x + p.y + p.z) / 3.0);
+    vec3 d0 = p - (cell - (cell.x + cell.y + cell.z) * G3);
+
+    vec3 o1;
+    vec3 o2;
+    if (d0.x >= d0.y) {
+        if (d0.y >= d0.z) {
+            o1 = vec3(1.0, 0.0, 0.0);
+            o2 = vec3(1.0, 1.0, 0.0);
+        } else if (d0.x >= d0.z) {
+            o1 = vec3(1.0, 0.0, 0.0);
+            o2 = vec3(1.0, 0.0, 1.0);
+        } else {
+            o1 = vec3(0.0, 0.0, 1.0);
+            o2 = vec3(1.0, 0.0, 1.0);
+        }
+    } else if (d0.y < d0.z) {
+        o1 = vec3(0.0, 0.0, 1.0);
+        o2 = vec3(0.0, 1.0, 1.0);
+    } else if (d0.x < d0.z) {
+        o1 = vec3(0.0, 1.0, 0.0);
+        o2 = vec3(0.0, 1.0, 1.0);
+    } else {
+        o1 = vec3(0.0, 1.0, 0.0);
+        o2 = vec3(1.0, 1.0, 0.0);
+    }
+
+    return 32.0 * (simplexCorner(cell, d0, vec3(0.0), 0.0) + simplexCorner(cell, d0, o1, 1.0)
+        + simplexCorner(cell, d0, o2, 2.0) + simplexCorner(cell, d0, vec3(1.0), 3.0));
+}
+
+float noise(int kind, vec3 p) {
+    return kind == 1 ? simplex(p) : perlin(p);
+}
+
+float octaves(int kind, vec3 p, int n, bool absolute) {
+    float sum = 0.0;
+    float amplitude = 1.0;
+    float total = 0.0;
+    for (int i = 0; i < max(n, 1); i++) {
+        float v = noise(kind, p);
+        sum += amplitude * (absolute ? abs(v) : v);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    return sum / total;
+}
+
+float worley(vec3 p) {
+    vec3 cell = floor(p);
+    float nearest = 1e30;
+    for (int k = -1; k <= 1; k++) {
+        for (int j = -1; j <= 1; j++) {
+            for (int i = -1; i <= 1; i++) {
+                uint h = hash3(ivec3(cell) + ivec3(i, j, k));
+                vec3 feature = cell + vec3(i, j, k) + vec3(toUnit(h), toUnit(hash(h)), toUnit(hash(hash(h))));
+                vec3 d = feature - p;
+                nearest = min(nearest, dot(d, d));
+            }
+        }
+    }
+    return sqrt(nearest);
+}
+
+// runs the flattened expression tree; ops are gfx::procedural's OP_ constants
+vec3 pattern(vec3 p) {
+    vec3 stack[MAX_STACK];
+    int top = 0;
+
+    for (int i = 0; i < patternCount; i++) {
+        int op = patternOp[i];
+        vec4 q = patternParams[i];
+        vec3 v;
+
+        if (op == 0) {
+            v = q.xyz;
+        } else if (op == 1) {
+            v = vec3(0.5 + 0.5 * clamp(noise(int(q.z), p * q.x), -1.0, 1.0));
+        } else if (op == 2) {
+            v = vec3(0.5 + 0.5 * clamp(octaves(int(q.z), p * q.x, int(q.y), false), -1.0, 1.0));
+        } else if (op == 3) {
+            v = vec3(min(octaves(int(q.z), p * q.x, int(q.y), true), 1.0));
+        } else if (op == 4) {
+            v = vec3(min(worley(p * q.x), 1.0));
+        } else if (op == 8) {
+            vec3 t = stack[--top];
+            vec3 b = stack[--top];
+            vec3 a = stack[--top];
+            v = mix(a, b, t.x);
+        } else {
+            vec3 b = stack[--top];
+            vec3 a = stack[--top];
+            vec3 s = p * q.x;
+
+            if (op == 5) {
+                vec3 c = floor(s + 1e-3);
+                v = mod(c.x + c.y + c.z, 2.0) < 1.0 ? a : b;
+            } else if (op == 6) {
+                float bend = q.y * octaves(0, s, 4, true);
+                v = mix(a, b, 0.5 + 0.5 * sin((s.x + bend) * PI));
+            } else if (op == 7) {
+                float t = length(s.xz) + q.y * octaves(0, s, 2, false);
+                v = mix(a, b, fract(t));
+            } else if (op == 9) {
+                v = a + b;
+            } else {
+                v = a * b;
+            }
+        }
+
+        stack[top++] = v;
+    }
+
+    return stack[0];
+}
 
 void main() {
+    if (material == 3) {
+        FragColor = vec4(emission, 1.0);
+        return;
+    }
+
+    // flat face normal from the screen space derivatives, facing the viewer
+    vec3 n = normalize(cross(dFdx(position), dFdy(position)));
+    vec3 v = normalize(eye - position);
+    if (dot(n, v) < 0.0) {
+        n = -n;
+    }
+    vec3 r = reflect(-v, n);
+    float cos_v = max(dot(n, v), 0.0);
+
+    vec3 color = textured == 1 ? texture(albedoMap, uv).rgb : vertexColor;
+    if (patternCount > 0) {
+        color = pattern(local);
+    }
+
+    vec3 base = color;
+    if (material == 1) {
+        // rough metals reflect a blurrier environment
+        base = color * environment(r, parameter);
+    } else if (material == 2) {
+        float f0 = (parameter - 1.0) / (parameter + 1.0);
+        f0 *= f0;
+        float f = f0 + (1.0 - f0) * pow(1.0 - cos_v, 5.0);
+        base = mix(vec3(0.05, 0.05, 0.1), environment(r, 0.0), f);
+    }
+
+    if (lightCount > 0) {
+        // blinn-phong stand in for the microfacet lobe; glass is treated as smooth
+        float alpha = material == 1 ? max(parameter * parameter, 0.05) : 0.05;
+        float shininess = 2.0 / (alpha * alpha) - 2.0;
+
+        // ambient from the environment; metals and glass already reflect it in base
+        vec3 lit = material == 0 ? color * environment(n, 1.0) * 0.1 : base;
+        for (int i = 0; i < lightCount; i++) {
+            vec3 l;
+            vec3 radiance = lightColor[i];
+
+            if (lightKind[i] == 1) {
+                l = -lightDirection[i];
+            } else {
+                l = lightPosition[i] - position;
+                float d2 = dot(l, l);
+                l = normalize(l);
+                radiance /= d2;
+
+                if (lightKind[i] == 2) {
+                    radiance *= smoothstep(lightCone[i].y, lightCone[i].x, dot(-l, lightDirection[i]));
+                } else if (lightKind[i] == 3) {
+                    radiance *= max(dot(-l, lightDirection[i]), 0.0);
+                }
+            }
+
+            float cos_l = max(dot(n, l), 0.0);
+            if (material == 0) {
+                lit += color / PI * radiance * cos_l;
+            } else {
+                vec3 h = normalize(l + v);
+                float spec = pow(max(dot(n, h), 0.0), shininess) * (shininess + 8.0) / (8.0 * PI);
+                lit += (material == 1 ? color : vec3(1.0)) * spec * radiance * cos_l;
+            }
+        }
+
+        FragColor = vec4(lit, 1.0);
+        return;
+    }
+
+    // no lights, fall back to depth cueing
     float m = 50.0;
     float d;
     if (distance < m) {
@@ -17,7 +305,7 @@ void main() {
         d = 1.0;
     }
     float cubic = 1.0 - (d / 1.3) * (d / 1.3);
-    FragColor = vec4((color * cubic * 0.6) + (color * 0.4), 1.0);
+    FragColor = vec4((base * cubic * 0.6) + (base * 0.4), 1.0);
     //FragColor = vec4(color, 1.0);
 
    //FragColor = vec4(normalize(color+ light), 1.0);
@@ -0,0 +1,50 @@
+#version 330
+
+out vec4 FragColor;
+
+in vec2 uv;
+
+uniform sampler2D image;
+
+// see gfx::tonemap; 0 clamp, 1 reinhard, 2 hable, 3 aces
+uniform int tonemap;
+// linear scale, 2 to the power of the exposure in stops
+uniform float exposure;
+// 0 for the sRGB curve
+uniform float gamma;
+
+vec3 hable_curve(vec3 x) {
+    float a = 0.15;
+    float b = 0.50;
+    float c = 0.10;
+    float d = 0.20;
+    float e = 0.02;
+    float f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+vec3 tone(vec3 x) {
+    x = max(x, vec3(0.0));
+    if (tonemap == 1) {
+        x = x / (1.0 + x);
+    } else if (tonemap == 2) {
+        x = hable_curve(x * 2.0) / hable_curve(vec3(11.2));
+    } else if (tonemap == 3) {
+        x *= 0.6;
+        x = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    }
+    return clamp(x, 0.0, 1.0);
+}
+
+vec3 encode(vec3 c) {
+    if (gamma > 0.0) {
+        return pow(c, vec3(1.0 / gamma));
+    }
+    vec3 low = c * 12.92;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(c, vec3(0.0031308)));
+}
+
+void main() {
+    FragColor = vec4(encode(tone(texture(image, uv).rgb * exposure)), 1.0);
+}
@@ -0,0 +1,11 @@
+#version 330
+
+in vec2 attribPosition;
+in vec2 attribUv;
+
+out vec2 uv;
+
+void main() {
+    gl_Position = vec4(attribPosition, 0.0, 1.0);
+    uv = attribUv;
+}
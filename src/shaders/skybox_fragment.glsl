@@ -0,0 +1,12 @@
+#version 330
+
+out vec4 FragColor;
+
+in vec3 direction;
+
+// see gfx::environment::Environment::bind
+uniform samplerCube environmentMap;
+
+void main() {
+    FragColor = vec4(textureLod(environmentMap, normalize(direction), 0.0).rgb, 1.0);
+}
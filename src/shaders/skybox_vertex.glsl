@@ -0,0 +1,16 @@
+#version 330
+
+in vec2 attribPosition;
+
+uniform mat4 inverseCamera;
+uniform vec3 eye;
+
+out vec3 direction;
+
+void main() {
+    // on the far plane, so nothing is hidden behind the sky
+    gl_Position = vec4(attribPosition, 1.0, 1.0);
+
+    vec4 p = inverseCamera * vec4(attribPosition, 1.0, 1.0);
+    direction = p.xyz / p.w - eye;
+}
@@ -2,18 +2,28 @@
 
 in vec3 attribPosition;
 in vec3 attribColor;
+in vec2 attribUv;
 
 uniform mat4 model;
 uniform mat4 camera;
+// the object's own scaling, already part of model
+uniform vec3 scale;
 
-out vec3 color;
+out vec3 vertexColor;
+out vec2 uv;
+// in the object's own space (scaled, not rotated or moved), for procedural textures
+out vec3 local;
+out vec3 position;
 out float distance;
 
 void main() {
     vec4 p = model * vec4(attribPosition, 1.0);
     gl_Position = camera * p;
 
-    color = attribColor;
+    vertexColor = attribColor;
+    uv = attribUv;
+    local = attribPosition * scale;
+    position = p.xyz;
 
     distance = length(p);
 }
@@ -5,45 +5,16 @@ pub struct Axes {
     pub gfx: crate::gfx::render::Renderer,
 }
 
-pub fn new() -> Axes {
-    let x = crate::shapes::rectangle::new(
-        0.0,
-        0.0,
-        0.0,
-        1000.0,
-        0.2,
-        |i| { [1.0, 0.0, 0.0] },
-    );
-
-    let y = crate::shapes::rectangle::new(
-        0.0,
-        0.0,
-        0.0,
-        0.2,
-        1000.0,
-        |i| { [0.0, 1.0, 0.0] },
-    );
-
-    let mut z = crate::shapes::rectangle::new(
-        0.0,
-        0.0,
-        0.0,
-        0.2,
-        1000.0,
-        |i| { [0.0, 0.0, 1.0] },
-    );
-    z.phys.rot = na::Vector3::x() * -std::f32::consts::FRAC_PI_2;
-
-    let mut mesh: std::vec::Vec<crate::gfx::Triangle> = x.vertices();
-    mesh.extend(y.vertices());
-    mesh.extend(z.vertices());
+pub fn new(registry: &mut crate::gfx::geometry::Registry) -> Axes {
+    let geometry = registry.get_or_add("axes", geometry);
 
     Axes{
         phys: crate::physics::new(0.0, 0.0, 0.0),
         gfx: crate::gfx::render::new(
-            1.0,
-            mesh,
-            |i| {
+            registry,
+            geometry,
+            na::Vector3::new(1.0, 1.0, 1.0),
+            crate::gfx::material::from_color_fn(|i| {
                 if (i / 6) == 0 {
                     [1.0, 0.0, 0.0]
                 } else if (i / 12) == 0 {
@@ -51,14 +22,30 @@ pub fn new() -> Axes {
                 } else {
                     [0.0, 0.0, 1.0]
                 }
-            },
+            }),
         ),
     }
 }
 
+// a long thin rectangle along each axis
+fn geometry() -> (crate::gfx::Mesh, crate::gfx::Uvs) {
+    let x = na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(1000.0, 0.2, 1.0));
+    let y = na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(0.2, 1000.0, 1.0));
+    let z = na::Isometry3::new(na::Vector3::zeros(), na::Vector3::x() * -std::f32::consts::FRAC_PI_2).to_homogeneous() * y;
+
+    let mut mesh: crate::gfx::Mesh = vec![];
+    let mut uvs: crate::gfx::Uvs = vec![];
+    for mat in [x, y, z].iter() {
+        let (m, u) = crate::shapes::rectangle::face(mat);
+        mesh.extend(m);
+        uvs.extend(u);
+    }
+
+    (mesh, uvs)
+}
+
 impl Axes {
     pub fn render(&self, params: &crate::gfx::render::Params) {
         self.gfx.render(&self.phys, params);
     }
 }
-
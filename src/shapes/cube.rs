@@ -6,84 +6,47 @@ pub struct Cube {
     pub gfx: crate::gfx::render::Renderer,
 }
 
-pub fn new(id: i32, x: f32, y: f32, z: f32, width: f32, height: f32, depth: f32, color: crate::gfx::ColorFn) -> Cube {
-    let mut front = crate::shapes::rectangle::new(
-        0.0,
-        0.0,
-        depth / 2.0,
-        width,
-        height,
-        color,
-    );
-
-    let mut back = crate::shapes::rectangle::new(
-        0.0,
-        0.0,
-        -depth / 2.0,
-        width,
-        height,
-        color,
-    );
-
-    let mut left = crate::shapes::rectangle::new(
-        -width / 2.0,
-        0.0,
-        0.0,
-        depth,
-        height,
-        color,
-    );
-    left.phys.rot = na::Vector3::y() * std::f32::consts::FRAC_PI_2;
-
-    let mut right = crate::shapes::rectangle::new(
-        width / 2.0,
-        0.0,
-        0.0,
-        depth,
-        height,
-        color,
-    );
-    right.phys.rot = na::Vector3::y() * -std::f32::consts::FRAC_PI_2;
-
-    let mut top = crate::shapes::rectangle::new(
-        0.0,
-        height / 2.0,
-        0.0,
-        width,
-        depth,
-        color,
-    );
-    top.phys.rot = na::Vector3::x() * std::f32::consts::FRAC_PI_2;
-
-    let mut bottom = crate::shapes::rectangle::new(
-        0.0,
-        -height / 2.0,
-        0.0,
-        width,
-        depth,
-        color,
-    );
-    bottom.phys.rot = na::Vector3::x() * -std::f32::consts::FRAC_PI_2;
-
-
-    let mut mesh: std::vec::Vec<crate::gfx::Triangle> = front.vertices();
-    mesh.extend(back.vertices());
-    mesh.extend(left.vertices());
-    mesh.extend(right.vertices());
-    mesh.extend(top.vertices());
-    mesh.extend(bottom.vertices());
+// every cube shares one unit cube, stretched to its width, height and depth
+pub fn new(registry: &mut crate::gfx::geometry::Registry, id: i32, x: f32, y: f32, z: f32, width: f32, height: f32, depth: f32, material: crate::gfx::material::Material) -> Cube {
+    let geometry = registry.get_or_add("cube", geometry);
 
     Cube{
         id,
         phys: crate::physics::new(x, y, z),
         gfx: crate::gfx::render::new(
-            1.0,
-            mesh,
-            color,
+            registry,
+            geometry,
+            na::Vector3::new(width, height, depth),
+            material,
         ),
     }
 }
 
+// a unit cube centered on the origin
+pub fn geometry() -> (crate::gfx::Mesh, crate::gfx::Uvs) {
+    // each face is rotated so its normal points out of the cube: front, back,
+    // left, right, top and bottom
+    let faces = [
+        (na::Vector3::z() * 0.5, na::Vector3::zeros()),
+        (na::Vector3::z() * -0.5, na::Vector3::y() * std::f32::consts::PI),
+        (na::Vector3::x() * -0.5, na::Vector3::y() * -std::f32::consts::FRAC_PI_2),
+        (na::Vector3::x() * 0.5, na::Vector3::y() * std::f32::consts::FRAC_PI_2),
+        (na::Vector3::y() * 0.5, na::Vector3::x() * -std::f32::consts::FRAC_PI_2),
+        (na::Vector3::y() * -0.5, na::Vector3::x() * std::f32::consts::FRAC_PI_2),
+    ];
+
+    // every face gets the whole texture
+    let mut mesh: crate::gfx::Mesh = vec![];
+    let mut uvs: crate::gfx::Uvs = vec![];
+    for &(position, rotation) in faces.iter() {
+        let (m, u) = crate::shapes::rectangle::face(&na::Isometry3::new(position, rotation).to_homogeneous());
+        mesh.extend(m);
+        uvs.extend(u);
+    }
+
+    (mesh, uvs)
+}
+
 impl Cube {
-    pub fn render(&mut self, params: &crate::gfx::render::Params) { self.gfx.render(&self.phys, params).expect("err rendering") }
+    pub fn render(&mut self, params: &crate::gfx::render::Params) -> Result<(), std::string::String> { self.gfx.render(&self.phys, params) }
 }
@@ -1,3 +1,4 @@
 pub mod rectangle;
 pub mod cube;
 pub mod axes;
+pub mod solid;
@@ -6,28 +6,51 @@ pub struct Rectangle {
     pub gfx: crate::gfx::render::Renderer,
 }
 
-pub fn new<'a>(x: f32, y: f32, z: f32, width: f32, height: f32, color: crate::gfx::ColorFn) -> Rectangle {
+// every rectangle shares one unit square, stretched to its width and height
+pub fn new<'a>(registry: &mut crate::gfx::geometry::Registry, x: f32, y: f32, z: f32, width: f32, height: f32, material: crate::gfx::material::Material) -> Rectangle {
+    let geometry = registry.get_or_add("rectangle", geometry);
+
+    Rectangle{
+        phys: crate::physics::new(x, y, z),
+        gfx: crate::gfx::render::new(
+            registry,
+            geometry,
+            na::Vector3::new(width, height, 1.0),
+            material,
+        ),
+    }
+}
+
+// a unit square centered on the origin
+pub fn geometry() -> (crate::gfx::Mesh, crate::gfx::Uvs) {
+    // both triangles wind counter clockwise, so their normals face +z
     let mesh: std::vec::Vec<crate::gfx::Triangle> = vec![
         [
-            na::Point3::new(-width/2.0,  height/2.0,  0.0), // top left corner
-            na::Point3::new( width/2.0,  height/2.0,  0.0), // top right corner
-            na::Point3::new( width/2.0, -height/2.0,  0.0), // bottom right corner
+            na::Point3::new(-0.5,  0.5,  0.0), // top left corner
+            na::Point3::new( 0.5, -0.5,  0.0), // bottom right corner
+            na::Point3::new( 0.5,  0.5,  0.0), // top right corner
         ],
         [
-            na::Point3::new(-width/2.0,  height/2.0,  0.0), // top left corner
-            na::Point3::new(-width/2.0, -height/2.0,  0.0), // bottom left corner
-            na::Point3::new( width/2.0, -height/2.0,  0.0), // bottom right corner
+            na::Point3::new(-0.5,  0.5,  0.0), // top left corner
+            na::Point3::new(-0.5, -0.5,  0.0), // bottom left corner
+            na::Point3::new( 0.5, -0.5,  0.0), // bottom right corner
         ]
     ];
 
-    Rectangle{
-        phys: crate::physics::new(x, y, z),
-        gfx: crate::gfx::render::new(
-            1.0,
-            mesh,
-            color,
-        ),
-    }
+    // the whole texture once across the rectangle, v up
+    let uvs: crate::gfx::Uvs = vec![
+        [na::Point2::new(0.0, 1.0), na::Point2::new(1.0, 0.0), na::Point2::new(1.0, 1.0)],
+        [na::Point2::new(0.0, 1.0), na::Point2::new(0.0, 0.0), na::Point2::new(1.0, 0.0)],
+    ];
+
+    (mesh, uvs)
+}
+
+// the unit square under mat, for building bigger meshes out of rectangles;
+// in the same order as Rectangle::vertices
+pub fn face(mat: &na::Matrix4<f32>) -> (crate::gfx::Mesh, crate::gfx::Uvs) {
+    let (mesh, uvs) = geometry();
+    (mesh.iter().map(|t| translate_mesh(mat, &t)).rev().collect(), uvs.into_iter().rev().collect())
 }
 
 fn translate_mesh(&mat: &na::Matrix4<f32>, triangle: &crate::gfx::Triangle) -> crate::gfx::Triangle {
@@ -41,9 +64,14 @@ fn translate_mesh(&mat: &na::Matrix4<f32>, triangle: &crate::gfx::Triangle) -> c
 }
 
 impl Rectangle {
-    pub fn render(&mut self, params: &crate::gfx::render::Params) { self.gfx.render(&self.phys, params).expect("err rendering") }
-    pub fn vertices(&self) -> std::vec::Vec<crate::gfx::Triangle> {
-        let mat = self.phys.mat_model().to_homogeneous();
-        return self.gfx.mesh.iter().map(|t| translate_mesh(&mat, &t)).rev().collect();
+    pub fn render(&mut self, params: &crate::gfx::render::Params) -> Result<(), std::string::String> { self.gfx.render(&self.phys, params) }
+    // in world space
+    pub fn vertices(&self, registry: &crate::gfx::geometry::Registry) -> std::vec::Vec<crate::gfx::Triangle> {
+        let mat = self.gfx.mat_model(&self.phys);
+        return registry.get(self.gfx.geometry).mesh.iter().map(|t| translate_mesh(&mat, &t)).rev().collect();
+    }
+    // in the same order as vertices
+    pub fn uvs(&self, registry: &crate::gfx::geometry::Registry) -> crate::gfx::Uvs {
+        registry.get(self.gfx.geometry).uvs.iter().rev().cloned().collect()
     }
 }
@@ -0,0 +1,33 @@
+// An analytic primitive placed in the world: traced exactly by the CPU tracer,
+// drawn from its tessellation by the GL renderer.
+
+// around the y axis (or the torus' tube)
+const SEGMENTS: usize = 48;
+
+pub struct Solid {
+    pub id: i32,
+    pub primitive: crate::gfx::primitive::Primitive,
+    pub phys: crate::physics::Physics,
+    pub gfx: crate::gfx::render::Renderer,
+}
+
+// solids of the same shape and size share one tessellation
+pub fn new(registry: &mut crate::gfx::geometry::Registry, id: i32, x: f32, y: f32, z: f32, primitive: crate::gfx::primitive::Primitive, material: crate::gfx::material::Material) -> Solid {
+    let geometry = registry.get_or_add(&format!("{:?}", primitive), || primitive.tessellate(SEGMENTS));
+
+    Solid{
+        id,
+        primitive,
+        phys: crate::physics::new(x, y, z),
+        gfx: crate::gfx::render::new(
+            registry,
+            geometry,
+            nalgebra::Vector3::new(1.0, 1.0, 1.0),
+            material,
+        ),
+    }
+}
+
+impl Solid {
+    pub fn render(&mut self, params: &crate::gfx::render::Params) -> Result<(), std::string::String> { self.gfx.render(&self.phys, params) }
+}
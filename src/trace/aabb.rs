@@ -0,0 +1,120 @@
+use nalgebra as na;
+
+// bound on the relative error of three float operations (pbrt's gamma(3))
+const GAMMA_3: f32 = 3.0 * std::f32::EPSILON * 0.5 / (1.0 - 3.0 * std::f32::EPSILON * 0.5);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: na::Point3<f32>,
+    pub max: na::Point3<f32>,
+}
+
+// inverted bounds, so growing by anything yields that thing's bounds
+pub fn empty() -> Aabb {
+    Aabb{
+        min: na::Point3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY),
+        max: na::Point3::new(std::f32::NEG_INFINITY, std::f32::NEG_INFINITY, std::f32::NEG_INFINITY),
+    }
+}
+
+pub fn from_triangle(triangle: &crate::gfx::Triangle) -> Aabb {
+    let mut b = empty();
+    for p in triangle.iter() {
+        b.grow(p);
+    }
+    b
+}
+
+impl Aabb {
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: &na::Point3<f32>) {
+        self.min = na::Point3::from(self.min.coords.inf(&p.coords));
+        self.max = na::Point3::from(self.max.coords.sup(&p.coords));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb{
+            min: na::Point3::from(self.min.coords.inf(&other.min.coords)),
+            max: na::Point3::from(self.max.coords.sup(&other.max.coords)),
+        }
+    }
+
+    pub fn centroid(&self) -> na::Point3<f32> {
+        na::center(&self.min, &self.max)
+    }
+
+    pub fn extent(&self) -> na::Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.extent();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // bounds of all 8 corners after the transform
+    pub fn transform(&self, mat: &na::Matrix4<f32>) -> Aabb {
+        let mut b = empty();
+        if self.is_empty() {
+            return b;
+        }
+        for i in 0..8 {
+            let corner = na::Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            b.grow(&mat.transform_point(&corner));
+        }
+        b
+    }
+
+    // slab test; inv_dir is 1 / ray.dir, computed once per ray by the caller.
+    // returns the distance at which the ray enters the box
+    pub fn intersect(&self, ray: &crate::gfx::ray::Ray, inv_dir: &na::Vector3<f32>) -> Option<f32> {
+        self.range(ray, inv_dir).map(|(t0, _)| t0)
+    }
+
+    // the distances at which the ray enters and leaves the box, clipped to
+    // [0, ray.t_max]
+    pub fn range(&self, ray: &crate::gfx::ray::Ray, inv_dir: &na::Vector3<f32>) -> Option<(f32, f32)> {
+        let mut t0 = 0.0;
+        let mut t1 = ray.t_max;
+
+        for i in 0..3 {
+            let mut near = (self.min[i] - ray.origin[i]) * inv_dir[i];
+            let mut far = (self.max[i] - ray.origin[i]) * inv_dir[i];
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // pad for rounding error, flat boxes (ie, a cube face) would otherwise be missed
+            far *= 1.0 + 2.0 * GAMMA_3;
+
+            // NaN (0 * inf) fails these comparisons, leaving the interval alone
+            if near > t0 { t0 = near; }
+            if far < t1 { t1 = far; }
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+}
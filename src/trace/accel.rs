@@ -0,0 +1,420 @@
+use crate::trace::aabb;
+use crate::trace::bvh;
+
+// Two level acceleration structure: one bvh per mesh (built once, in the mesh's
+// own space) and a top level bvh over instances, each a mesh or an analytic
+// primitive under a transform.
+// Moving an instance only refits the top level; it's rebuilt when the refit tree
+// has degraded too far past its freshly built cost. Instances can also move
+// while the shutter is open (motion blur): rays are tested against the pose at
+// their own time, and the top level bounds cover the whole interval.
+
+// what an instance places in the world
+#[derive(Clone, Copy)]
+pub enum Shape {
+    // index into Accel::meshes
+    Mesh(usize),
+    Primitive(crate::gfx::primitive::Primitive),
+}
+
+pub struct Instance {
+    pub shape: Shape,
+    // the pose at time 0
+    pub transform: crate::gfx::ray::Transform,
+    pub motion: Option<Motion>,
+    // world space bounds of the shape under the transform, over the whole
+    // shutter interval if it moves
+    pub bounds: aabb::Aabb,
+}
+
+// an instance moving the way its Physics does: local is applied first (ie the
+// renderer's scale), then the pose the physics has reached at a given time
+#[derive(Clone, Copy)]
+pub struct Motion {
+    pub phys: crate::physics::Physics,
+    pub local: nalgebra::Matrix4<f32>,
+}
+
+impl Motion {
+    pub fn model(&self, time: f32) -> nalgebra::Matrix4<f32> {
+        let mut phys = self.phys;
+        phys.move_(time);
+        phys.mat_translation() * phys.mat_rotation() * self.local
+    }
+
+    // bounds of the local space box over [open, close]. Translation is linear so
+    // the ends cover it; a spinning mesh is bounded by the sphere it sweeps
+    fn bounds(&self, local: &aabb::Aabb, open: f32, close: f32) -> aabb::Aabb {
+        if self.phys.ang == nalgebra::Vector3::zeros() {
+            return local.transform(&self.model(open)).union(&local.transform(&self.model(close)));
+        }
+
+        let scaled = local.transform(&self.local);
+        let r = scaled.min.coords.abs().sup(&scaled.max.coords.abs()).magnitude();
+        let reach = nalgebra::Vector3::new(r, r, r);
+
+        let mut b = aabb::empty();
+        for &t in [open, close].iter() {
+            let center = nalgebra::Point3::from(self.phys.pos + self.phys.vel * t);
+            b.grow(&(center - reach));
+            b.grow(&(center + reach));
+        }
+        b
+    }
+}
+
+pub struct Accel {
+    pub meshes: std::vec::Vec<bvh::Bvh>,
+    pub instances: std::vec::Vec<Instance>,
+    // rebuild once the refit sah cost exceeds the built cost by this factor
+    pub rebuild_ratio: f32,
+    // times the shutter opens and closes; moving instances are bounded over it
+    shutter: (f32, f32),
+    nodes: std::vec::Vec<bvh::Node>,
+    // instance indices in leaf order
+    order: std::vec::Vec<u32>,
+    built_cost: f32,
+}
+
+pub fn new() -> Accel {
+    Accel{
+        meshes: vec![],
+        instances: vec![],
+        rebuild_ratio: 1.5,
+        shutter: (0.0, 0.0),
+        nodes: vec![],
+        order: vec![],
+        built_cost: 0.0,
+    }
+}
+
+impl Accel {
+    pub fn add_mesh(&mut self, mesh: &crate::gfx::Mesh) -> usize {
+        self.meshes.push(bvh::new(mesh));
+        self.meshes.len() - 1
+    }
+
+    pub fn add_instance(&mut self, mesh: usize, model: nalgebra::Matrix4<f32>) -> usize {
+        self.add_shape(Shape::Mesh(mesh), model)
+    }
+
+    pub fn add_primitive(&mut self, primitive: crate::gfx::primitive::Primitive, model: nalgebra::Matrix4<f32>) -> usize {
+        self.add_shape(Shape::Primitive(primitive), model)
+    }
+
+    fn add_shape(&mut self, shape: Shape, model: nalgebra::Matrix4<f32>) -> usize {
+        let bounds = self.shape_bounds(&shape).transform(&model);
+        self.instances.push(Instance{
+            shape,
+            transform: crate::gfx::ray::transform(model),
+            motion: None,
+            bounds,
+        });
+        self.instances.len() - 1
+    }
+
+    // takes effect on the next call to update or build, as do set_motion and
+    // set_shutter. Stops the instance moving
+    pub fn set_transform(&mut self, instance: usize, model: nalgebra::Matrix4<f32>) {
+        let local = self.shape_bounds(&self.instances[instance].shape);
+        let i = &mut self.instances[instance];
+        i.bounds = local.transform(&model);
+        i.transform = crate::gfx::ray::transform(model);
+        i.motion = None;
+    }
+
+    pub fn set_motion(&mut self, instance: usize, motion: Motion) {
+        let (open, close) = self.shutter;
+        let local = self.shape_bounds(&self.instances[instance].shape);
+        let i = &mut self.instances[instance];
+        i.bounds = motion.bounds(&local, open, close);
+        i.transform = crate::gfx::ray::transform(motion.model(0.0));
+        i.motion = Some(motion);
+    }
+
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter = (open, close);
+        for i in 0..self.instances.len() {
+            if let Some(m) = self.instances[i].motion {
+                self.set_motion(i, m);
+            }
+        }
+    }
+
+    // bounds in the shape's own space
+    pub fn shape_bounds(&self, shape: &Shape) -> aabb::Aabb {
+        match shape {
+            Shape::Mesh(mesh) => self.meshes[*mesh].bounds(),
+            Shape::Primitive(p) => p.bounds(),
+        }
+    }
+
+    // the instance's model matrix at the given time
+    pub fn model(&self, instance: usize, time: f32) -> nalgebra::Matrix4<f32> {
+        let i = &self.instances[instance];
+        match i.motion {
+            Some(m) if time != 0.0 => m.model(time),
+            _ => i.transform.model,
+        }
+    }
+
+    // the transform a ray cast at `time` sees the instance under; moved is
+    // somewhere to keep it if it isn't the stored one
+    fn transform_at<'a>(instance: &'a Instance, time: f32, moved: &'a mut Option<crate::gfx::ray::Transform>) -> &'a crate::gfx::ray::Transform {
+        match instance.motion {
+            Some(m) if time != 0.0 => moved.get_or_insert(crate::gfx::ray::transform(m.model(time))),
+            _ => &instance.transform,
+        }
+    }
+
+    pub fn build(&mut self) {
+        let bounds: std::vec::Vec<aabb::Aabb> = self.instances.iter().map(|i| i.bounds).collect();
+        let (nodes, order) = bvh::build_nodes(&bounds);
+        self.nodes = nodes;
+        self.order = order;
+        self.built_cost = bvh::stats(&self.nodes).sah_cost;
+    }
+
+    // O(n) refit of the top level, rebuilding instead if quality has degraded.
+    // returns true if it rebuilt
+    pub fn update(&mut self) -> bool {
+        if self.order.len() != self.instances.len() {
+            self.build();
+            return true;
+        }
+
+        let instances = &self.instances;
+        let order = &self.order;
+        bvh::refit(&mut self.nodes, |first, count| {
+            order[first..first + count].iter().fold(aabb::empty(), |b, &i| b.union(&instances[i as usize].bounds))
+        });
+
+        if bvh::stats(&self.nodes).sah_cost > self.built_cost * self.rebuild_ratio {
+            self.build();
+            return true;
+        }
+
+        false
+    }
+
+    pub fn bounds(&self) -> aabb::Aabb {
+        self.nodes.first().map(|n| n.bounds).unwrap_or_else(aabb::empty)
+    }
+
+    // returns the instance that was hit; hit.triangle indexes that instance's
+    // mesh, if it has one
+    pub fn closest_hit(&self, ray: &crate::gfx::ray::Ray) -> Option<(usize, crate::gfx::ray::Hit)> {
+        let mut ray = *ray;
+        let mut closest: Option<(usize, crate::gfx::ray::Hit)> = None;
+
+        bvh::traverse(&self.nodes, &mut ray, |slot, ray| {
+            let index = self.order[slot] as usize;
+            let instance = &self.instances[index];
+            let mut moved = None;
+            let transform = Accel::transform_at(instance, ray.time, &mut moved);
+            let local = transform.ray_to_local(ray);
+
+            let hit = match instance.shape {
+                Shape::Mesh(mesh) => self.meshes[mesh].closest_hit(&local),
+                Shape::Primitive(p) => p.intersect(&local),
+            };
+
+            if let Some(mut hit) = hit {
+                hit.normal = transform.normal_to_world(&hit.normal);
+                ray.t_max = hit.t;
+                closest = Some((index, hit));
+            }
+            false
+        });
+
+        closest
+    }
+
+    pub fn any_hit(&self, ray: &crate::gfx::ray::Ray) -> bool {
+        let mut ray = *ray;
+        let mut hit = false;
+
+        bvh::traverse(&self.nodes, &mut ray, |slot, ray| {
+            let instance = &self.instances[self.order[slot] as usize];
+            let mut moved = None;
+            let transform = Accel::transform_at(instance, ray.time, &mut moved);
+            let local = transform.ray_to_local(ray);
+            hit = match instance.shape {
+                Shape::Mesh(mesh) => self.meshes[mesh].any_hit(&local),
+                Shape::Primitive(p) => p.intersect(&local).is_some(),
+            };
+            hit
+        });
+
+        hit
+    }
+
+    // top level only
+    pub fn stats(&self) -> bvh::Stats {
+        bvh::stats(&self.nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra as na;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    // a 4 x 4 grid of the same triangle soup, each instance turned a little
+    fn grid() -> Accel {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+        let soup: crate::gfx::Mesh = (0..40).map(|_| {
+            let c = na::Point3::new(rng.gen_range(-2.0, 2.0), rng.gen_range(-2.0, 2.0), rng.gen_range(-2.0, 2.0));
+            let mut corner = || c + na::Vector3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+            [corner(), corner(), corner()]
+        }).collect();
+
+        let mut accel = new();
+        let mesh = accel.add_mesh(&soup);
+        for i in 0..16 {
+            let at = na::Vector3::new((i % 4) as f32 * 8.0 - 12.0, (i / 4) as f32 * 8.0 - 12.0, 0.0);
+            accel.add_instance(mesh, na::Matrix4::new_translation(&at) * na::Matrix4::from_euler_angles(0.1 * i as f32, 0.2, 0.0));
+        }
+        accel.build();
+        accel
+    }
+
+    // every instance in turn, keeping the nearest; (instance, t)
+    fn brute_force(accel: &Accel, ray: &crate::gfx::ray::Ray) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        for (i, instance) in accel.instances.iter().enumerate() {
+            let local = instance.transform.ray_to_local(ray);
+            let hit = match instance.shape {
+                Shape::Mesh(mesh) => accel.meshes[mesh].closest_hit(&local),
+                Shape::Primitive(p) => p.intersect(&local),
+            };
+            if let Some(hit) = hit {
+                if nearest.map_or(true, |(_, best)| hit.t < best) {
+                    nearest = Some((i, hit.t));
+                }
+            }
+        }
+        nearest
+    }
+
+    fn check_hits(accel: &Accel) {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(2);
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let origin = na::Point3::new(rng.gen_range(-20.0, 20.0), rng.gen_range(-20.0, 20.0), -30.0);
+            let target = na::Point3::new(rng.gen_range(-20.0, 20.0), rng.gen_range(-20.0, 20.0), rng.gen_range(-4.0, 4.0));
+            let ray = crate::gfx::ray::new(origin, target - origin);
+
+            let expected = brute_force(accel, &ray);
+            assert_eq!(accel.closest_hit(&ray).map(|(i, h)| (i, h.t)), expected);
+            assert_eq!(accel.any_hit(&ray), expected.is_some());
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 100, "only {} rays hit anything", hits);
+    }
+
+    #[test]
+    fn refit_keeps_hits_after_moving_an_instance() {
+        let mut accel = grid();
+        check_hits(&accel);
+
+        let nudged = na::Matrix4::new_translation(&na::Vector3::new(-3.0, -3.0, 1.0)) * accel.instances[5].transform.model;
+        accel.set_transform(5, nudged);
+        assert!(!accel.update(), "a small move shouldn't need a rebuild");
+        check_hits(&accel);
+    }
+
+    #[test]
+    fn rebuild_keeps_hits_after_moving_an_instance() {
+        let mut accel = grid();
+        // any loss of quality rebuilds
+        accel.rebuild_ratio = 1.0;
+
+        let moved = na::Matrix4::new_translation(&na::Vector3::new(40.0, 40.0, 0.0)) * accel.instances[0].transform.model;
+        accel.set_transform(0, moved);
+        assert!(accel.update(), "a move off the grid should rebuild");
+        check_hits(&accel);
+
+        let ray = crate::gfx::ray::new(na::Point3::new(-12.0, -12.0, -30.0), na::Vector3::z());
+        assert_ne!(accel.closest_hit(&ray).map(|(i, _)| i), Some(0));
+    }
+
+    fn moving(ang: na::Vector3<f32>) -> Motion {
+        let mut phys = crate::physics::new(1.0, -2.0, 3.0);
+        phys.vel = na::Vector3::new(4.0, 0.5, -1.0);
+        phys.rot = na::Vector3::new(0.3, 0.0, 0.2);
+        phys.ang = ang;
+        Motion{ phys, local: na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(2.0, 1.0, 0.5)) }
+    }
+
+    #[test]
+    fn motion_follows_the_physics() {
+        let motion = moving(na::Vector3::new(0.0, 1.5, 0.0));
+        let p = na::Point3::new(1.0, 1.0, 1.0);
+
+        for &t in [0.0, 0.25, 1.0].iter() {
+            let mut phys = motion.phys;
+            phys.move_(t);
+            let rotated = na::Rotation3::new(phys.rot) * na::Point3::new(2.0, 1.0, 0.5);
+            let expected = rotated + phys.pos;
+            assert!((motion.model(t).transform_point(&p) - expected).magnitude() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn motion_bounds_cover_every_pose_while_the_shutter_is_open() {
+        let cube = aabb::Aabb{ min: na::Point3::new(-1.0, -1.0, -1.0), max: na::Point3::new(1.0, 1.0, 1.0) };
+
+        for &ang in [na::Vector3::zeros(), na::Vector3::new(0.5, 2.0, -1.0)].iter() {
+            let motion = moving(ang);
+            let bounds = motion.bounds(&cube, 0.0, 0.5);
+
+            for step in 0..=50 {
+                let t = 0.5 * step as f32 / 50.0;
+                let model = motion.model(t);
+                for corner in 0..8 {
+                    let c = na::Point3::new(
+                        if corner & 1 == 0 { -1.0 } else { 1.0 },
+                        if corner & 2 == 0 { -1.0 } else { 1.0 },
+                        if corner & 4 == 0 { -1.0 } else { 1.0 },
+                    );
+                    let p = model.transform_point(&c);
+                    for k in 0..3 {
+                        assert!(p[k] >= bounds.min[k] - 1e-4 && p[k] <= bounds.max[k] + 1e-4, "{:?} at {} is outside {:?}", p, t, bounds);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rays_see_moving_instances_where_they_are_at_their_time() {
+        let quad = vec![
+            [na::Point3::new(-1.0, -1.0, 0.0), na::Point3::new(1.0, -1.0, 0.0), na::Point3::new(1.0, 1.0, 0.0)],
+            [na::Point3::new(-1.0, -1.0, 0.0), na::Point3::new(1.0, 1.0, 0.0), na::Point3::new(-1.0, 1.0, 0.0)],
+        ];
+        let mut accel = new();
+        let mesh = accel.add_mesh(&quad);
+        accel.add_instance(mesh, na::Matrix4::identity());
+        accel.add_instance(mesh, na::Matrix4::new_translation(&na::Vector3::new(5.0, 0.0, 0.0)));
+        accel.set_shutter(0.0, 1.0);
+
+        // instance 0 slides away from the camera along the ray, so later rays
+        // hit it further off
+        let mut phys = crate::physics::new(0.0, 0.2, 0.0);
+        phys.vel = na::Vector3::new(0.0, 0.0, 100.0);
+        accel.set_motion(0, Motion{ phys, local: na::Matrix4::identity() });
+        accel.build();
+
+        let mut ray = crate::gfx::ray::new(na::Point3::new(0.1, 0.3, -30.0), na::Vector3::z());
+        for &time in [0.0, 0.25, 0.5, 1.0].iter() {
+            ray.time = time;
+            let (i, hit) = accel.closest_hit(&ray).unwrap();
+            assert_eq!(i, 0);
+            assert!((hit.t - (30.0 + 100.0 * time)).abs() < 1e-3, "{} at {}", hit.t, time);
+            assert!((accel.model(0, time).transform_point(&na::Point3::origin()).z - 100.0 * time).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use nalgebra as na;
+
+// Arbitrary output variables: what the camera rays hit first, as images
+// alongside the color, for compositing and debugging (and for guiding a
+// denoiser). They're traced separately from the color, through the same
+// sample positions. Depth, normal, albedo and motion are averaged over the
+// pixel's samples that hit something; the object id can't be averaged, so it
+// comes from the sample nearest the pixel center.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    // distance along the camera's view axis; infinite where nothing was hit
+    Depth,
+    // world space geometric normal
+    Normal,
+    Albedo,
+    // 0 where nothing was hit, see Id
+    Id,
+    // how far the surface moves across the screen per second of simulation,
+    // in pixels with +y down, from the cubes' and the camera's velocities
+    Motion,
+}
+
+pub const KINDS: [Kind; 5] = [Kind::Depth, Kind::Normal, Kind::Albedo, Kind::Id, Kind::Motion];
+
+// what the id layer numbers objects by
+#[derive(Clone, Copy, PartialEq)]
+pub enum Id {
+    // Cube::id plus one
+    Group,
+    // instance index plus one, unique per cube
+    Instance,
+}
+
+// seconds the motion vectors are differenced over; spinning cubes don't move
+// in straight lines, so it's kept short
+const MOTION_DT: f32 = 1e-2;
+
+pub fn kind(name: &str) -> Option<Kind> {
+    KINDS.iter().cloned().find(|k| k.name() == name)
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Depth => "depth",
+            Kind::Normal => "normal",
+            Kind::Albedo => "albedo",
+            Kind::Id => "id",
+            Kind::Motion => "motion",
+        }
+    }
+
+    // channel names, as they're called in multilayer files
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Kind::Depth => &["Z"],
+            Kind::Normal => &["X", "Y", "Z"],
+            Kind::Albedo => &["R", "G", "B"],
+            Kind::Id => &["V"],
+            Kind::Motion => &["X", "Y"],
+        }
+    }
+}
+
+// one image of a single kind, channels interleaved, rows from the top
+pub struct Layer {
+    pub kind: Kind,
+    pub width: usize,
+    pub height: usize,
+    pub data: std::vec::Vec<f32>,
+}
+
+pub fn layer(kind: Kind, width: usize, height: usize) -> Layer {
+    Layer{ kind, width, height, data: vec![0.0; width * height * kind.channels().len()] }
+}
+
+impl Layer {
+    pub fn get(&self, x: usize, y: usize) -> &[f32] {
+        let n = self.kind.channels().len();
+        let i = (y * self.width + x) * n;
+        &self.data[i..i + n]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: &[f32]) {
+        let n = self.kind.channels().len();
+        let i = (y * self.width + x) * n;
+        self.data[i..i + n].copy_from_slice(&value[..n]);
+    }
+
+    // the raw values, with missing channels left at 0
+    pub fn framebuffer(&self) -> crate::trace::Framebuffer {
+        let mut fb = crate::trace::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut c = [0.0; 3];
+                for (k, v) in self.get(x, y).iter().enumerate() {
+                    c[k] = *v;
+                }
+                fb.set(x, y, c);
+            }
+        }
+        fb
+    }
+
+    // something to look at: depth fades out to black in the distance, normals
+    // and motion are mapped from [-1, 1] (scaled by the largest motion) to
+    // [0, 1], ids get arbitrary but stable colors; albedo is left linear
+    pub fn preview(&self) -> crate::trace::Framebuffer {
+        let mut fb = crate::trace::new(self.width, self.height);
+        let far = self.data.iter().cloned().filter(|d| d.is_finite()).fold(0.0f32, f32::max).max(1e-6);
+        let fastest = match self.kind {
+            Kind::Motion => self.data.chunks(2).map(|m| (m[0] * m[0] + m[1] * m[1]).sqrt()).fold(0.0f32, f32::max).max(1e-6),
+            _ => 1.0,
+        };
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let v = self.get(x, y);
+                let c = match self.kind {
+                    Kind::Depth => {
+                        let d = if v[0].is_finite() { 1.0 - v[0] / far } else { 0.0 };
+                        [d, d, d]
+                    },
+                    Kind::Normal => [v[0] * 0.5 + 0.5, v[1] * 0.5 + 0.5, v[2] * 0.5 + 0.5],
+                    Kind::Albedo => [v[0], v[1], v[2]],
+                    Kind::Id => id_color(v[0] as u32),
+                    Kind::Motion => [v[0] / fastest * 0.5 + 0.5, v[1] / fastest * 0.5 + 0.5, 0.5],
+                };
+                fb.set(x, y, c);
+            }
+        }
+        fb
+    }
+}
+
+// distinct looking colors for neighbouring ids; 0 is black
+fn id_color(id: u32) -> crate::gfx::Color {
+    if id == 0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let h = crate::gfx::procedural::hash(id);
+    [
+        0.2 + 0.8 * (h & 0xff) as f32 / 255.0,
+        0.2 + 0.8 * ((h >> 8) & 0xff) as f32 / 255.0,
+        0.2 + 0.8 * ((h >> 16) & 0xff) as f32 / 255.0,
+    ]
+}
+
+// what one camera ray saw
+struct Sample {
+    depth: f32,
+    normal: na::Vector3<f32>,
+    albedo: na::Vector3<f32>,
+    id: f32,
+    motion: na::Vector2<f32>,
+}
+
+fn trace(camera: &crate::gfx::camera::Camera, scene: &crate::trace::scene::Scene, ray: &crate::gfx::ray::Ray, width: usize, height: usize, id: Id) -> Option<Sample> {
+    let (instance, hit) = scene.accel.closest_hit(ray)?;
+    let p = ray.at(hit.t);
+
+    let now = camera.at(ray.time);
+    let (_, _, z,) = now.phys.direction();
+
+    // where the same point of the cube is a moment later, and where the camera is
+    let later = na::Point3::from_homogeneous(scene.accel.model(instance, ray.time + MOTION_DT) * hit.local.to_homogeneous()).unwrap_or(p);
+    let motion = match (now.project(&p), camera.at(ray.time + MOTION_DT).project(&later)) {
+        (Some((s0, t0)), Some((s1, t1))) => {
+            na::Vector2::new((s1 - s0) * 0.5 * width as f32, (t0 - t1) * 0.5 * height as f32) / MOTION_DT
+        },
+        _ => na::Vector2::zeros(),
+    };
+
+    let id = match id {
+        Id::Group => scene.groups[instance] + 1,
+        Id::Instance => instance as i32 + 1,
+    };
+
+    Some(Sample{
+        depth: (p.coords - now.phys.pos).dot(&z),
+        normal: hit.normal,
+        albedo: crate::trace::rgb(scene.color(instance, &hit)),
+        id: id as f32,
+        motion,
+    })
+}
+
+// the requested layers, in the order asked for, with the color's samples per
+// pixel (which the film would filter, but these aren't); None if cancelled
+pub fn render(camera: &crate::gfx::camera::Camera, scene: &crate::trace::scene::Scene, settings: &crate::trace::Settings, width: usize, height: usize, kinds: &[Kind], id: Id, cancel: &crate::trace::tiles::Cancel) -> Option<std::vec::Vec<Layer>> {
+    if kinds.is_empty() {
+        return Some(vec![]);
+    }
+
+    let camera = &crate::trace::focus(camera, scene);
+    let samples = settings.samples.max(1);
+
+    let scheduler = crate::trace::tiles::new();
+    let tiles = scheduler.run(width, height, cancel, |_| {}, |tile| {
+        let mut sampler = crate::trace::sampler::new(settings.sampler, settings.seed, settings.samples);
+        let mut pixels = std::vec::Vec::with_capacity(tile.width * tile.height);
+
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                let mut sum = Sample{ depth: 0.0, normal: na::Vector3::zeros(), albedo: na::Vector3::zeros(), id: 0.0, motion: na::Vector2::zeros() };
+                let mut hits = 0;
+                let mut nearest = std::f32::INFINITY;
+
+                for i in 0..samples {
+                    let (fx, fy, ray) = crate::trace::camera_ray(camera, settings, sampler.as_mut(), x, y, width, height, i);
+                    let s = trace(camera, scene, &ray, width, height, id);
+
+                    let d = (fx - x as f32 - 0.5).powi(2) + (fy - y as f32 - 0.5).powi(2);
+                    if d < nearest {
+                        nearest = d;
+                        sum.id = s.as_ref().map(|s| s.id).unwrap_or(0.0);
+                    }
+
+                    if let Some(s) = s {
+                        sum.depth += s.depth;
+                        sum.normal += s.normal;
+                        sum.albedo += s.albedo;
+                        sum.motion += s.motion;
+                        hits += 1;
+                    }
+                }
+
+                if hits == 0 {
+                    sum.depth = std::f32::INFINITY;
+                } else {
+                    let n = hits as f32;
+                    sum.depth /= n;
+                    sum.normal = sum.normal.try_normalize(1e-6).unwrap_or_else(na::Vector3::zeros);
+                    sum.albedo /= n;
+                    sum.motion /= n;
+                }
+                pixels.push(sum);
+            }
+        }
+        pixels
+    })?;
+
+    let mut layers: std::vec::Vec<Layer> = kinds.iter().map(|&k| layer(k, width, height)).collect();
+    for (tile, pixels) in crate::trace::tiles::tiles(width, height, scheduler.tile_size).iter().zip(tiles.iter()) {
+        for (i, s) in pixels.iter().enumerate() {
+            let (x, y) = (tile.x + i % tile.width, tile.y + i / tile.width);
+            for l in layers.iter_mut() {
+                match l.kind {
+                    Kind::Depth => l.set(x, y, &[s.depth]),
+                    Kind::Normal => l.set(x, y, s.normal.as_slice()),
+                    Kind::Albedo => l.set(x, y, s.albedo.as_slice()),
+                    Kind::Id => l.set(x, y, &[s.id]),
+                    Kind::Motion => l.set(x, y, s.motion.as_slice()),
+                }
+            }
+        }
+    }
+    Some(layers)
+}
@@ -0,0 +1,208 @@
+use nalgebra as na;
+use crate::trace::sampling;
+
+// Scattering at a surface. Directions are in the local shading frame (+z is the
+// normal), both pointing away from the surface.
+pub enum Bsdf {
+    Lambert{ albedo: na::Vector3<f32> },
+    // perfect mirror
+    Mirror{ color: na::Vector3<f32> },
+    // rough conductor; GGX microfacets with schlick fresnel, alpha is roughness squared
+    Metal{ color: na::Vector3<f32>, alpha: f32 },
+    // smooth glass; eta is the ratio of the index of refraction on the far side
+    // of the surface to the one wo travels in
+    Glass{ eta: f32 },
+}
+
+pub struct Sample {
+    pub wi: na::Vector3<f32>,
+    pub f: na::Vector3<f32>,
+    pub pdf: f32,
+    // a delta lobe; light sampling can't hit it, so MIS is skipped
+    pub specular: bool,
+}
+
+// reflection about the normal, in the local frame
+pub fn reflect(wo: &na::Vector3<f32>) -> na::Vector3<f32> {
+    na::Vector3::new(-wo.x, -wo.y, wo.z)
+}
+
+// Snell's law in the local frame, for wo above the surface; None on total
+// internal reflection
+pub fn refract(wo: &na::Vector3<f32>, eta: f32) -> Option<na::Vector3<f32>> {
+    let cos_i = wo.z;
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + na::Vector3::z() * (cos_i / eta - cos_t))
+}
+
+// fraction of light reflected off a smooth dielectric, unpolarized
+pub fn fresnel(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.abs().min(1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+// GGX normal distribution
+fn ggx_d(h: &na::Vector3<f32>, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let cos2 = h.z * h.z;
+    let denom = cos2 * (a2 - 1.0) + 1.0;
+    a2 / (std::f32::consts::PI * denom * denom)
+}
+
+// Smith's auxiliary function for GGX
+fn ggx_lambda(w: &na::Vector3<f32>, alpha: f32) -> f32 {
+    let cos2 = w.z * w.z;
+    if cos2 == 0.0 {
+        return std::f32::INFINITY;
+    }
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) / 2.0
+}
+
+fn schlick(f0: &na::Vector3<f32>, cos: f32) -> na::Vector3<f32> {
+    let one = na::Vector3::new(1.0, 1.0, 1.0);
+    f0 + (one - f0) * (1.0 - cos.abs()).powi(5)
+}
+
+fn half_vector(wo: &na::Vector3<f32>, wi: &na::Vector3<f32>) -> Option<na::Vector3<f32>> {
+    let h = wo + wi;
+    if h == na::Vector3::zeros() {
+        return None;
+    }
+    let h = h.normalize();
+    Some(if h.z < 0.0 { -h } else { h })
+}
+
+fn same_hemisphere(a: &na::Vector3<f32>, b: &na::Vector3<f32>) -> bool {
+    a.z * b.z > 0.0
+}
+
+impl Bsdf {
+    pub fn eval(&self, wo: &na::Vector3<f32>, wi: &na::Vector3<f32>) -> na::Vector3<f32> {
+        match self {
+            Bsdf::Lambert{ albedo } => {
+                if same_hemisphere(wo, wi) {
+                    albedo * std::f32::consts::FRAC_1_PI
+                } else {
+                    na::Vector3::zeros()
+                }
+            },
+            Bsdf::Metal{ color, alpha } => {
+                if !same_hemisphere(wo, wi) {
+                    return na::Vector3::zeros();
+                }
+                let h = match half_vector(wo, wi) {
+                    Some(h) => h,
+                    None => return na::Vector3::zeros(),
+                };
+                let g = 1.0 / (1.0 + ggx_lambda(wo, *alpha) + ggx_lambda(wi, *alpha));
+                schlick(color, wi.dot(&h)) * (ggx_d(&h, *alpha) * g / (4.0 * wo.z.abs() * wi.z.abs()))
+            },
+            // delta lobes are only ever reached through sample
+            Bsdf::Mirror{ .. } | Bsdf::Glass{ .. } => na::Vector3::zeros(),
+        }
+    }
+
+    pub fn pdf(&self, wo: &na::Vector3<f32>, wi: &na::Vector3<f32>) -> f32 {
+        match self {
+            Bsdf::Lambert{ .. } => {
+                if same_hemisphere(wo, wi) {
+                    sampling::cosine_hemisphere_pdf(wi.z.abs())
+                } else {
+                    0.0
+                }
+            },
+            Bsdf::Metal{ alpha, .. } => {
+                if !same_hemisphere(wo, wi) {
+                    return 0.0;
+                }
+                match half_vector(wo, wi) {
+                    Some(h) => ggx_d(&h, *alpha) * h.z / (4.0 * wo.dot(&h).abs()),
+                    None => 0.0,
+                }
+            },
+            Bsdf::Mirror{ .. } | Bsdf::Glass{ .. } => 0.0,
+        }
+    }
+
+    pub fn sample(&self, wo: &na::Vector3<f32>, u: f32, v: f32) -> Option<Sample> {
+        match self {
+            Bsdf::Lambert{ .. } => {
+                let mut wi = sampling::cosine_hemisphere(u, v);
+                if wo.z < 0.0 {
+                    wi.z = -wi.z;
+                }
+
+                let pdf = self.pdf(wo, &wi);
+                if pdf <= 0.0 {
+                    return None;
+                }
+
+                Some(Sample{ wi, f: self.eval(wo, &wi), pdf, specular: false })
+            },
+            Bsdf::Metal{ alpha, .. } => {
+                // microfacet normal distributed as D(h) cos(theta_h)
+                let a2 = alpha * alpha;
+                let cos2 = (1.0 - u) / (1.0 + (a2 - 1.0) * u);
+                let sin = (1.0 - cos2).max(0.0).sqrt();
+                let phi = 2.0 * std::f32::consts::PI * v;
+                let mut h = na::Vector3::new(sin * phi.cos(), sin * phi.sin(), cos2.sqrt());
+                if wo.z < 0.0 {
+                    h = -h;
+                }
+
+                let wi = h * (2.0 * wo.dot(&h)) - wo;
+                let pdf = self.pdf(wo, &wi);
+                if pdf <= 0.0 {
+                    return None;
+                }
+
+                Some(Sample{ wi, f: self.eval(wo, &wi), pdf, specular: false })
+            },
+            Bsdf::Mirror{ color } => {
+                let wi = reflect(wo);
+                Some(Sample{ wi, f: color / wi.z.abs(), pdf: 1.0, specular: true })
+            },
+            Bsdf::Glass{ eta } => {
+                // pick reflection or refraction in proportion to the fresnel term
+                let f = fresnel(wo.z, *eta);
+                let one = na::Vector3::new(1.0, 1.0, 1.0);
+
+                match refract(wo, *eta) {
+                    Some(wi) if u >= f => {
+                        // radiance is compressed into the smaller solid angle on the dense side
+                        let t = (1.0 - f) / (eta * eta);
+                        Some(Sample{ wi, f: one * (t / wi.z.abs()), pdf: 1.0 - f, specular: true })
+                    },
+                    Some(_) => {
+                        let wi = reflect(wo);
+                        Some(Sample{ wi, f: one * (f / wi.z.abs()), pdf: f, specular: true })
+                    },
+                    None => {
+                        let wi = reflect(wo);
+                        Some(Sample{ wi, f: one / wi.z.abs(), pdf: 1.0, specular: true })
+                    },
+                }
+            },
+        }
+    }
+
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Bsdf::Lambert{ .. } | Bsdf::Metal{ .. } => false,
+            Bsdf::Mirror{ .. } | Bsdf::Glass{ .. } => true,
+        }
+    }
+}
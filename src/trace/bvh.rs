@@ -0,0 +1,450 @@
+use nalgebra as na;
+use crate::trace::aabb;
+
+// binned SAH; 12 bins is within a percent or two of a full sweep
+const BINS: usize = 12;
+const MAX_LEAF: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECT_COST: f32 = 1.0;
+// traversal keeps one entry per level above the current node, so no leaf may
+// sit deeper than this; see build for how that's kept
+const STACK_SIZE: usize = 64;
+
+// Nodes are stored depth first: an interior node's first child is the next
+// node in the array, `offset` is the second child. For leaves, `offset` is the
+// first of `count` triangles.
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub bounds: aabb::Aabb,
+    pub offset: u32,
+    pub count: u16,
+    pub axis: u8,
+}
+
+impl Node {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+pub struct Bvh {
+    pub nodes: std::vec::Vec<Node>,
+    // triangles reordered so each leaf's are contiguous
+    pub triangles: crate::gfx::Mesh,
+    // maps a reordered triangle back to its index in the source mesh
+    pub indices: std::vec::Vec<u32>,
+}
+
+pub struct Stats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depth: usize,
+    pub min_leaf: usize,
+    pub max_leaf: usize,
+    pub mean_leaf: f32,
+    // expected cost of a random ray, relative to intersecting one triangle
+    pub sah_cost: f32,
+}
+
+struct Primitive {
+    index: u32,
+    bounds: aabb::Aabb,
+    centroid: na::Point3<f32>,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: aabb::Aabb,
+    count: usize,
+}
+
+pub fn new(mesh: &crate::gfx::Mesh) -> Bvh {
+    let bounds: std::vec::Vec<aabb::Aabb> = mesh.iter().map(aabb::from_triangle).collect();
+    let (nodes, indices) = build_nodes(&bounds);
+    let triangles = indices.iter().map(|&i| mesh[i as usize]).collect();
+
+    Bvh{ nodes, triangles, indices }
+}
+
+// Builds a tree over anything with bounds. Returns the nodes along with the
+// order primitives should be stored in so that each leaf's are contiguous.
+pub fn build_nodes(bounds: &[aabb::Aabb]) -> (std::vec::Vec<Node>, std::vec::Vec<u32>) {
+    let mut primitives: std::vec::Vec<Primitive> = bounds.iter().enumerate().map(|(i, b)| {
+        Primitive{ index: i as u32, bounds: *b, centroid: b.centroid() }
+    }).collect();
+
+    let mut nodes: std::vec::Vec<Node> = std::vec::Vec::with_capacity(2 * bounds.len());
+    if !primitives.is_empty() {
+        build(&mut nodes, &mut primitives, 0, 0);
+    }
+
+    (nodes, primitives.iter().map(|p| p.index).collect())
+}
+
+fn leaf(nodes: &mut std::vec::Vec<Node>, bounds: aabb::Aabb, first: usize, count: usize) {
+    nodes.push(Node{ bounds, offset: first as u32, count: count as u16, axis: 0 });
+}
+
+// builds the subtree for primitives, whose first element sits at `first` in the final ordering
+fn build(nodes: &mut std::vec::Vec<Node>, primitives: &mut [Primitive], first: usize, depth: usize) {
+    let bounds = primitives.iter().fold(aabb::empty(), |b, p| b.union(&p.bounds));
+    let n = primitives.len();
+
+    if n <= 1 {
+        return leaf(nodes, bounds, first, n);
+    }
+
+    let mut centroids = aabb::empty();
+    for p in primitives.iter() {
+        centroids.grow(&p.centroid);
+    }
+
+    let axis = centroids.longest_axis();
+    let lo = centroids.min[axis];
+    let extent = centroids.max[axis] - lo;
+
+    // all centroids coincide, no split can separate them
+    if extent <= 0.0 {
+        if n <= MAX_LEAF {
+            return leaf(nodes, bounds, first, n);
+        }
+        return split(nodes, primitives, first, depth, bounds, axis, n / 2);
+    }
+
+    // Median splits halve n, so they finish within ceil(log2(n)) more levels.
+    // Switching to them once that would reach STACK_SIZE keeps every leaf
+    // within STACK_SIZE of the root, however lopsided the SAH splits above were
+    if depth + ceil_log2(n) >= STACK_SIZE {
+        return median(nodes, primitives, first, depth, bounds, axis);
+    }
+
+    let bin_of = |p: &Primitive| (((p.centroid[axis] - lo) / extent * BINS as f32) as usize).min(BINS - 1);
+
+    let mut bins = [Bin{ bounds: aabb::empty(), count: 0 }; BINS];
+    for p in primitives.iter() {
+        let b = &mut bins[bin_of(p)];
+        b.bounds = b.bounds.union(&p.bounds);
+        b.count += 1;
+    }
+
+    // sweep from the right to get the cost of every right hand side, then from the left
+    let mut right_area = [0.0; BINS];
+    let mut right_count = [0; BINS];
+    let mut acc = aabb::empty();
+    let mut count = 0;
+    for i in (1..BINS).rev() {
+        acc = acc.union(&bins[i].bounds);
+        count += bins[i].count;
+        right_area[i] = acc.surface_area();
+        right_count[i] = count;
+    }
+
+    let mut best_cost = std::f32::INFINITY;
+    let mut best_bin = 0;
+    let mut acc = aabb::empty();
+    let mut count = 0;
+    for i in 0..BINS - 1 {
+        acc = acc.union(&bins[i].bounds);
+        count += bins[i].count;
+        let cost = acc.surface_area() * count as f32 + right_area[i + 1] * right_count[i + 1] as f32;
+        if cost < best_cost {
+            best_cost = cost;
+            best_bin = i;
+        }
+    }
+
+    let area = bounds.surface_area().max(std::f32::MIN_POSITIVE);
+    let split_cost = TRAVERSAL_COST + INTERSECT_COST * best_cost / area;
+    let leaf_cost = INTERSECT_COST * n as f32;
+
+    if n <= MAX_LEAF && leaf_cost <= split_cost {
+        return leaf(nodes, bounds, first, n);
+    }
+
+    let mid = partition(primitives, |p| bin_of(p) <= best_bin);
+    if mid == 0 || mid == n {
+        return median(nodes, primitives, first, depth, bounds, axis);
+    }
+
+    split(nodes, primitives, first, depth, bounds, axis, mid)
+}
+
+fn median(nodes: &mut std::vec::Vec<Node>, primitives: &mut [Primitive], first: usize, depth: usize, bounds: aabb::Aabb, axis: usize) {
+    primitives.sort_by(|a, b| a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = primitives.len() / 2;
+    split(nodes, primitives, first, depth, bounds, axis, mid)
+}
+
+fn split(nodes: &mut std::vec::Vec<Node>, primitives: &mut [Primitive], first: usize, depth: usize, bounds: aabb::Aabb, axis: usize, mid: usize) {
+    let index = nodes.len();
+    nodes.push(Node{ bounds, offset: 0, count: 0, axis: axis as u8 });
+
+    let (left, right) = primitives.split_at_mut(mid);
+    build(nodes, left, first, depth + 1);
+    nodes[index].offset = nodes.len() as u32;
+    build(nodes, right, first + mid, depth + 1);
+}
+
+fn ceil_log2(n: usize) -> usize {
+    (usize::BITS - (n.max(1) - 1).leading_zeros()) as usize
+}
+
+fn partition<F: Fn(&Primitive) -> bool>(primitives: &mut [Primitive], pred: F) -> usize {
+    let mut i = 0;
+    for j in 0..primitives.len() {
+        if pred(&primitives[j]) {
+            primitives.swap(i, j);
+            i += 1;
+        }
+    }
+    i
+}
+
+fn inverse(dir: &na::Vector3<f32>) -> na::Vector3<f32> {
+    na::Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z)
+}
+
+// Walks every leaf the ray's box test lets through, calling visit for each
+// primitive slot. visit may shrink ray.t_max to cull what lies behind a hit, or
+// return true to stop early.
+pub fn traverse<F: FnMut(usize, &mut crate::gfx::ray::Ray) -> bool>(nodes: &[Node], ray: &mut crate::gfx::ray::Ray, mut visit: F) {
+    if nodes.is_empty() {
+        return;
+    }
+
+    let inv_dir = inverse(&ray.dir);
+
+    let mut stack = [0usize; STACK_SIZE];
+    let mut top = 0;
+    let mut current = 0;
+
+    loop {
+        let node = &nodes[current];
+
+        if node.bounds.intersect(ray, &inv_dir).is_some() {
+            if node.is_leaf() {
+                let first = node.offset as usize;
+                for i in first..first + node.count as usize {
+                    if visit(i, ray) {
+                        return;
+                    }
+                }
+            } else {
+                // visit the child nearer the ray origin first, so t_max shrinks sooner
+                let (near, far) = if ray.dir[node.axis as usize] < 0.0 {
+                    (node.offset as usize, current + 1)
+                } else {
+                    (current + 1, node.offset as usize)
+                };
+                stack[top] = far;
+                top += 1;
+                current = near;
+                continue;
+            }
+        }
+
+        if top == 0 {
+            return;
+        }
+        top -= 1;
+        current = stack[top];
+    }
+}
+
+// Recomputes every node's bounds bottom up without changing the topology.
+// Children always come after their parent, so a reverse sweep sees them first.
+pub fn refit<F: Fn(usize, usize) -> aabb::Aabb>(nodes: &mut [Node], leaf_bounds: F) {
+    for i in (0..nodes.len()).rev() {
+        let node = nodes[i];
+        nodes[i].bounds = if node.is_leaf() {
+            leaf_bounds(node.offset as usize, node.count as usize)
+        } else {
+            nodes[i + 1].bounds.union(&nodes[node.offset as usize].bounds)
+        };
+    }
+}
+
+pub fn stats(nodes: &[Node]) -> Stats {
+    let mut s = Stats{
+        nodes: nodes.len(),
+        leaves: 0,
+        depth: 0,
+        min_leaf: std::usize::MAX,
+        max_leaf: 0,
+        mean_leaf: 0.0,
+        sah_cost: 0.0,
+    };
+
+    if nodes.is_empty() {
+        s.min_leaf = 0;
+        return s;
+    }
+
+    let root_area = nodes[0].bounds.surface_area().max(std::f32::MIN_POSITIVE);
+    let mut total = 0;
+
+    // (node, depth)
+    let mut stack = vec![(0usize, 1usize)];
+    while let Some((i, depth)) = stack.pop() {
+        let node = &nodes[i];
+        let p = node.bounds.surface_area() / root_area;
+        s.depth = s.depth.max(depth);
+
+        if node.is_leaf() {
+            let n = node.count as usize;
+            s.leaves += 1;
+            s.min_leaf = s.min_leaf.min(n);
+            s.max_leaf = s.max_leaf.max(n);
+            s.sah_cost += p * INTERSECT_COST * n as f32;
+            total += n;
+        } else {
+            s.sah_cost += p * TRAVERSAL_COST;
+            stack.push((i + 1, depth + 1));
+            stack.push((node.offset as usize, depth + 1));
+        }
+    }
+
+    s.mean_leaf = total as f32 / s.leaves as f32;
+    s
+}
+
+impl Bvh {
+    pub fn bounds(&self) -> aabb::Aabb {
+        self.nodes.first().map(|n| n.bounds).unwrap_or_else(aabb::empty)
+    }
+
+    // the normal is in the same space as the mesh the bvh was built from
+    pub fn closest_hit(&self, ray: &crate::gfx::ray::Ray) -> Option<crate::gfx::ray::Hit> {
+        let mut ray = *ray;
+        let shear = crate::gfx::ray::shear(&ray);
+
+        let mut closest: Option<(usize, f32, f32)> = None;
+        traverse(&self.nodes, &mut ray, |i, ray| {
+            if let Some((t, u, v)) = crate::gfx::ray::intersect_sheared(ray, &shear, &self.triangles[i]) {
+                ray.t_max = t;
+                closest = Some((i, u, v));
+            }
+            false
+        });
+
+        closest.map(|(i, u, v)| crate::gfx::ray::Hit{
+            t: ray.t_max,
+            u,
+            v,
+            normal: crate::gfx::ray::face_normal(&self.triangles[i]).normalize(),
+            triangle: self.indices[i] as usize,
+            local: ray.at(ray.t_max),
+            uv: None,
+        })
+    }
+
+    // occlusion query; true as soon as anything lies within ray.t_max
+    pub fn any_hit(&self, ray: &crate::gfx::ray::Ray) -> bool {
+        let mut ray = *ray;
+        let shear = crate::gfx::ray::shear(&ray);
+
+        let mut hit = false;
+        traverse(&self.nodes, &mut ray, |i, ray| {
+            hit = crate::gfx::ray::intersect_sheared(ray, &shear, &self.triangles[i]).is_some();
+            hit
+        });
+        hit
+    }
+
+    pub fn stats(&self) -> Stats {
+        stats(&self.nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    fn soup(n: usize, seed: u64) -> crate::gfx::Mesh {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+        (0..n).map(|_| {
+            let c = na::Point3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0));
+            let mut corner = || c + na::Vector3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+            [corner(), corner(), corner()]
+        }).collect()
+    }
+
+    // stats counts the root as depth 1
+    fn check_shape(bvh: &Bvh, triangles: usize) {
+        let s = bvh.stats();
+        assert!(s.depth <= STACK_SIZE + 1, "depth {} past the traversal stack", s.depth);
+        assert!(s.min_leaf >= 1);
+        assert!(s.max_leaf <= MAX_LEAF, "leaf of {}", s.max_leaf);
+        assert_eq!((s.mean_leaf * s.leaves as f32).round() as usize, triangles);
+        assert_eq!(s.nodes, 2 * s.leaves - 1);
+    }
+
+    // every triangle in turn, keeping the nearest; (triangle, t)
+    fn brute_force(mesh: &crate::gfx::Mesh, ray: &crate::gfx::ray::Ray) -> Option<(usize, f32)> {
+        let shear = crate::gfx::ray::shear(ray);
+        let mut nearest: Option<(usize, f32)> = None;
+        for (i, triangle) in mesh.iter().enumerate() {
+            if let Some((t, _, _)) = crate::gfx::ray::intersect_sheared(ray, &shear, triangle) {
+                if nearest.map_or(true, |(_, best)| t < best) {
+                    nearest = Some((i, t));
+                }
+            }
+        }
+        nearest
+    }
+
+    #[test]
+    fn random_soup_is_well_formed() {
+        let mesh = soup(5000, 1);
+        check_shape(&new(&mesh), mesh.len());
+    }
+
+    #[test]
+    fn ceil_log2_bounds_median_levels() {
+        let expected = [(1, 0), (2, 1), (3, 2), (4, 2), (5, 3), (64, 6), (65, 7), (1 << 20, 20)];
+        for &(n, levels) in expected.iter() {
+            assert_eq!(ceil_log2(n), levels, "ceil_log2({})", n);
+        }
+    }
+
+    // geometrically spaced triangles make SAH split off only a few at a time,
+    // growing a deep, lopsided tree
+    #[test]
+    fn lopsided_splits_stay_within_the_stack() {
+        let mesh: crate::gfx::Mesh = (0..300).map(|i| {
+            let x = 1.3f32.powi(i);
+            [na::Point3::new(x, 0.0, 0.0), na::Point3::new(1.1 * x, 0.0, 0.0), na::Point3::new(x, 0.1 * x, 0.0)]
+        }).collect();
+        let bvh = new(&mesh);
+        check_shape(&bvh, mesh.len());
+
+        for &i in [0, 150, 299].iter() {
+            let x = 1.3f32.powi(i);
+            let ray = crate::gfx::ray::new(na::Point3::new(1.02 * x, 0.02 * x, -1.0), na::Vector3::z());
+            assert_eq!(bvh.closest_hit(&ray).map(|h| h.triangle), Some(i as usize));
+        }
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        let mesh = soup(2000, 2);
+        let bvh = new(&mesh);
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+        for _ in 0..2000 {
+            let origin = na::Point3::new(rng.gen_range(-15.0, 15.0), rng.gen_range(-15.0, 15.0), rng.gen_range(-15.0, 15.0));
+            let target = na::Point3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0));
+            let ray = crate::gfx::ray::new(origin, target - origin);
+
+            let expected = brute_force(&mesh, &ray);
+            assert_eq!(bvh.any_hit(&ray), expected.is_some());
+            let got = bvh.closest_hit(&ray);
+            assert_eq!(got.as_ref().map(|h| h.triangle), expected.map(|(i, _)| i));
+            if let (Some(hit), Some((_, t))) = (got, expected) {
+                assert!((hit.t - t).abs() <= 1e-5 * t.max(1.0));
+            }
+        }
+    }
+}
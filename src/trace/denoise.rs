@@ -0,0 +1,299 @@
+use nalgebra as na;
+
+// Edge aware à-trous wavelet filter (Dammertz et al., with the variance
+// guided luminance weights of SVGF) for renders with only a few samples per
+// pixel. The color is divided by the albedo first, so textures stay sharp and
+// only the lighting is blurred, then a 5x5 kernel is applied a few times with
+// its taps twice as far apart each time. Taps count for less the more their
+// normal, depth or lighting differ from the pixel's; the lighting tolerance
+// comes from how noisy the pixel's neighbourhood is, and shrinks as it's
+// smoothed out.
+
+#[derive(Clone, Copy)]
+pub struct Settings {
+    // each one doubles the filter's reach: 5 covers 61 pixels across
+    pub iterations: u32,
+    // how many standard deviations of noise two luminances may differ by
+    pub sigma_luminance: f32,
+    // exponent on the cosine between normals
+    pub sigma_normal: f32,
+    // relative depth difference allowed per pixel of distance
+    pub sigma_depth: f32,
+}
+
+pub fn settings() -> Settings {
+    Settings{
+        iterations: 5,
+        sigma_luminance: 4.0,
+        sigma_normal: 64.0,
+        sigma_depth: 0.02,
+    }
+}
+
+// the aovs denoise needs, see aov::render
+pub const GUIDES: [crate::trace::aov::Kind; 3] = [
+    crate::trace::aov::Kind::Albedo,
+    crate::trace::aov::Kind::Normal,
+    crate::trace::aov::Kind::Depth,
+];
+
+// B3 spline, from the center outwards
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// below this an albedo channel isn't divided out
+const MIN_ALBEDO: f32 = 1e-3;
+
+fn luminance(c: &na::Vector3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn guide<'a>(guides: &'a [crate::trace::aov::Layer], kind: crate::trace::aov::Kind, fb: &crate::trace::Framebuffer) -> Result<&'a crate::trace::aov::Layer, std::string::String> {
+    let layer = guides.iter().find(|l| l.kind == kind).ok_or_else(|| format!("denoise: no {} aov", kind.name()))?;
+    if layer.width != fb.width || layer.height != fb.height {
+        return Err(format!("denoise: {} aov is {}x{}, the image {}x{}", kind.name(), layer.width, layer.height, fb.width, fb.height));
+    }
+    Ok(layer)
+}
+
+// what's known about each pixel's surface
+struct Surface {
+    // false where the camera ray missed, those pixels are left alone
+    hit: bool,
+    normal: na::Vector3<f32>,
+    depth: f32,
+    albedo: na::Vector3<f32>,
+}
+
+// the denoised image; guides must hold the GUIDES layers, rendered the same size
+pub fn denoise(fb: &crate::trace::Framebuffer, guides: &[crate::trace::aov::Layer], settings: &Settings) -> Result<crate::trace::Framebuffer, std::string::String> {
+    use crate::trace::aov::Kind;
+
+    let albedo = guide(guides, Kind::Albedo, fb)?;
+    let normal = guide(guides, Kind::Normal, fb)?;
+    let depth = guide(guides, Kind::Depth, fb)?;
+    let (width, height) = (fb.width, fb.height);
+
+    let mut surfaces = std::vec::Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (a, n, d) = (albedo.get(x, y), normal.get(x, y), depth.get(x, y)[0]);
+            surfaces.push(Surface{
+                hit: d.is_finite(),
+                normal: na::Vector3::new(n[0], n[1], n[2]),
+                depth: d,
+                albedo: na::Vector3::new(a[0], a[1], a[2]),
+            });
+        }
+    }
+
+    // lighting alone
+    let mut color: std::vec::Vec<na::Vector3<f32>> = fb.pixels.iter().zip(surfaces.iter()).map(|(c, s)| {
+        let c = crate::trace::rgb(*c);
+        if !s.hit {
+            return c;
+        }
+        c.zip_map(&s.albedo, |c, a| if a > MIN_ALBEDO { c / a } else { c })
+    }).collect();
+
+    let mut variance = spatial_variance(&color, &surfaces, width, height);
+
+    for i in 0..settings.iterations {
+        let (c, v) = step(&color, &variance, &surfaces, width, height, 1 << i, settings);
+        color = c;
+        variance = v;
+    }
+
+    let mut out = crate::trace::new(width, height);
+    for (p, (c, s)) in out.pixels.iter_mut().zip(color.iter().zip(surfaces.iter())) {
+        let c = if s.hit { c.zip_map(&s.albedo, |c, a| if a > MIN_ALBEDO { c * a } else { c }) } else { *c };
+        *p = [c.x, c.y, c.z];
+    }
+    Ok(out)
+}
+
+// variance of the luminance over each pixel's 5x5 neighbourhood, among the
+// neighbours facing about the same way; stands in for the per pixel variance
+// of the samples, which the film doesn't keep
+fn spatial_variance(color: &[na::Vector3<f32>], surfaces: &[Surface], width: usize, height: usize) -> std::vec::Vec<f32> {
+    let mut variance = vec![0.0; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let p = &surfaces[y * width + x];
+            if !p.hit {
+                continue;
+            }
+
+            let (mut sum, mut sum2, mut n) = (0.0, 0.0, 0.0);
+            for qy in y.saturating_sub(2)..(y + 3).min(height) {
+                for qx in x.saturating_sub(2)..(x + 3).min(width) {
+                    let q = &surfaces[qy * width + qx];
+                    if !q.hit || p.normal.dot(&q.normal) < 0.9 {
+                        continue;
+                    }
+                    let l = luminance(&color[qy * width + qx]);
+                    sum += l;
+                    sum2 += l * l;
+                    n += 1.0;
+                }
+            }
+
+            if n > 0.0 {
+                let mean = sum / n;
+                variance[y * width + x] = (sum2 / n - mean * mean).max(0.0);
+            }
+        }
+    }
+
+    variance
+}
+
+// one iteration, with taps `stride` pixels apart; returns the filtered color
+// and its variance
+fn step(color: &[na::Vector3<f32>], variance: &[f32], surfaces: &[Surface], width: usize, height: usize, stride: usize, settings: &Settings) -> (std::vec::Vec<na::Vector3<f32>>, std::vec::Vec<f32>) {
+    let mut out_color = color.to_vec();
+    let mut out_variance = variance.to_vec();
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let p = &surfaces[i];
+            if !p.hit {
+                continue;
+            }
+
+            let lp = luminance(&color[i]);
+            // the variance is noisy too, so it's blurred a little before use
+            let deviation = blurred(variance, surfaces, width, height, x, y).sqrt();
+
+            let mut sum = na::Vector3::zeros();
+            let mut sum_variance = 0.0;
+            let mut total = 0.0;
+
+            for dy in -2i64..=2 {
+                for dx in -2i64..=2 {
+                    let qx = x as i64 + dx * stride as i64;
+                    let qy = y as i64 + dy * stride as i64;
+                    if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                        continue;
+                    }
+                    let j = qy as usize * width + qx as usize;
+                    let q = &surfaces[j];
+                    if !q.hit {
+                        continue;
+                    }
+
+                    let distance = ((dx * dx + dy * dy) as f32).sqrt() * stride as f32;
+                    let wn = p.normal.dot(&q.normal).max(0.0).powf(settings.sigma_normal);
+                    let wz = if distance > 0.0 {
+                        (-(p.depth - q.depth).abs() / (settings.sigma_depth * p.depth.abs().max(1e-6) * distance)).exp()
+                    } else {
+                        1.0
+                    };
+                    let wl = (-(lp - luminance(&color[j])).abs() / (settings.sigma_luminance * deviation + 1e-6)).exp();
+
+                    let w = KERNEL[dx.unsigned_abs() as usize] * KERNEL[dy.unsigned_abs() as usize] * wn * wz * wl;
+                    sum += color[j] * w;
+                    sum_variance += variance[j] * w * w;
+                    total += w;
+                }
+            }
+
+            // nothing counts if the normals averaged out to nothing
+            if total > 0.0 {
+                out_color[i] = sum / total;
+                out_variance[i] = sum_variance / (total * total);
+            }
+        }
+    }
+
+    (out_color, out_variance)
+}
+
+// 3x3 gaussian of the variance around (x, y), over pixels that hit something
+fn blurred(variance: &[f32], surfaces: &[Surface], width: usize, height: usize, x: usize, y: usize) -> f32 {
+    let weights = [0.25, 0.5, 0.25];
+    let (mut sum, mut total) = (0.0, 0.0);
+
+    for qy in y.saturating_sub(1)..(y + 2).min(height) {
+        for qx in x.saturating_sub(1)..(x + 2).min(width) {
+            let j = qy * width + qx;
+            if !surfaces[j].hit {
+                continue;
+            }
+            let w = weights[qx + 1 - x] * weights[qy + 1 - y];
+            sum += variance[j] * w;
+            total += w;
+        }
+    }
+
+    sum / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::aov::Kind;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    const SIZE: usize = 24;
+
+    // albedo, normal and depth guides; pixels left of `edge` face +z, the rest +x
+    fn guides(edge: usize) -> std::vec::Vec<crate::trace::aov::Layer> {
+        let mut albedo = crate::trace::aov::layer(Kind::Albedo, SIZE, SIZE);
+        let mut normal = crate::trace::aov::layer(Kind::Normal, SIZE, SIZE);
+        let mut depth = crate::trace::aov::layer(Kind::Depth, SIZE, SIZE);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                albedo.set(x, y, &[0.5, 0.5, 0.5]);
+                normal.set(x, y, if x < edge { &[0.0, 0.0, 1.0] } else { &[1.0, 0.0, 0.0] });
+                depth.set(x, y, &[10.0]);
+            }
+        }
+        vec![albedo, normal, depth]
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let mut fb = crate::trace::new(SIZE, SIZE);
+        for p in fb.pixels.iter_mut() {
+            *p = [0.3, 0.2, 0.1];
+        }
+
+        let out = denoise(&fb, &guides(SIZE / 2), &settings()).unwrap();
+        for p in out.pixels.iter() {
+            for k in 0..3 {
+                assert!((p[k] - fb.pixels[0][k]).abs() < 1e-5, "{:?}", p);
+            }
+        }
+    }
+
+    #[test]
+    fn edges_in_the_guides_are_kept() {
+        // two noisy flat regions meeting where the normals change
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+        let mut fb = crate::trace::new(SIZE, SIZE);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let base = if x < SIZE / 2 { 0.2 } else { 0.8 };
+                let c = base + rng.gen_range(-0.1, 0.1);
+                fb.set(x, y, [c, c, c]);
+            }
+        }
+
+        let out = denoise(&fb, &guides(SIZE / 2), &settings()).unwrap();
+        let mut noise = (0.0, 0.0);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let base = if x < SIZE / 2 { 0.2 } else { 0.8 };
+                let c = out.get(x, y)[0];
+                // the sides don't bleed into each other, even right at the edge
+                assert!((c - base).abs() < 0.05, "{} at ({}, {})", c, x, y);
+                noise.0 += (fb.get(x, y)[0] - base).abs();
+                noise.1 += (c - base).abs();
+            }
+        }
+        assert!(noise.1 < noise.0 * 0.5, "{:?}", noise);
+    }
+}
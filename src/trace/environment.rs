@@ -0,0 +1,81 @@
+use nalgebra as na;
+
+// The environment as a light for the path tracer. Directions are importance
+// sampled by luminance: the map is tabulated on a latitude-longitude grid,
+// weighted by each row's solid angle, and sampled through a 2d distribution
+// over it, so bright regions (the sun, windows) get most of the samples.
+
+pub struct Light {
+    pub environment: std::sync::Arc<crate::gfx::environment::Environment>,
+    distribution: crate::trace::sampling::Distribution2d,
+    // average radiance over the sphere, for the whitted integrator's ambient term
+    pub average: na::Vector3<f32>,
+}
+
+// finer than this doesn't sample noticeably better
+const MAX_WIDTH: usize = 1024;
+
+fn luminance(c: &crate::gfx::Color) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+pub fn new(environment: std::sync::Arc<crate::gfx::environment::Environment>) -> Light {
+    use crate::gfx::environment::Map;
+
+    // about a texel per cell
+    let width = match &environment.map {
+        Map::Equirect(t) => t.width,
+        Map::Cubemap(faces) => faces[0].width * 4,
+    }.clamp(2, MAX_WIDTH);
+    let height = width / 2;
+
+    let mut func = vec![0.0; width * height];
+    let mut average = na::Vector3::zeros();
+    let mut total = 0.0;
+
+    for y in 0..height {
+        let v = (y as f32 + 0.5) / height as f32;
+        let sin_theta = (v * std::f32::consts::PI).sin();
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32;
+            let c = environment.radiance(&crate::gfx::environment::equirect_direction(u, v));
+            func[y * width + x] = luminance(&c) * sin_theta;
+            average += crate::trace::rgb(c) * sin_theta;
+            total += sin_theta;
+        }
+    }
+
+    Light{
+        environment,
+        distribution: crate::trace::sampling::distribution_2d(&func, width, height),
+        average: average / total,
+    }
+}
+
+impl Light {
+    pub fn radiance(&self, dir: &na::Vector3<f32>) -> na::Vector3<f32> {
+        crate::trace::rgb(self.environment.radiance(dir))
+    }
+
+    // a direction towards the environment, its radiance and its solid angle density
+    pub fn sample(&self, u: f32, v: f32) -> (na::Vector3<f32>, na::Vector3<f32>, f32) {
+        let (p, pdf) = self.distribution.sample(u, v);
+        let wi = crate::gfx::environment::equirect_direction(p.x, p.y);
+        (wi, self.radiance(&wi), solid_angle_pdf(pdf, p.y))
+    }
+
+    pub fn pdf(&self, dir: &na::Vector3<f32>) -> f32 {
+        let p = crate::gfx::environment::equirect_uv(dir);
+        let p = na::Point2::new(p.x - p.x.floor(), p.y.clamp(0.0, 1.0));
+        solid_angle_pdf(self.distribution.pdf(&p), p.y)
+    }
+}
+
+// the (u, v) square covers the sphere with 2 pi^2 sin(theta) of solid angle per unit area
+fn solid_angle_pdf(pdf: f32, v: f32) -> f32 {
+    let sin_theta = (v * std::f32::consts::PI).sin();
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+    pdf / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta)
+}
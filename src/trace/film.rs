@@ -0,0 +1,247 @@
+use nalgebra as na;
+
+// Where camera samples end up. Every sample is spread over the pixels within
+// the filter's radius of where it landed, weighted by the filter, and each
+// pixel is the weighted average of what reached it. Wider filters trade
+// sharpness for less aliasing; Mitchell and Lanczos have negative lobes that
+// sharpen edges again, at the cost of some ringing.
+
+#[derive(Clone, Copy)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    // B = C = 1/3
+    Mitchell,
+    // sinc windowed by a sinc stretched to the radius
+    Lanczos,
+}
+
+pub fn filter(name: &str) -> Option<Filter> {
+    match name {
+        "box" => Some(Filter::Box),
+        "tent" => Some(Filter::Tent),
+        "gaussian" => Some(Filter::Gaussian),
+        "mitchell" => Some(Filter::Mitchell),
+        "lanczos" => Some(Filter::Lanczos),
+        _ => None,
+    }
+}
+
+// radius each filter is usually used at, in pixels
+pub fn default_radius(filter: Filter) -> f32 {
+    match filter {
+        Filter::Box => 0.5,
+        Filter::Tent => 1.0,
+        Filter::Gaussian => 1.5,
+        Filter::Mitchell => 2.0,
+        Filter::Lanczos => 3.0,
+    }
+}
+
+const GAUSSIAN_ALPHA: f32 = 2.0;
+
+fn sinc(x: f32) -> f32 {
+    let x = x.abs() * std::f32::consts::PI;
+    if x < 1e-5 { 1.0 } else { x.sin() / x }
+}
+
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x > 2.0 {
+        0.0
+    } else if x > 1.0 {
+        ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+    }
+}
+
+impl Filter {
+    // weight of a sample d pixels away along one axis; filters are separable,
+    // and 1 at the center (the film divides by the total weight anyway)
+    fn eval_1d(&self, d: f32, radius: f32) -> f32 {
+        if d.abs() >= radius {
+            return 0.0;
+        }
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - d.abs() / radius,
+            Filter::Gaussian => {
+                let edge = (-GAUSSIAN_ALPHA * radius * radius).exp();
+                ((-GAUSSIAN_ALPHA * d * d).exp() - edge).max(0.0) / (1.0 - edge)
+            },
+            // mitchell's support is [-2, 2], stretched to the radius
+            Filter::Mitchell => mitchell(2.0 * d / radius) / mitchell(0.0),
+            // windowed by a sinc stretched to the radius, so the kernel ends
+            // where its window reaches zero
+            Filter::Lanczos => sinc(d) * sinc(d / radius),
+        }
+    }
+
+    pub fn eval(&self, dx: f32, dy: f32, radius: f32) -> f32 {
+        self.eval_1d(dx, radius) * self.eval_1d(dy, radius)
+    }
+}
+
+// a window of the image: the whole of it, or a tile plus the border its
+// samples spill into
+pub struct Film {
+    // position of the window in the image
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    // size of the whole image
+    pub image_width: usize,
+    pub image_height: usize,
+    pub filter: Filter,
+    pub radius: f32,
+    sum: std::vec::Vec<na::Vector3<f32>>,
+    weight: std::vec::Vec<f32>,
+}
+
+pub fn new(width: usize, height: usize, filter: Filter, radius: f32) -> Film {
+    Film{
+        x: 0,
+        y: 0,
+        width,
+        height,
+        image_width: width,
+        image_height: height,
+        filter,
+        radius,
+        sum: vec![na::Vector3::zeros(); width * height],
+        weight: vec![0.0; width * height],
+    }
+}
+
+impl Film {
+    // an empty window of the same image, filtered the same way
+    fn window(&self, x: usize, y: usize, width: usize, height: usize) -> Film {
+        Film{
+            x,
+            y,
+            width,
+            height,
+            image_width: self.image_width,
+            image_height: self.image_height,
+            filter: self.filter,
+            radius: self.radius,
+            sum: vec![na::Vector3::zeros(); width * height],
+            weight: vec![0.0; width * height],
+        }
+    }
+
+    // how many pixels past its own a sample can reach
+    pub fn reach(&self) -> usize {
+        (self.radius - 0.5).max(0.0).ceil() as usize
+    }
+
+    // an empty film covering the tile and everything its samples can reach
+    pub fn tile(&self, tile: &crate::trace::tiles::Tile) -> Film {
+        let r = self.reach();
+        let x0 = tile.x.saturating_sub(r);
+        let y0 = tile.y.saturating_sub(r);
+        let x1 = (tile.x + tile.width + r).min(self.image_width);
+        let y1 = (tile.y + tile.height + r).min(self.image_height);
+        self.window(x0, y0, x1 - x0, y1 - y0)
+    }
+
+    // a sample that landed at (fx, fy) in image coordinates, where pixel (x, y)
+    // covers [x, x + 1) x [y, y + 1)
+    pub fn add(&mut self, fx: f32, fy: f32, l: &na::Vector3<f32>) {
+        // pixel centers are at half integers
+        let x0 = ((fx - 0.5 - self.radius).ceil().max(self.x as f32)) as usize;
+        let y0 = ((fy - 0.5 - self.radius).ceil().max(self.y as f32)) as usize;
+        let x1 = ((fx - 0.5 + self.radius).floor() as isize).min((self.x + self.width) as isize - 1);
+        let y1 = ((fy - 0.5 + self.radius).floor() as isize).min((self.y + self.height) as isize - 1);
+
+        for y in y0 as isize..=y1 {
+            for x in x0 as isize..=x1 {
+                let w = self.filter.eval(x as f32 + 0.5 - fx, y as f32 + 0.5 - fy, self.radius);
+                if w != 0.0 {
+                    let i = (y as usize - self.y) * self.width + (x as usize - self.x);
+                    self.sum[i] += l * w;
+                    self.weight[i] += w;
+                }
+            }
+        }
+    }
+
+    // adds in another window of the same image
+    pub fn merge(&mut self, other: &Film) {
+        for y in 0..other.height {
+            for x in 0..other.width {
+                let (ix, iy) = (other.x + x, other.y + y);
+                if ix < self.x || iy < self.y || ix >= self.x + self.width || iy >= self.y + self.height {
+                    continue;
+                }
+                let i = (iy - self.y) * self.width + (ix - self.x);
+                let j = y * other.width + x;
+                self.sum[i] += other.sum[j];
+                self.weight[i] += other.weight[j];
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for s in self.sum.iter_mut() {
+            *s = na::Vector3::zeros();
+        }
+        for w in self.weight.iter_mut() {
+            *w = 0.0;
+        }
+    }
+
+    // pixels nothing reached (or whose weights cancelled out) are black
+    pub fn framebuffer(&self) -> crate::trace::Framebuffer {
+        let mut fb = crate::trace::new(self.width, self.height);
+        for (p, (s, &w)) in fb.pixels.iter_mut().zip(self.sum.iter().zip(self.weight.iter())) {
+            let c = if w > 0.0 { s / w } else { na::Vector3::zeros() };
+            *p = [c.x, c.y, c.z];
+        }
+        fb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 5] = [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell, Filter::Lanczos];
+
+    #[test]
+    fn filters_are_one_at_the_center_and_zero_at_the_radius() {
+        for &f in FILTERS.iter() {
+            let r = default_radius(f);
+            assert!((f.eval(0.0, 0.0, r) - 1.0).abs() < 1e-5);
+            assert_eq!(f.eval(r, 0.0, r), 0.0);
+            assert_eq!(f.eval(0.0, -r, r), 0.0);
+            // and get there smoothly, except for the box
+            if let Filter::Box = f {
+                continue;
+            }
+            assert!(f.eval(r - 1e-3, 0.0, r).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn box_splats_conserve_energy() {
+        let mut film = new(8, 8, Filter::Box, 0.5);
+        let l = na::Vector3::new(1.0, 2.0, 3.0);
+        let n = 20;
+        for i in 0..n {
+            for j in 0..n {
+                film.add((i as f32 + 0.37) / n as f32 * 8.0, (j as f32 + 0.61) / n as f32 * 8.0, &l);
+            }
+        }
+
+        let samples = (n * n) as f32;
+        let weight: f32 = film.weight.iter().sum();
+        let sum: na::Vector3<f32> = film.sum.iter().sum();
+        assert!((weight - samples).abs() < 1e-3);
+        assert!((sum - l * samples).amax() < 1e-2);
+    }
+}
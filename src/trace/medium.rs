@@ -0,0 +1,285 @@
+use nalgebra as na;
+use rand::Rng;
+use crate::trace::aabb;
+
+// Participating media for the path tracer: fog and smoke that absorb light and
+// scatter it in new directions. Each medium fills a box in world space, with a
+// density that's either constant or interpolated from a grid. Coefficients are
+// gray (the same in every channel), so free flights can be sampled exactly by
+// delta tracking and getting through a medium never changes a path's color.
+
+pub struct Medium {
+    pub bounds: aabb::Aabb,
+    // per unit of distance, at density 1
+    pub sigma_a: f32,
+    pub sigma_s: f32,
+    // Henyey-Greenstein asymmetry; negative scatters back, positive forward
+    pub g: f32,
+    pub density: Density,
+}
+
+pub enum Density {
+    // 1 everywhere in the box
+    Constant,
+    Grid(Grid),
+}
+
+// densities at the centers of nx * ny * nz cells spanning the medium's box,
+// trilinearly interpolated in between
+pub struct Grid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    // x fastest, then y, then z
+    values: std::vec::Vec<f32>,
+    // largest value, which bounds the interpolated density
+    max: f32,
+}
+
+// homogeneous fog filling the box
+pub fn fog(bounds: aabb::Aabb, sigma_a: f32, sigma_s: f32, g: f32) -> Medium {
+    Medium{ bounds, sigma_a, sigma_s, g, density: Density::Constant }
+}
+
+pub fn volume(bounds: aabb::Aabb, grid: Grid, sigma_a: f32, sigma_s: f32, g: f32) -> Medium {
+    Medium{ bounds, sigma_a, sigma_s, g, density: Density::Grid(grid) }
+}
+
+// density from f at every cell center, given in [0, 1] across the box; negative
+// densities are clamped to 0
+pub fn grid<F: Fn(&na::Point3<f32>) -> f32>(nx: usize, ny: usize, nz: usize, f: F) -> Grid {
+    let mut values = std::vec::Vec::with_capacity(nx * ny * nz);
+    for z in 0..nz {
+        for y in 0..ny {
+            for x in 0..nx {
+                let p = na::Point3::new(
+                    (x as f32 + 0.5) / nx as f32,
+                    (y as f32 + 0.5) / ny as f32,
+                    (z as f32 + 0.5) / nz as f32,
+                );
+                values.push(f(&p).max(0.0));
+            }
+        }
+    }
+
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    Grid{ nx, ny, nz, values, max }
+}
+
+impl Grid {
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[(z * self.ny + y) * self.nx + x]
+    }
+
+    // q is in [0, 1] across the box
+    pub fn lookup(&self, q: &na::Point3<f32>) -> f32 {
+        // cell centers sit at half integers; clamp at the outer ones
+        let axis = |u: f32, n: usize| {
+            let x = (u * n as f32 - 0.5).clamp(0.0, (n - 1) as f32);
+            let i = (x as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), x - i as f32)
+        };
+        let (x0, x1, fx) = axis(q.x, self.nx);
+        let (y0, y1, fy) = axis(q.y, self.ny);
+        let (z0, z1, fz) = axis(q.z, self.nz);
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let c00 = lerp(self.at(x0, y0, z0), self.at(x1, y0, z0), fx);
+        let c10 = lerp(self.at(x0, y1, z0), self.at(x1, y1, z0), fx);
+        let c01 = lerp(self.at(x0, y0, z1), self.at(x1, y0, z1), fx);
+        let c11 = lerp(self.at(x0, y1, z1), self.at(x1, y1, z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+// a scattering event inside one of the scene's media
+pub struct Collision {
+    // along the ray, in units of ray.dir
+    pub t: f32,
+    pub medium: usize,
+}
+
+impl Medium {
+    pub fn sigma_t(&self) -> f32 {
+        self.sigma_a + self.sigma_s
+    }
+
+    // chance a collision scatters rather than absorbs
+    pub fn albedo(&self) -> f32 {
+        if self.sigma_t() > 0.0 { self.sigma_s / self.sigma_t() } else { 0.0 }
+    }
+
+    pub fn density(&self, p: &na::Point3<f32>) -> f32 {
+        match &self.density {
+            Density::Constant => 1.0,
+            Density::Grid(grid) => {
+                let q = (p - self.bounds.min).component_div(&self.bounds.extent());
+                grid.lookup(&na::Point3::from(q))
+            },
+        }
+    }
+
+    fn max_density(&self) -> f32 {
+        match &self.density {
+            Density::Constant => 1.0,
+            Density::Grid(grid) => grid.max,
+        }
+    }
+
+    // the part of the ray inside the box, before ray.t_max
+    fn range(&self, ray: &crate::gfx::ray::Ray) -> Option<(f32, f32)> {
+        let inv_dir = na::Vector3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        self.bounds.range(ray, &inv_dir)
+    }
+
+    // Delta tracking: steps through the box at the rate the densest point would
+    // stop the ray, keeping each step as a real collision with the chance the
+    // density there is of that. None if the ray gets through
+    fn sample<R: Rng>(&self, ray: &crate::gfx::ray::Ray, rng: &mut R) -> Option<f32> {
+        let (t0, t1) = self.range(ray)?;
+        let majorant = self.sigma_t() * self.max_density() * ray.dir.magnitude();
+        if majorant <= 0.0 {
+            return None;
+        }
+
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rng.gen::<f32>()).ln() / majorant;
+            if t >= t1 {
+                return None;
+            }
+            if rng.gen::<f32>() * self.max_density() < self.density(&ray.at(t)) {
+                return Some(t);
+            }
+        }
+    }
+
+    // Beer's law in closed form for constant density; otherwise ratio tracking,
+    // which takes the same steps as delta tracking but weighs the ray down by
+    // the chance of each being real instead of stopping it
+    fn transmittance<R: Rng>(&self, ray: &crate::gfx::ray::Ray, rng: &mut R) -> f32 {
+        let (t0, t1) = match self.range(ray) {
+            Some(r) => r,
+            None => return 1.0,
+        };
+        let majorant = self.sigma_t() * self.max_density() * ray.dir.magnitude();
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+
+        if let Density::Constant = self.density {
+            return (-majorant * (t1 - t0)).exp();
+        }
+
+        let mut tr = 1.0;
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rng.gen::<f32>()).ln() / majorant;
+            if t >= t1 || tr <= 0.0 {
+                return tr;
+            }
+            tr *= 1.0 - self.density(&ray.at(t)) / self.max_density();
+        }
+    }
+}
+
+// The first collision along the ray before ray.t_max in any of the media. Each
+// medium is tracked on its own and the nearest collision wins, which samples
+// their sum exactly. None if the ray gets through all of them
+pub fn sample<R: Rng>(media: &[Medium], ray: &crate::gfx::ray::Ray, rng: &mut R) -> Option<Collision> {
+    let mut ray = *ray;
+    let mut nearest = None;
+    for (i, m) in media.iter().enumerate() {
+        if let Some(t) = m.sample(&ray, rng) {
+            ray.t_max = t;
+            nearest = Some(Collision{ t, medium: i });
+        }
+    }
+    nearest
+}
+
+// fraction of light getting through every medium along the ray, up to ray.t_max
+pub fn transmittance<R: Rng>(media: &[Medium], ray: &crate::gfx::ray::Ray, rng: &mut R) -> f32 {
+    media.iter().map(|m| m.transmittance(ray, rng)).product()
+}
+
+// density over solid angle of scattering by an angle with cosine cos; cos is
+// measured against wo, which points back where the light is headed, so g > 0
+// peaks at cos = -1, straight on
+pub fn henyey_greenstein(cos: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g + 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * denom.max(0.0).sqrt())
+}
+
+// a direction scattered from wo and its density, which is also the phase
+// function's value, since it's sampled exactly
+pub fn sample_phase(wo: &na::Vector3<f32>, g: f32, u: f32, v: f32) -> (na::Vector3<f32>, f32) {
+    let cos = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u
+    } else {
+        let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u);
+        -(1.0 + g * g - s * s) / (2.0 * g)
+    };
+    let cos = cos.clamp(-1.0, 1.0);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * v;
+
+    let frame = crate::trace::sampling::frame(wo);
+    let wi = frame.to_world(&na::Vector3::new(sin * phi.cos(), sin * phi.sin(), cos));
+    (wi, henyey_greenstein(cos, g))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn bounds(size: f32) -> aabb::Aabb {
+        aabb::Aabb{ min: na::Point3::new(0.0, 0.0, 0.0), max: na::Point3::new(size, size, size) }
+    }
+
+    #[test]
+    fn ratio_tracking_matches_beers_law() {
+        // half density in the bottom half of the box and full in the top, so
+        // a ray along the bottom sees half the majorant the whole way
+        let grid = grid(1, 2, 1, |p| if p.y < 0.5 { 0.5 } else { 1.0 });
+        let m = volume(bounds(10.0), grid, 0.05, 0.1, 0.0);
+        let ray = crate::gfx::ray::new(na::Point3::new(-1.0, 1.0, 5.0), na::Vector3::x());
+        let expected = (-m.sigma_t() * 0.5 * 10.0).exp();
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+        let n = 100000;
+        let tr = (0..n).map(|_| m.transmittance(&ray, &mut rng)).sum::<f32>() / n as f32;
+        assert!((tr - expected).abs() < 0.01, "{} != {}", tr, expected);
+
+        let fog = fog(bounds(10.0), 0.05, 0.1, 0.0);
+        assert!((fog.transmittance(&ray, &mut rng) - (-fog.sigma_t() * 10.0).exp()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn henyey_greenstein_integrates_to_one() {
+        let n = 100000;
+        for &g in [-0.7, 0.0, 0.3, 0.9].iter() {
+            let dcos = 2.0 / n as f32;
+            let sum: f32 = (0..n).map(|i| henyey_greenstein(-1.0 + (i as f32 + 0.5) * dcos, g)).sum();
+            let total = sum * dcos * 2.0 * std::f32::consts::PI;
+            assert!((total - 1.0).abs() < 1e-3, "g = {}: {}", g, total);
+        }
+    }
+
+    #[test]
+    fn phase_samples_come_with_their_pdf() {
+        let wo = na::Vector3::new(0.3, -0.5, 0.8).normalize();
+        for &g in [-0.7, 0.0, 0.0005, 0.3, 0.9].iter() {
+            for i in 0..16 {
+                for j in 0..16 {
+                    let (u, v) = ((i as f32 + 0.5) / 16.0, (j as f32 + 0.5) / 16.0);
+                    let (wi, pdf) = sample_phase(&wo, g, u, v);
+                    assert!((wi.magnitude() - 1.0).abs() < 1e-4);
+                    let expected = henyey_greenstein(wo.dot(&wi), g);
+                    assert!((pdf - expected).abs() <= 1e-3 * expected, "g = {}: {} != {}", g, pdf, expected);
+                }
+            }
+        }
+    }
+}
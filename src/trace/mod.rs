@@ -0,0 +1,299 @@
+use nalgebra as na;
+
+pub mod aabb;
+pub mod bvh;
+pub mod accel;
+pub mod scene;
+pub mod sampling;
+pub mod bsdf;
+pub mod path;
+pub mod whitted;
+pub mod progressive;
+pub mod tiles;
+pub mod output;
+pub mod sampler;
+pub mod film;
+pub mod environment;
+pub mod aov;
+pub mod denoise;
+pub mod medium;
+pub mod spectrum;
+
+// CPU ray tracer; renders the same cubes and solids the GL path draws, without
+// needing a GL context.
+
+pub const BACKGROUND: crate::gfx::Color = [0.05, 0.05, 0.1];
+
+#[derive(Clone, Copy)]
+pub enum Mode {
+    // unlit vertex colors, matching the GL renderer
+    Flat,
+    Path,
+    // deterministic; hard shadows, mirrors and glass
+    Whitted,
+}
+
+impl Mode {
+    // cycles Flat, Path, Whitted, for switching the preview while it runs
+    pub fn next(&self) -> Mode {
+        match self {
+            Mode::Flat => Mode::Path,
+            Mode::Path => Mode::Whitted,
+            Mode::Whitted => Mode::Flat,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub mode: Mode,
+    // per pixel
+    pub samples: u32,
+    pub max_depth: u32,
+    // bounces before russian roulette may end a path
+    pub roulette_depth: u32,
+    pub sampler: sampler::Kind,
+    pub filter: film::Filter,
+    // in pixels
+    pub filter_radius: f32,
+    pub seed: u64,
+    // worker threads for tiled renders, 0 for one per core
+    pub threads: usize,
+    // path mode traces one wavelength per sample instead of RGB, see spectrum
+    pub spectral: bool,
+}
+
+pub fn settings() -> Settings {
+    Settings{
+        mode: Mode::Flat,
+        samples: 1,
+        max_depth: 8,
+        roulette_depth: 3,
+        sampler: sampler::Kind::Sobol,
+        // each sample only counts towards the pixel it landed in
+        filter: film::Filter::Box,
+        filter_radius: 0.5,
+        seed: 0,
+        threads: 0,
+        spectral: false,
+    }
+}
+
+// linear radiance; tone mapping only happens on the way to 8-bit images or
+// the screen
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: std::vec::Vec<crate::gfx::Color>,
+}
+
+pub fn new(width: usize, height: usize) -> Framebuffer {
+    Framebuffer{
+        width,
+        height,
+        pixels: vec![BACKGROUND; width * height],
+    }
+}
+
+impl Framebuffer {
+    pub fn get(&self, x: usize, y: usize) -> crate::gfx::Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: crate::gfx::Color) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    // packed 8-bit rgb, row major from the top left corner, tone mapped and
+    // display encoded
+    pub fn to_rgb8(&self, tonemap: &crate::gfx::tonemap::Tonemap) -> std::vec::Vec<u8> {
+        let mut v: std::vec::Vec<u8> = std::vec::Vec::with_capacity(self.pixels.len() * 3);
+        for &c in self.pixels.iter() {
+            for &channel in tonemap.apply(c).iter() {
+                v.push((channel * 255.0 + 0.5) as u8);
+            }
+        }
+        v
+    }
+}
+
+pub fn rgb(c: crate::gfx::Color) -> na::Vector3<f32> {
+    na::Vector3::new(c[0], c[1], c[2])
+}
+
+// origin for a ray leaving a surface at p, nudged off to the side dir points to;
+// scaled by distance from the world origin to stay above float error
+pub fn spawn(p: &na::Point3<f32>, n: &na::Vector3<f32>, dir: &na::Vector3<f32>) -> na::Point3<f32> {
+    let eps = 1e-4 * (1.0 + p.coords.amax());
+    if n.dot(dir) > 0.0 { p + n * eps } else { p - n * eps }
+}
+
+// mirrors fragment.glsl so both backends produce the same picture
+fn shade(position: &na::Point3<f32>, color: crate::gfx::Color) -> crate::gfx::Color {
+    let m = 50.0;
+    let distance = position.coords.magnitude();
+    let d = if distance < m { distance / m } else { 1.0 };
+    let cubic = 1.0 - (d / 1.3) * (d / 1.3);
+
+    let mut c = [0.0; 3];
+    for i in 0..3 {
+        c[i] = (color[i] * cubic * 0.6) + (color[i] * 0.4);
+    }
+    c
+}
+
+fn flat(ray: &crate::gfx::ray::Ray, scene: &scene::Scene) -> crate::gfx::Color {
+    match scene.accel.closest_hit(ray) {
+        Some((instance, hit)) => shade(&ray.at(hit.t), scene.color(instance, &hit)),
+        None => match &scene.environment {
+            Some(e) => e.environment.radiance(&ray.dir.normalize()),
+            None => BACKGROUND,
+        },
+    }
+}
+
+pub fn render(camera: &crate::gfx::camera::Camera, registry: &crate::gfx::geometry::Registry, cubes: &[crate::shapes::cube::Cube], solids: &[crate::shapes::solid::Solid], settings: &Settings, width: usize, height: usize) -> Result<Framebuffer, std::string::String> {
+    Ok(render_scene(camera, &scene::new(registry, cubes, solids)?, settings, width, height))
+}
+
+pub fn render_scene(camera: &crate::gfx::camera::Camera, scene: &scene::Scene, settings: &Settings, width: usize, height: usize) -> Framebuffer {
+    // nothing else holds the token, so this can't be cancelled
+    render_tiles(camera, scene, settings, width, height, &tiles::cancel(), |_| {}).unwrap()
+}
+
+// render_scene on every core, reporting each finished tile; None if cancelled
+pub fn render_tiles<P>(camera: &crate::gfx::camera::Camera, scene: &scene::Scene, settings: &Settings, width: usize, height: usize, cancel: &tiles::Cancel, progress: P) -> Option<Framebuffer>
+where
+    P: Fn(&tiles::Progress) + Sync,
+{
+    let film = render_film(camera, scene, settings, width, height, 0, settings.samples.max(1), cancel, progress)?;
+    Some(film.framebuffer())
+}
+
+// samples first..first + count of every pixel, filtered into a film
+pub fn render_film<P>(camera: &crate::gfx::camera::Camera, scene: &scene::Scene, settings: &Settings, width: usize, height: usize, first: u32, count: u32, cancel: &tiles::Cancel, progress: P) -> Option<film::Film>
+where
+    P: Fn(&tiles::Progress) + Sync,
+{
+    let camera = &focus(camera, scene);
+    let mut film = film::new(width, height, settings.filter, settings.filter_radius);
+
+    let mut scheduler = tiles::new();
+    if settings.threads > 0 {
+        scheduler.threads = settings.threads;
+    }
+
+    let tiles = scheduler.run(width, height, cancel, progress, |tile| {
+        let mut f = film.tile(tile);
+        let mut sampler = sampler::new(settings.sampler, settings.seed, settings.samples);
+
+        for y in tile.y..tile.y + tile.height {
+            if cancel.is_cancelled() {
+                break;
+            }
+            for x in tile.x..tile.x + tile.width {
+                for i in first..first + count {
+                    let (fx, fy, l) = sample(camera, scene, settings, sampler.as_mut(), x, y, width, height, i);
+                    f.add(fx, fy, &l);
+                }
+            }
+        }
+        f
+    })?;
+
+    // tiles overlap where their samples spill over, and are always added in the
+    // same order so the float sums come out the same
+    for t in tiles.iter() {
+        film.merge(t);
+    }
+    Some(film)
+}
+
+// the camera, focused on whatever is under the screen center if it auto
+// focuses; the focus is left alone when the center ray misses everything
+pub fn focus(camera: &crate::gfx::camera::Camera, scene: &scene::Scene) -> crate::gfx::camera::Camera {
+    let mut camera = *camera;
+    if camera.auto_focus {
+        let ray = camera.ray(0.0, 0.0);
+        if let Some((_, hit)) = scene.accel.closest_hit(&ray) {
+            let (_, _, z,) = camera.phys.direction();
+            camera.focus_distance = hit.t * ray.dir.dot(&z);
+        }
+    }
+    camera
+}
+
+// sample `index` of pixel (x, y): where it landed on the film, and the radiance
+// it brought back. The sampler is restarted from the seed, pixel and index, so
+// a render is reproducible and progressive passes (see progressive::Progressive)
+// continue the sequence instead of repeating it
+pub fn sample(camera: &crate::gfx::camera::Camera, scene: &scene::Scene, settings: &Settings, sampler: &mut dyn sampler::Sampler, x: usize, y: usize, width: usize, height: usize, index: u32) -> (f32, f32, na::Vector3<f32>) {
+    let (fx, fy, ray) = camera_ray(camera, settings, sampler, x, y, width, height, index);
+
+    let l = match settings.mode {
+        Mode::Flat => rgb(flat(&ray, scene)),
+        Mode::Path if settings.spectral => {
+            let (wavelength, pdf) = spectrum::sample_wavelength(sampler.next_1d());
+            spectrum::to_rgb(path::radiance(scene, &ray, settings, Some(wavelength), sampler).x, wavelength, pdf)
+        },
+        Mode::Path => path::radiance(scene, &ray, settings, None, sampler),
+        Mode::Whitted => whitted::radiance(scene, &ray, settings.max_depth),
+    };
+    (fx, fy, l)
+}
+
+// the ray sample `index` of pixel (x, y) starts with, and where it landed on
+// the film; leaves the sampler ready for the rest of the path
+pub fn camera_ray(camera: &crate::gfx::camera::Camera, settings: &Settings, sampler: &mut dyn sampler::Sampler, x: usize, y: usize, width: usize, height: usize, index: u32) -> (f32, f32, crate::gfx::ray::Ray) {
+    sampler.start(x, y, index);
+
+    // jittered within the pixel, every sample including the first
+    let (jx, jy) = sampler.next_2d();
+    let (fx, fy) = (x as f32 + jx, y as f32 + jy);
+
+    let (lu, lv) = sampler.next_2d();
+    let time = camera.shutter_open + (camera.shutter_close - camera.shutter_open) * sampler.next_1d();
+
+    // normalized device coordinates, +y is up
+    let s = (fx / width as f32) * 2.0 - 1.0;
+    let t = 1.0 - (fy / height as f32) * 2.0;
+    let mut ray = camera.at(time).lens_ray(s, t, lu, lv);
+    ray.time = time;
+    (fx, fy, ray)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_same_on_any_number_of_threads() {
+        let mut registry = crate::gfx::geometry::new();
+        let cubes: std::vec::Vec<crate::shapes::cube::Cube> = (0..12).map(|i| {
+            let material = crate::gfx::material::Material::Lambertian{ albedo: crate::gfx::material::Albedo::Solid([0.8, 0.3 + 0.05 * i as f32, 0.2]) };
+            let x = (i % 4) as f32 * 12.0 - 18.0;
+            let y = (i / 4) as f32 * 12.0 - 12.0;
+            crate::shapes::cube::new(&mut registry, i % 3, x, y, 0.0, 8.0, 8.0, 8.0, material)
+        }).collect();
+        let mut scene = scene::new(&registry, &cubes, &[]).unwrap();
+        scene.lights = vec![crate::gfx::light::directional(na::Vector3::new(-0.3, -1.0, 0.2), [1.0, 1.0, 1.0], 2.0)];
+
+        let (width, height) = (80, 56);
+        let camera = crate::gfx::camera::new(0.0, 0.0, -80.0, width as f32 / height as f32, std::f32::consts::FRAC_PI_4);
+        let mut settings = settings();
+        settings.mode = Mode::Path;
+        settings.samples = 4;
+        settings.filter = film::Filter::Gaussian;
+        settings.filter_radius = film::default_radius(settings.filter);
+
+        settings.threads = 1;
+        let one = render_scene(&camera, &scene, &settings, width, height);
+        settings.threads = 4;
+        let many = render_scene(&camera, &scene, &settings, width, height);
+
+        assert!(one.pixels.iter().any(|p| p != &BACKGROUND));
+        for (a, b) in one.pixels.iter().zip(many.pixels.iter()) {
+            assert_eq!([a[0].to_bits(), a[1].to_bits(), a[2].to_bits()], [b[0].to_bits(), b[1].to_bits(), b[2].to_bits()]);
+        }
+    }
+}
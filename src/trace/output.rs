@@ -0,0 +1,268 @@
+use std::io::Write;
+
+// Image files for a rendered framebuffer. PPM and PNG are 8-bit, tone mapped
+// and encoded for display; PFM and EXR keep the raw linear floats, for
+// anything brighter than white. EXR can also carry the aovs as extra layers.
+
+fn create(path: &str) -> Result<std::io::BufWriter<std::fs::File>, std::string::String> {
+    let f = std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(std::io::BufWriter::new(f))
+}
+
+// binary (P6) portable pixmap
+pub fn write_ppm(fb: &crate::trace::Framebuffer, path: &str, tonemap: &crate::gfx::tonemap::Tonemap) -> Result<(), std::string::String> {
+    let mut w = create(path)?;
+    write!(w, "P6\n{} {}\n255\n", fb.width, fb.height).map_err(|e| e.to_string())?;
+    w.write_all(&fb.to_rgb8(tonemap)).map_err(|e| e.to_string())?;
+    w.flush().map_err(|e| e.to_string())
+}
+
+pub fn write_png(fb: &crate::trace::Framebuffer, path: &str, tonemap: &crate::gfx::tonemap::Tonemap) -> Result<(), std::string::String> {
+    image::save_buffer(path, &fb.to_rgb8(tonemap), fb.width as u32, fb.height as u32, image::ColorType::Rgb8)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+// portable float map; a negative scale marks little endian, and rows run from
+// the bottom up
+pub fn write_pfm(fb: &crate::trace::Framebuffer, path: &str) -> Result<(), std::string::String> {
+    let mut w = create(path)?;
+    write!(w, "PF\n{} {}\n-1.0\n", fb.width, fb.height).map_err(|e| e.to_string())?;
+
+    for y in (0..fb.height).rev() {
+        for x in 0..fb.width {
+            for channel in fb.get(x, y).iter() {
+                w.write_all(&channel.to_le_bytes()).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    w.flush().map_err(|e| e.to_string())
+}
+
+// OpenEXR, uncompressed 32-bit float scanlines: the color as R, G and B, and
+// every layer's channels under its name, ie depth.Z or normal.X
+pub fn write_exr(fb: &crate::trace::Framebuffer, layers: &[crate::trace::aov::Layer], path: &str) -> Result<(), std::string::String> {
+    // (name, layer or None for the color, channel within it), sorted by name
+    // as the format wants
+    let mut channels: std::vec::Vec<(std::string::String, Option<&crate::trace::aov::Layer>, usize)> = vec![];
+    for (k, name) in ["R", "G", "B"].iter().enumerate() {
+        channels.push((name.to_string(), None, k));
+    }
+    for l in layers.iter() {
+        for (k, name) in l.kind.channels().iter().enumerate() {
+            channels.push((format!("{}.{}", l.kind.name(), name), Some(l), k));
+        }
+    }
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header: std::vec::Vec<u8> = vec![];
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+
+    let mut list: std::vec::Vec<u8> = vec![];
+    for (name, _, _) in channels.iter() {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        // float pixels, not perceptually linear, three reserved bytes, no subsampling
+        list.extend_from_slice(&2i32.to_le_bytes());
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+
+    let mut window: std::vec::Vec<u8> = vec![];
+    for v in [0, 0, fb.width as i32 - 1, fb.height as i32 - 1].iter() {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute("channels", "chlist", &list);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let mut w = create(path)?;
+    // magic number, then version 2 of the single part scanline format
+    w.write_all(&20000630i32.to_le_bytes()).map_err(|e| e.to_string())?;
+    w.write_all(&2i32.to_le_bytes()).map_err(|e| e.to_string())?;
+    w.write_all(&header).map_err(|e| e.to_string())?;
+
+    // a table of where each scanline starts, then the scanlines: y, byte
+    // count, and each channel's row in turn
+    let line = channels.len() * fb.width * 4;
+    let start = 8 + header.len() + fb.height * 8;
+    for y in 0..fb.height {
+        let offset = (start + y * (8 + line)) as u64;
+        w.write_all(&offset.to_le_bytes()).map_err(|e| e.to_string())?;
+    }
+
+    for y in 0..fb.height {
+        w.write_all(&(y as i32).to_le_bytes()).map_err(|e| e.to_string())?;
+        w.write_all(&(line as i32).to_le_bytes()).map_err(|e| e.to_string())?;
+        for (_, layer, k) in channels.iter() {
+            for x in 0..fb.width {
+                let v = match layer {
+                    Some(l) => l.get(x, y)[*k],
+                    None => fb.get(x, y)[*k],
+                };
+                w.write_all(&v.to_le_bytes()).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    w.flush().map_err(|e| e.to_string())
+}
+
+// an aov on its own: the raw values for .pfm and .exr, a preview (see
+// aov::Layer::preview) for the 8-bit formats
+pub fn write_layer(layer: &crate::trace::aov::Layer, path: &str) -> Result<(), std::string::String> {
+    if path.to_lowercase().ends_with(".pfm") || path.to_lowercase().ends_with(".exr") {
+        return write(&layer.framebuffer(), path, &crate::gfx::tonemap::new());
+    }
+
+    // albedo is a color, the rest are already display values
+    let mut tonemap = crate::gfx::tonemap::new();
+    if layer.kind != crate::trace::aov::Kind::Albedo {
+        tonemap.gamma = Some(1.0);
+    }
+    write(&layer.preview(), path, &tonemap)
+}
+
+// where write_all puts a layer next to the color: image.png gets image.depth.png
+pub fn layer_path(path: &str, kind: crate::trace::aov::Kind) -> std::string::String {
+    let p = std::path::Path::new(path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match p.extension().and_then(|e| e.to_str()) {
+        Some(e) => format!("{}.{}.{}", stem, kind.name(), e),
+        None => format!("{}.{}", stem, kind.name()),
+    };
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// the color and its aovs: all in the one file for .exr, otherwise each layer
+// in its own file (see layer_path). Returns the files written
+pub fn write_all(fb: &crate::trace::Framebuffer, layers: &[crate::trace::aov::Layer], path: &str, tonemap: &crate::gfx::tonemap::Tonemap) -> Result<std::vec::Vec<std::string::String>, std::string::String> {
+    if path.to_lowercase().ends_with(".exr") {
+        write_exr(fb, layers, path)?;
+        return Ok(vec![path.to_string()]);
+    }
+
+    write(fb, path, tonemap)?;
+    let mut written = vec![path.to_string()];
+    for l in layers.iter() {
+        let p = layer_path(path, l.kind);
+        write_layer(l, &p)?;
+        written.push(p);
+    }
+    Ok(written)
+}
+
+// picks the format from the file extension
+pub fn write(fb: &crate::trace::Framebuffer, path: &str, tonemap: &crate::gfx::tonemap::Tonemap) -> Result<(), std::string::String> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_deref() {
+        Some("ppm") => write_ppm(fb, path, tonemap),
+        Some("png") => write_png(fb, path, tonemap),
+        Some("pfm") => write_pfm(fb, path),
+        Some("exr") => write_exr(fb, &[], path),
+        _ => Err(format!("{}: unknown image format, expected .ppm, .png, .pfm or .exr", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn i32_at(bytes: &[u8], at: usize) -> i32 {
+        let mut b = [0; 4];
+        b.copy_from_slice(&bytes[at..at + 4]);
+        i32::from_le_bytes(b)
+    }
+
+    fn f32_at(bytes: &[u8], at: usize) -> f32 {
+        f32::from_bits(i32_at(bytes, at) as u32)
+    }
+
+    fn string_at(bytes: &[u8], at: usize) -> (std::string::String, usize) {
+        let end = at + bytes[at..].iter().position(|&b| b == 0).unwrap();
+        (std::string::String::from_utf8(bytes[at..end].to_vec()).unwrap(), end + 1)
+    }
+
+    #[test]
+    fn exr_header_and_scanlines() {
+        let mut fb = crate::trace::new(2, 2);
+        let mut depth = crate::trace::aov::layer(crate::trace::aov::Kind::Depth, 2, 2);
+        for y in 0..2 {
+            for x in 0..2 {
+                fb.set(x, y, [x as f32, y as f32, 0.5]);
+                depth.set(x, y, &[10.0 * (y * 2 + x) as f32]);
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("raytrace-test-{}.exr", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        write_exr(&fb, &[depth], &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(i32_at(&bytes, 0), 20000630);
+        assert_eq!(i32_at(&bytes, 4), 2);
+
+        // attributes up to the empty name that ends the header
+        let mut at = 8;
+        let mut channels = vec![];
+        loop {
+            let (name, next) = string_at(&bytes, at);
+            if name.is_empty() {
+                at = next;
+                break;
+            }
+            let (_, next) = string_at(&bytes, next);
+            let size = i32_at(&bytes, next) as usize;
+            let value = next + 4;
+            if name == "channels" {
+                let mut c = value;
+                while bytes[c] != 0 {
+                    let (channel, next) = string_at(&bytes, c);
+                    // float pixels
+                    assert_eq!(i32_at(&bytes, next), 2);
+                    channels.push(channel);
+                    c = next + 16;
+                }
+            }
+            at = value + size;
+        }
+        assert_eq!(channels, vec!["B", "G", "R", "depth.Z"]);
+
+        // each offset points at its scanline, which starts with its y and then
+        // holds each channel's row in the order of the list
+        for y in 0..2 {
+            let offset = i32_at(&bytes, at + y * 8) as usize;
+            assert_eq!(i32_at(&bytes, at + y * 8 + 4), 0);
+            assert_eq!(i32_at(&bytes, offset), y as i32);
+            assert_eq!(i32_at(&bytes, offset + 4), 4 * 2 * 4);
+
+            let row = |channel: usize| (0..2).map(|x| f32_at(&bytes, offset + 8 + (channel * 2 + x) * 4)).collect::<std::vec::Vec<f32>>();
+            assert_eq!(row(0), vec![0.5, 0.5]);
+            assert_eq!(row(1), vec![y as f32, y as f32]);
+            assert_eq!(row(2), vec![0.0, 1.0]);
+            assert_eq!(row(3), vec![20.0 * y as f32, 20.0 * y as f32 + 10.0]);
+        }
+        assert_eq!(bytes.len(), i32_at(&bytes, at + 8) as usize + 8 + 4 * 2 * 4);
+    }
+}
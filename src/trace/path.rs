@@ -0,0 +1,307 @@
+use nalgebra as na;
+use rand::{RngCore, SeedableRng};
+use crate::trace::bsdf;
+use crate::trace::medium;
+use crate::trace::sampler::Sampler;
+use crate::trace::sampling;
+use crate::trace::scene;
+use crate::trace::spectrum;
+
+// Unidirectional path tracer. At every bounce one light is sampled explicitly
+// (next event estimation) and the bsdf is sampled to continue the path; both
+// strategies can find the same emitter, so each is weighted with the power
+// heuristic (multiple importance sampling). Inside fog or smoke, paths can also
+// scatter between surfaces, where the phase function stands in for the bsdf.
+// Given a wavelength, every color is taken at it, so the radiance comes back
+// the same in all three channels (see spectrum).
+
+fn sky_on(scene: &scene::Scene) -> bool {
+    scene.environment.is_some() || scene.sky.iter().any(|&c| c > 0.0)
+}
+
+// every light is picked with equal probability: emissive instances first, then
+// scene lights, then the sky (or environment)
+fn light_count(scene: &scene::Scene) -> usize {
+    scene.emitters.len() + scene.lights.len() + if sky_on(scene) { 1 } else { 0 }
+}
+
+// solid angle density of sampling a point on the emitter as seen from `from`
+fn emitter_pdf(scene: &scene::Scene, e: &scene::Emitter, from: &na::Point3<f32>, p: &na::Point3<f32>, n: &na::Vector3<f32>) -> f32 {
+    let d = p - from;
+    let dist2 = d.magnitude_squared();
+    let cos = n.dot(&d).abs() / dist2.sqrt();
+    if cos <= 0.0 || e.area() <= 0.0 {
+        return 0.0;
+    }
+    dist2 / (cos * e.area() * light_count(scene) as f32)
+}
+
+// environments are importance sampled, a constant sky uniformly
+fn sky_pdf(scene: &scene::Scene, dir: &na::Vector3<f32>) -> f32 {
+    let pdf = match &scene.environment {
+        Some(e) => e.pdf(dir),
+        None => sampling::UNIFORM_SPHERE_PDF,
+    };
+    pdf / light_count(scene) as f32
+}
+
+// where a path scatters: off a surface, or inside a medium
+enum Vertex<'a> {
+    // wo is in the frame around the shading normal n
+    Surface{ n: na::Vector3<f32>, frame: &'a sampling::Frame, bsdf: &'a bsdf::Bsdf, wo: na::Vector3<f32> },
+    // wo is in world space
+    Medium{ wo: na::Vector3<f32>, g: f32 },
+}
+
+impl<'a> Vertex<'a> {
+    // normal to spawn rays along; zero in a medium
+    fn normal(&self) -> na::Vector3<f32> {
+        match self {
+            Vertex::Surface{ n, .. } => *n,
+            Vertex::Medium{ .. } => na::Vector3::zeros(),
+        }
+    }
+
+    // how much light arriving from wi (in world space) is scattered towards wo,
+    // including the cosine at surfaces, and the density of sampling wi
+    fn eval(&self, wi: &na::Vector3<f32>) -> (na::Vector3<f32>, f32) {
+        match self {
+            Vertex::Surface{ frame, bsdf, wo, .. } => {
+                let wi_local = frame.to_local(wi);
+                (bsdf.eval(wo, &wi_local) * wi_local.z.abs(), bsdf.pdf(wo, &wi_local))
+            },
+            Vertex::Medium{ wo, g } => {
+                let phase = medium::henyey_greenstein(wo.dot(wi), *g);
+                (na::Vector3::new(phase, phase, phase), phase)
+            },
+        }
+    }
+}
+
+// Random numbers for the walk through media, seeded from the bounce's number
+// the first time one is drawn. Without media nothing draws from it, so paths
+// skip seeding it every bounce
+struct Walk {
+    seed: f32,
+    rng: Option<rand::rngs::SmallRng>,
+}
+
+impl Walk {
+    fn rng(&mut self) -> &mut rand::rngs::SmallRng {
+        let seed = self.seed;
+        self.rng.get_or_insert_with(|| rand::rngs::SmallRng::seed_from_u64(crate::trace::sampler::hash((seed as f64 * 4294967296.0) as u64)))
+    }
+}
+
+impl RngCore for Walk {
+    fn next_u32(&mut self) -> u32 { self.rng().next_u32() }
+    fn next_u64(&mut self) -> u64 { self.rng().next_u64() }
+    fn fill_bytes(&mut self, dest: &mut [u8]) { self.rng().fill_bytes(dest) }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> { self.rng().try_fill_bytes(dest) }
+}
+
+// russian roulette once the path is deep enough; paths carrying little
+// throughput are ended early, and the survivors are boosted to keep the
+// estimate unbiased. False if the path ends
+fn roulette(beta: &mut na::Vector3<f32>, depth: u32, settings: &crate::trace::Settings, survive: f32) -> bool {
+    if depth < settings.roulette_depth {
+        return true;
+    }
+    let q = (1.0 - beta.max()).max(0.05);
+    if survive < q {
+        return false;
+    }
+    *beta /= 1.0 - q;
+    true
+}
+
+// next event estimation from p towards one randomly chosen light, at the given
+// time and wavelength. The first random number chooses the light, the others
+// the point on it; rng tracks the shadow ray through any media
+fn sample_light<R: rand::Rng>(scene: &scene::Scene, p: &na::Point3<f32>, vertex: &Vertex, time: f32, wavelength: Option<f32>, random: [f32; 4], rng: &mut R) -> na::Vector3<f32> {
+    let count = light_count(scene);
+    if count == 0 {
+        return na::Vector3::zeros();
+    }
+
+    let [pick, u, v, w] = random;
+    let pick = ((pick * count as f32) as usize).min(count - 1);
+    let emitters = scene.emitters.len();
+
+    // (direction, distance, radiance, pdf including the pick, delta)
+    let (wi, dist, radiance, pdf, delta) = if pick < emitters {
+        let e = &scene.emitters[pick];
+        let (q, nq) = scene.sample_emitter(e, u, v, w, time);
+        let d = q - p;
+        let dist = d.magnitude();
+        (d / dist, dist, crate::trace::rgb(e.radiance), emitter_pdf(scene, e, p, &q, &nq), false)
+    } else if pick < emitters + scene.lights.len() {
+        match scene.lights[pick - emitters].sample(p, u, v) {
+            Some(i) => (i.wi, i.dist, i.radiance, i.pdf / count as f32, i.delta),
+            None => return na::Vector3::zeros(),
+        }
+    } else {
+        let (wi, radiance) = match &scene.environment {
+            Some(e) => {
+                let (wi, radiance, _) = e.sample(u, v);
+                (wi, radiance)
+            },
+            None => (sampling::uniform_sphere(u, v), crate::trace::rgb(scene.sky)),
+        };
+        (wi, std::f32::INFINITY, radiance, sky_pdf(scene, &wi), false)
+    };
+
+    if pdf <= 0.0 {
+        return na::Vector3::zeros();
+    }
+
+    let (f, scatter_pdf) = vertex.eval(&wi);
+    if f == na::Vector3::zeros() {
+        return na::Vector3::zeros();
+    }
+    let tr = scene.transmittance(p, &vertex.normal(), &wi, dist, time, rng);
+    if tr <= 0.0 {
+        return na::Vector3::zeros();
+    }
+
+    // a delta light can't be found by sampling the bsdf, so it gets all the weight
+    let weight = if delta { 1.0 } else { sampling::power_heuristic(pdf, scatter_pdf) };
+    f.component_mul(&spectrum::lift(&radiance, wavelength)) * (tr * weight / pdf)
+}
+
+// every bounce takes the same eight dimensions from the sampler, used or not:
+// light choice, point on the light (3), bsdf or phase direction (2), russian
+// roulette, and a seed for the random walk through any media, which takes as
+// many numbers as it needs
+pub fn radiance(scene: &scene::Scene, ray: &crate::gfx::ray::Ray, settings: &crate::trace::Settings, wavelength: Option<f32>, sampler: &mut dyn Sampler) -> na::Vector3<f32> {
+    let mut l = na::Vector3::zeros();
+    let mut beta = na::Vector3::new(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+
+    // the previous bounce's bsdf pdf, and whether it was a delta lobe (or the
+    // camera), in which case emitters hit by it are counted in full
+    let mut bsdf_pdf = 0.0;
+    let mut specular = true;
+    let mut prev = ray.origin;
+
+    let mut depth = 0;
+    loop {
+        let closest = scene.accel.closest_hit(&ray);
+
+        let pick = sampler.next_1d();
+        let (lu, lv) = sampler.next_2d();
+        let lw = sampler.next_1d();
+        let (bu, bv) = sampler.next_2d();
+        let survive = sampler.next_1d();
+        let mut rng = Walk{ seed: sampler.next_1d(), rng: None };
+
+        let mut to_surface = ray;
+        to_surface.t_max = closest.as_ref().map(|(_, h)| h.t).unwrap_or(std::f32::INFINITY);
+        let light = scene.hit_light(&to_surface);
+
+        // a medium may scatter the path before it gets to the surface or light
+        let mut to_medium = to_surface;
+        if let Some((_, t)) = light {
+            to_medium.t_max = t;
+        }
+        if let Some(c) = medium::sample(&scene.media, &to_medium, &mut rng) {
+            if depth >= settings.max_depth {
+                break;
+            }
+
+            // the chance of absorbing is the same in every channel, so it's
+            // folded into the throughput instead of ending the path
+            let m = &scene.media[c.medium];
+            beta *= m.albedo();
+
+            let p = ray.at(c.t);
+            let wo = -ray.dir.normalize();
+            let vertex = Vertex::Medium{ wo, g: m.g };
+            l += beta.component_mul(&sample_light(scene, &p, &vertex, ray.time, wavelength, [pick, lu, lv, lw], &mut rng));
+
+            // sampled exactly, so the phase function and pdf cancel
+            let (wi, pdf) = medium::sample_phase(&wo, m.g, bu, bv);
+            bsdf_pdf = pdf;
+            specular = false;
+            prev = p;
+            ray = ray.secondary(p, wi);
+
+            depth += 1;
+            if !roulette(&mut beta, depth, settings, survive) {
+                break;
+            }
+            continue;
+        }
+
+        // area lights in front of whatever surface was hit end the path
+        if let Some((i, t)) = light {
+            let light = &scene.lights[i];
+            let weight = if specular {
+                1.0
+            } else {
+                sampling::power_heuristic(bsdf_pdf, light.pdf(&prev, &ray.dir, t) / light_count(scene) as f32)
+            };
+            l += beta.component_mul(&spectrum::lift(&light.power(), wavelength)) * weight;
+            break;
+        }
+
+        let (instance, hit) = match closest {
+            Some(h) => h,
+            None => {
+                if sky_on(scene) {
+                    let dir = ray.dir.normalize();
+                    let weight = if specular { 1.0 } else { sampling::power_heuristic(bsdf_pdf, sky_pdf(scene, &dir)) };
+                    l += beta.component_mul(&spectrum::lift(&scene.background(&dir), wavelength)) * weight;
+                }
+                break;
+            },
+        };
+
+        let p = ray.at(hit.t);
+
+        if let Some(e) = scene.emitter(instance) {
+            let weight = if specular {
+                1.0
+            } else {
+                sampling::power_heuristic(bsdf_pdf, emitter_pdf(scene, e, &prev, &p, &hit.normal))
+            };
+            l += beta.component_mul(&spectrum::lift(&crate::trace::rgb(e.radiance), wavelength)) * weight;
+        }
+
+        if depth >= settings.max_depth {
+            break;
+        }
+
+        // shade with the normal facing the incoming ray, so both sides of a triangle work
+        let entering = hit.normal.dot(&ray.dir) < 0.0;
+        let n = if entering { hit.normal } else { -hit.normal };
+        let frame = sampling::frame(&n);
+        let wo = frame.to_local(&-ray.dir.normalize());
+        let bsdf = scene.bsdf(instance, &hit, entering, wavelength);
+
+        if !bsdf.is_specular() {
+            let vertex = Vertex::Surface{ n, frame: &frame, bsdf: &bsdf, wo };
+            l += beta.component_mul(&sample_light(scene, &p, &vertex, ray.time, wavelength, [pick, lu, lv, lw], &mut rng));
+        }
+
+        let s = match bsdf.sample(&wo, bu, bv) {
+            Some(s) => s,
+            None => break,
+        };
+
+        beta = beta.component_mul(&s.f) * (s.wi.z.abs() / s.pdf);
+        bsdf_pdf = s.pdf;
+        specular = s.specular;
+        prev = p;
+
+        let wi = frame.to_world(&s.wi);
+        ray = ray.secondary(crate::trace::spawn(&p, &n, &wi), wi);
+
+        depth += 1;
+        if !roulette(&mut beta, depth, settings, survive) {
+            break;
+        }
+    }
+
+    l
+}
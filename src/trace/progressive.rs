@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Running average for the interactive preview: each pass adds one more sample
+// to every pixel, and reset starts over once the view has changed. Passes can
+// run in the background (start and poll) so the window stays responsive, and
+// are cancelled by reset. The first pass after a reset also renders the
+// denoiser's guides for the view.
+pub struct Progressive {
+    pub width: usize,
+    pub height: usize,
+    // samples taken so far, per pixel
+    pub passes: u32,
+    film: crate::trace::film::Film,
+    // see denoise::GUIDES
+    guides: std::vec::Vec<crate::trace::aov::Layer>,
+    // tiles finished in the running pass
+    done: std::sync::Arc<AtomicUsize>,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    cancel: crate::trace::tiles::Cancel,
+    handle: std::thread::JoinHandle<Option<(crate::trace::film::Film, std::vec::Vec<crate::trace::aov::Layer>)>>,
+}
+
+pub fn new(width: usize, height: usize, settings: &crate::trace::Settings) -> Progressive {
+    Progressive{
+        width,
+        height,
+        passes: 0,
+        film: crate::trace::film::new(width, height, settings.filter, settings.filter_radius),
+        guides: vec![],
+        done: std::sync::Arc::new(AtomicUsize::new(0)),
+        pending: None,
+    }
+}
+
+// one sample per pixel, numbered `first` so every pass gets different jitter,
+// and the guides if it's the first
+fn pass(camera: &crate::gfx::camera::Camera, scene: &crate::trace::scene::Scene, settings: &crate::trace::Settings, width: usize, height: usize, first: u32, cancel: &crate::trace::tiles::Cancel, done: &AtomicUsize) -> Option<(crate::trace::film::Film, std::vec::Vec<crate::trace::aov::Layer>)> {
+    let film = crate::trace::render_film(camera, scene, settings, width, height, first, 1, cancel, |p| done.store(p.done, Ordering::Relaxed))?;
+    let kinds: &[crate::trace::aov::Kind] = if first == 0 { &crate::trace::denoise::GUIDES } else { &[] };
+    let guides = crate::trace::aov::render(camera, scene, settings, width, height, kinds, crate::trace::aov::Id::Group, cancel)?;
+    Some((film, guides))
+}
+
+impl Progressive {
+    pub fn reset(&mut self) {
+        self.cancel();
+        self.passes = 0;
+        self.film.clear();
+        self.guides.clear();
+    }
+
+    // stops the running pass, if any, and waits for its threads
+    pub fn cancel(&mut self) {
+        if let Some(p) = self.pending.take() {
+            p.cancel.cancel();
+            let _ = p.handle.join();
+        }
+        self.done.store(0, Ordering::Relaxed);
+    }
+
+    pub fn busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // fraction of the running pass that's done
+    pub fn progress(&self) -> f32 {
+        let total = crate::trace::tiles::tiles(self.width, self.height, crate::trace::tiles::TILE_SIZE).len();
+        self.done.load(Ordering::Relaxed) as f32 / total.max(1) as f32
+    }
+
+    // renders a pass on the calling thread
+    pub fn step(&mut self, camera: &crate::gfx::camera::Camera, scene: &crate::trace::scene::Scene, settings: &crate::trace::Settings) {
+        self.cancel();
+        if let Some((film, guides)) = pass(camera, scene, settings, self.width, self.height, self.passes, &crate::trace::tiles::cancel(), &self.done) {
+            self.add(&film, guides);
+        }
+    }
+
+    // renders the next pass in the background, unless one is already running
+    pub fn start(&mut self, camera: crate::gfx::camera::Camera, scene: std::sync::Arc<crate::trace::scene::Scene>, settings: crate::trace::Settings) {
+        if self.pending.is_some() {
+            return;
+        }
+
+        let cancel = crate::trace::tiles::cancel();
+        let (width, height, first) = (self.width, self.height, self.passes);
+        let done = self.done.clone();
+        done.store(0, Ordering::Relaxed);
+
+        let c = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            pass(&camera, &scene, &settings, width, height, first, &c, &done)
+        });
+
+        self.pending = Some(Pending{ cancel, handle });
+    }
+
+    // adds the background pass in if it has finished; returns whether it had
+    pub fn poll(&mut self) -> bool {
+        match &self.pending {
+            Some(p) if p.handle.is_finished() => {},
+            _ => return false,
+        }
+
+        let p = self.pending.take().unwrap();
+        match p.handle.join() {
+            Ok(Some((film, guides))) => {
+                self.add(&film, guides);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn add(&mut self, film: &crate::trace::film::Film, guides: std::vec::Vec<crate::trace::aov::Layer>) {
+        self.film.merge(film);
+        self.passes += 1;
+        if !guides.is_empty() {
+            self.guides = guides;
+        }
+    }
+
+    // the average so far; black until the first pass is in
+    pub fn framebuffer(&self) -> crate::trace::Framebuffer {
+        self.film.framebuffer()
+    }
+
+    // the average so far, denoised once the guides are in
+    pub fn denoised(&self, settings: &crate::trace::denoise::Settings) -> crate::trace::Framebuffer {
+        let fb = self.film.framebuffer();
+        crate::trace::denoise::denoise(&fb, &self.guides, settings).unwrap_or(fb)
+    }
+}
@@ -0,0 +1,411 @@
+use rand::Rng;
+use rand::SeedableRng;
+
+// Where the tracer's random numbers come from. A sampler is restarted for every
+// camera sample, and then hands out one dimension after another: the pixel
+// jitter, the point on the lens and the time first, then the wavelength in
+// spectral mode, then a fixed number per bounce (see path::radiance), so the same dimension always drives the same
+// decision and low discrepancy sequences stay well distributed in it. Values
+// depend only on the seed, the pixel and the sample index, never on what ran
+// before, so renders are reproducible on any number of threads.
+pub trait Sampler {
+    // begins sample `index` of pixel (x, y), back at dimension 0
+    fn start(&mut self, x: usize, y: usize, index: u32);
+
+    // the next dimension of the current sample, in [0, 1)
+    fn next_1d(&mut self) -> f32;
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let u = self.next_1d();
+        (u, self.next_1d())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+pub fn kind(name: &str) -> Option<Kind> {
+    match name {
+        "independent" => Some(Kind::Independent),
+        "stratified" => Some(Kind::Stratified),
+        "halton" => Some(Kind::Halton),
+        "sobol" => Some(Kind::Sobol),
+        _ => None,
+    }
+}
+
+// samples is how many will be taken per pixel; only the stratified sampler
+// needs it
+pub fn new(kind: Kind, seed: u64, samples: u32) -> Box<dyn Sampler> {
+    match kind {
+        Kind::Independent => Box::new(independent(seed)),
+        Kind::Stratified => Box::new(stratified(seed, samples)),
+        Kind::Halton => Box::new(halton(seed)),
+        Kind::Sobol => Box::new(sobol(seed)),
+    }
+}
+
+// splitmix64's finalizer
+pub fn hash(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+fn combine(a: u64, b: u64) -> u64 {
+    hash(a ^ b.wrapping_add(0x9E37_79B9_7F4A_7C15).wrapping_add(a << 6).wrapping_add(a >> 2))
+}
+
+fn pixel_seed(seed: u64, x: usize, y: usize) -> u64 {
+    combine(combine(seed, x as u64), y as u64)
+}
+
+// uniform in [0, 1) from the top 24 bits
+fn to_unit(h: u64) -> f32 {
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// largest f32 below one, so scrambled values never round up to 1
+const ONE_MINUS_EPSILON: f32 = 1.0 - std::f32::EPSILON / 2.0;
+
+pub struct Independent {
+    seed: u64,
+    rng: rand::rngs::SmallRng,
+}
+
+pub fn independent(seed: u64) -> Independent {
+    Independent{ seed, rng: rand::rngs::SmallRng::seed_from_u64(seed) }
+}
+
+impl Sampler for Independent {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.rng = rand::rngs::SmallRng::seed_from_u64(combine(pixel_seed(self.seed, x, y), index as u64));
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+// Jittered strata, shuffled independently per dimension so dimensions don't
+// line up with each other. 1D draws split [0, 1) into `samples` strata, 2D
+// draws use the largest square grid that fits; samples past those counts (ie
+// progressive passes) fall back to plain random numbers.
+pub struct Stratified {
+    seed: u64,
+    samples: u32,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+pub fn stratified(seed: u64, samples: u32) -> Stratified {
+    Stratified{ seed, samples: samples.max(1), pixel: 0, index: 0, dimension: 0 }
+}
+
+// Kensler's hash based permutation of 0..l, picked by p; see "Correlated
+// Multi-Jittered Sampling"
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+
+    ((i as u64 + p as u64) % l as u64) as u32
+}
+
+impl Stratified {
+    // hashes for the current dimension: one to pick its permutation, one for the jitter
+    fn hashes(&self) -> (u32, u64) {
+        let d = combine(self.pixel, self.dimension as u64);
+        (d as u32, combine(d, self.index as u64))
+    }
+}
+
+impl Sampler for Stratified {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_seed(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let (p, h) = self.hashes();
+        self.dimension += 1;
+
+        if self.index >= self.samples {
+            return to_unit(h);
+        }
+        let stratum = permute(self.index, self.samples, p);
+        ((stratum as f32 + to_unit(h)) / self.samples as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let (p, h) = self.hashes();
+        self.dimension += 2;
+
+        let n = (self.samples as f32).sqrt() as u32;
+        let (jx, jy) = (to_unit(h), to_unit(hash(h)));
+        if self.index >= n * n {
+            return (jx, jy);
+        }
+
+        let stratum = permute(self.index, n * n, p);
+        (
+            (((stratum % n) as f32 + jx) / n as f32).min(ONE_MINUS_EPSILON),
+            (((stratum / n) as f32 + jy) / n as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+// one base per dimension; dimensions past these are plain random numbers
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// Halton sequence, each dimension shifted by a random per pixel offset
+// (Cranley-Patterson rotation) so neighbouring pixels don't share a pattern.
+pub struct Halton {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+pub fn halton(seed: u64) -> Halton {
+    Halton{ seed, pixel: 0, index: 0, dimension: 0 }
+}
+
+// index's digits in base b, mirrored around the decimal point
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inv = 1.0 / base as f64;
+    let mut scale = inv;
+    let mut r = 0.0;
+    while index > 0 {
+        r += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inv;
+    }
+    r
+}
+
+impl Sampler for Halton {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_seed(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let d = self.dimension as usize;
+        let shift = combine(self.pixel, d as u64);
+        self.dimension += 1;
+
+        if d >= PRIMES.len() {
+            return to_unit(combine(shift, self.index as u64));
+        }
+        let v = radical_inverse(PRIMES[d], self.index) + to_unit(shift) as f64;
+        (v.fract() as f32).min(ONE_MINUS_EPSILON)
+    }
+}
+
+// Owen scrambled Sobol points, after Burley's "Practical Hash-based Owen
+// Scrambling": dimensions come in groups of four from the first four Sobol
+// dimensions, and each group gets its own shuffle of the sample order so
+// groups are decorrelated from each other.
+pub struct Sobol {
+    seed: u64,
+    // generator matrices of the first four dimensions, one column per bit
+    matrices: [[u32; 32]; 4],
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+// direction numbers from Joe and Kuo: (degree, coefficients, initial m) of the
+// primitive polynomials behind dimensions 2 to 4; dimension 1 is van der Corput
+const POLYNOMIALS: [(usize, u32, [u32; 3]); 3] = [
+    (1, 0, [1, 0, 0]),
+    (2, 1, [1, 3, 0]),
+    (3, 1, [1, 3, 1]),
+];
+
+pub fn sobol(seed: u64) -> Sobol {
+    let mut matrices = [[0u32; 32]; 4];
+
+    for (k, column) in matrices[0].iter_mut().enumerate() {
+        *column = 1 << (31 - k);
+    }
+
+    for (d, &(s, a, m)) in POLYNOMIALS.iter().enumerate() {
+        let v = &mut matrices[d + 1];
+        for k in 0..32 {
+            v[k] = if k < s {
+                m[k] << (31 - k)
+            } else {
+                let mut x = v[k - s] ^ (v[k - s] >> s);
+                for j in 1..s {
+                    if (a >> (s - 1 - j)) & 1 == 1 {
+                        x ^= v[k - j];
+                    }
+                }
+                x
+            };
+        }
+    }
+
+    Sobol{ seed, matrices, pixel: 0, index: 0, dimension: 0 }
+}
+
+fn laine_karras(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+impl Sobol {
+    // the unscrambled point's bits in one of the first four dimensions
+    fn point(&self, component: usize, index: u32) -> u32 {
+        let mut bits = 0;
+        for k in 0..32 {
+            if (index >> k) & 1 == 1 {
+                bits ^= self.matrices[component][k];
+            }
+        }
+        bits
+    }
+}
+
+impl Sampler for Sobol {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_seed(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let group = combine(self.pixel, (self.dimension / 4) as u64);
+        let component = (self.dimension % 4) as usize;
+        self.dimension += 1;
+
+        let index = nested_uniform_scramble(self.index, group as u32);
+        let bits = self.point(component, index);
+        let scrambled = nested_uniform_scramble(bits, combine(group, component as u64) as u32);
+        (scrambled as f32 / 4_294_967_296.0).min(ONE_MINUS_EPSILON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [Kind; 4] = [Kind::Independent, Kind::Stratified, Kind::Halton, Kind::Sobol];
+
+    #[test]
+    fn values_are_in_the_unit_interval() {
+        for &kind in KINDS.iter() {
+            let mut sampler = new(kind, 7, 16);
+            for index in 0..64 {
+                sampler.start(3, 5, index);
+                // past the Halton primes and the stratified sample count
+                for _ in 0..40 {
+                    let v = sampler.next_1d();
+                    assert!(v >= 0.0 && v < 1.0, "{}", v);
+                    let (u, v) = sampler.next_2d();
+                    assert!(u >= 0.0 && u < 1.0 && v >= 0.0 && v < 1.0, "{} {}", u, v);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_puts_one_sample_in_each_stratum() {
+        let mut sampler = stratified(7, 16);
+        let mut strata_1d = [0; 16];
+        let mut strata_2d = [0; 16];
+        for index in 0..16 {
+            sampler.start(3, 5, index);
+            strata_1d[(sampler.next_1d() * 16.0) as usize] += 1;
+            let (u, v) = sampler.next_2d();
+            strata_2d[(v * 4.0) as usize * 4 + (u * 4.0) as usize] += 1;
+        }
+        assert_eq!(strata_1d, [1; 16]);
+        assert_eq!(strata_2d, [1; 16]);
+    }
+
+    #[test]
+    fn first_points_are_the_known_sequences() {
+        let halton_2: std::vec::Vec<f64> = (0..8).map(|i| radical_inverse(2, i)).collect();
+        assert_eq!(halton_2, [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+        let halton_3: std::vec::Vec<f64> = (0..4).map(|i| radical_inverse(3, i) * 9.0).collect();
+        for (h, expected) in halton_3.iter().zip([0.0, 3.0, 6.0, 1.0].iter()) {
+            assert!((h - expected).abs() < 1e-9, "{} != {}", h, expected);
+        }
+
+        let sobol = sobol(0);
+        let first: std::vec::Vec<std::vec::Vec<f32>> = (0..3).map(|d| {
+            (0..8).map(|i| sobol.point(d, i) as f32 / 4_294_967_296.0).collect()
+        }).collect();
+        assert_eq!(first[0], [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+        assert_eq!(first[1], [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875]);
+        assert_eq!(first[2], [0.0, 0.5, 0.75, 0.25, 0.375, 0.875, 0.625, 0.125]);
+    }
+
+    #[test]
+    fn same_seed_pixel_and_index_give_the_same_values() {
+        for &kind in KINDS.iter() {
+            let draw = |sampler: &mut Box<dyn Sampler>| {
+                sampler.start(3, 5, 7);
+                (0..40).map(|_| sampler.next_1d()).collect::<std::vec::Vec<f32>>()
+            };
+
+            let mut a = new(kind, 7, 16);
+            let first = draw(&mut a);
+            // whatever ran in between
+            a.start(4, 5, 2);
+            a.next_2d();
+            assert_eq!(draw(&mut a), first);
+            assert_eq!(draw(&mut new(kind, 7, 16)), first);
+            assert_ne!(draw(&mut new(kind, 8, 16)), first);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use nalgebra as na;
+
+// Warps from the unit square onto the shapes the integrators sample, along
+// with the matching densities.
+
+pub fn concentric_disk(u: f32, v: f32) -> (f32, f32) {
+    let a = 2.0 * u - 1.0;
+    let b = 2.0 * v - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, std::f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+// local space, +z is the normal
+pub fn cosine_hemisphere(u: f32, v: f32) -> na::Vector3<f32> {
+    let (x, y) = concentric_disk(u, v);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    na::Vector3::new(x, y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) * std::f32::consts::FRAC_1_PI
+}
+
+pub fn uniform_sphere(u: f32, v: f32) -> na::Vector3<f32> {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * v;
+    na::Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub const UNIFORM_SPHERE_PDF: f32 = 1.0 / (4.0 * std::f32::consts::PI);
+
+// barycentric weights (b1, b2) for a uniform point on a triangle
+pub fn uniform_triangle(u: f32, v: f32) -> (f32, f32) {
+    let su = u.sqrt();
+    (v * su, 1.0 - su)
+}
+
+// Veach's power heuristic with beta = 2
+pub fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other * other;
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
+}
+
+// orthonormal basis around a unit normal (Duff et al. 2017)
+pub struct Frame {
+    pub s: na::Vector3<f32>,
+    pub t: na::Vector3<f32>,
+    pub n: na::Vector3<f32>,
+}
+
+pub fn frame(n: &na::Vector3<f32>) -> Frame {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    Frame{
+        s: na::Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        t: na::Vector3::new(b, sign + n.y * n.y * a, -n.y),
+        n: *n,
+    }
+}
+
+impl Frame {
+    pub fn to_local(&self, v: &na::Vector3<f32>) -> na::Vector3<f32> {
+        na::Vector3::new(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: &na::Vector3<f32>) -> na::Vector3<f32> {
+        self.s * v.x + self.t * v.y + self.n * v.z
+    }
+}
+
+// Piecewise constant density over [0, 1) proportional to func, sampled by
+// inverting its cdf.
+pub struct Distribution1d {
+    pub func: std::vec::Vec<f32>,
+    cdf: std::vec::Vec<f32>,
+    // of func over [0, 1)
+    pub integral: f32,
+}
+
+// an all zero func is treated as uniform
+pub fn distribution_1d(func: &[f32]) -> Distribution1d {
+    let n = func.len();
+    let mut cdf = vec![0.0; n + 1];
+    for i in 0..n {
+        cdf[i + 1] = cdf[i] + func[i].abs() / n as f32;
+    }
+
+    let integral = cdf[n];
+    for i in 1..=n {
+        cdf[i] = if integral > 0.0 { cdf[i] / integral } else { i as f32 / n as f32 };
+    }
+
+    Distribution1d{ func: func.iter().map(|f| f.abs()).collect(), cdf, integral }
+}
+
+impl Distribution1d {
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // returns the point, its density, and the segment it fell in
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        // last segment starting at or below u, which skips empty ones
+        let i = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(self.count() - 1);
+
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let t = if width > 0.0 { (u - self.cdf[i]) / width } else { 0.0 };
+        let x = ((i as f32 + t) / self.count() as f32).min(ONE_MINUS_EPSILON);
+        (x, self.pdf(i), i)
+    }
+
+    // density of segment i
+    pub fn pdf(&self, i: usize) -> f32 {
+        if self.integral > 0.0 { self.func[i] / self.integral } else { 1.0 }
+    }
+}
+
+// largest f32 below one
+const ONE_MINUS_EPSILON: f32 = 1.0 - std::f32::EPSILON / 2.0;
+
+// Piecewise constant density over [0, 1)^2, from a width x height grid (rows
+// of constant y): a row is picked by the marginal, then a column within it.
+pub struct Distribution2d {
+    rows: std::vec::Vec<Distribution1d>,
+    marginal: Distribution1d,
+}
+
+pub fn distribution_2d(func: &[f32], width: usize, height: usize) -> Distribution2d {
+    let rows: std::vec::Vec<Distribution1d> = (0..height).map(|y| distribution_1d(&func[y * width..(y + 1) * width])).collect();
+    let marginal = distribution_1d(&rows.iter().map(|r| r.integral).collect::<std::vec::Vec<f32>>());
+    Distribution2d{ rows, marginal }
+}
+
+impl Distribution2d {
+    pub fn sample(&self, u: f32, v: f32) -> (na::Point2<f32>, f32) {
+        let (y, pdf_y, row) = self.marginal.sample(v);
+        let (x, pdf_x, _) = self.rows[row].sample(u);
+        (na::Point2::new(x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, p: &na::Point2<f32>) -> f32 {
+        let row = ((p.y * self.marginal.count() as f32) as usize).min(self.marginal.count() - 1);
+        let r = &self.rows[row];
+        let column = ((p.x * r.count() as f32) as usize).min(r.count() - 1);
+        r.pdf(column) * self.marginal.pdf(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNC: [f32; 6] = [1.0, 0.0, 3.0, 0.5, 0.0, 2.0];
+
+    #[test]
+    fn distribution_1d_samples_come_with_their_pdf() {
+        let d = distribution_1d(&FUNC);
+        assert!((d.integral - 6.5 / 6.0).abs() < 1e-6);
+
+        for i in 0..1000 {
+            let u = (i as f32 + 0.5) / 1000.0;
+            let (x, pdf, segment) = d.sample(u);
+            assert!(x >= 0.0 && x < 1.0);
+            assert_eq!(segment, (x * FUNC.len() as f32) as usize);
+            assert!(FUNC[segment] > 0.0, "landed in empty segment {}", segment);
+            assert_eq!(pdf, d.pdf(segment));
+        }
+    }
+
+    #[test]
+    fn distribution_1d_is_proportional_to_func() {
+        let d = distribution_1d(&FUNC);
+        let n = 60000;
+        let mut counts = [0; 6];
+        for i in 0..n {
+            counts[d.sample((i as f32 + 0.5) / n as f32).2] += 1;
+        }
+
+        let total: f32 = FUNC.iter().sum();
+        for (count, f) in counts.iter().zip(FUNC.iter()) {
+            assert!((*count as f32 / n as f32 - f / total).abs() < 1e-3, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn all_zero_distribution_is_uniform() {
+        let d = distribution_1d(&[0.0; 4]);
+        let (x, pdf, segment) = d.sample(0.6);
+        assert!((x - 0.6).abs() < 1e-6);
+        assert_eq!((pdf, segment), (1.0, 2));
+    }
+
+    #[test]
+    fn distribution_2d_samples_come_with_their_pdf() {
+        // 3 x 2, one empty row and one empty cell
+        let func = [1.0, 2.0, 3.0, 0.0, 4.0, 0.0];
+        let d = distribution_2d(&func, 3, 2);
+
+        let n = 200;
+        let mut counts = [0; 6];
+        for i in 0..n {
+            for j in 0..n {
+                let (p, pdf) = d.sample((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                assert!((pdf - d.pdf(&p)).abs() < 1e-5, "{} != {}", pdf, d.pdf(&p));
+                counts[(p.y * 2.0) as usize * 3 + (p.x * 3.0) as usize] += 1;
+            }
+        }
+
+        // the density is func over its integral, ie each cell gets func / sum of it
+        let total: f32 = func.iter().sum();
+        for (count, f) in counts.iter().zip(func.iter()) {
+            assert!((*count as f32 / (n * n) as f32 - f / total).abs() < 2e-3, "{:?}", counts);
+        }
+        assert!((d.pdf(&na::Point2::new(0.5, 0.75)) - 4.0 / (total / 6.0)).abs() < 1e-4);
+    }
+}
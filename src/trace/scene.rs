@@ -0,0 +1,314 @@
+use nalgebra as na;
+use crate::trace::accel;
+
+// What the tracer sees of the cubes and solids: one instance per cube, then one
+// per solid, each with its material. Cubes drawn from the same registered
+// geometry share one mesh bvh. Built once, then kept in step with the
+// simulation via update.
+pub struct Scene {
+    pub accel: accel::Accel,
+    // per instance
+    pub materials: std::vec::Vec<crate::gfx::material::Material>,
+    // the renderer's scale, which procedural patterns are evaluated after
+    pub scales: std::vec::Vec<na::Vector3<f32>>,
+    // per mesh in accel.meshes
+    pub uvs: std::vec::Vec<crate::gfx::Uvs>,
+    // each cube's Cube::id, then each solid's Solid::id
+    pub groups: std::vec::Vec<i32>,
+    // radiance arriving from every direction that misses the scene, unless
+    // there's an environment
+    pub sky: crate::gfx::Color,
+    pub environment: Option<crate::trace::environment::Light>,
+    // every instance with an emissive material
+    pub emitters: std::vec::Vec<Emitter>,
+    pub lights: std::vec::Vec<crate::gfx::light::Light>,
+    // fog and smoke, which only the path tracer sees
+    pub media: std::vec::Vec<crate::trace::medium::Medium>,
+}
+
+// below this a metal is treated as a perfect mirror
+const MIRROR_ROUGHNESS: f32 = 1e-3;
+
+// an instance that gives off light from every point of its surface
+pub struct Emitter {
+    pub instance: usize,
+    pub radiance: crate::gfx::Color,
+    // running total of the world space triangle areas, in the mesh bvh's order;
+    // just the whole area for a primitive
+    cdf: std::vec::Vec<f32>,
+}
+
+impl Emitter {
+    pub fn area(&self) -> f32 {
+        self.cdf.last().cloned().unwrap_or(0.0)
+    }
+}
+
+pub fn new(registry: &crate::gfx::geometry::Registry, cubes: &[crate::shapes::cube::Cube], solids: &[crate::shapes::solid::Solid]) -> Result<Scene, std::string::String> {
+    let mut accel = accel::new();
+    let mut uvs: std::vec::Vec<crate::gfx::Uvs> = vec![];
+    // accel mesh of each geometry
+    let mut meshes: std::collections::HashMap<crate::gfx::geometry::Handle, usize> = std::collections::HashMap::new();
+
+    for c in cubes.iter() {
+        let mesh = *meshes.entry(c.gfx.geometry).or_insert_with(|| {
+            let g = registry.get(c.gfx.geometry);
+            uvs.push(g.uvs.clone());
+            accel.add_mesh(&g.mesh)
+        });
+        let instance = accel.add_instance(mesh, c.gfx.mat_model(&c.phys));
+        accel.set_motion(instance, motion(c));
+    }
+
+    // traced exactly, their tessellation is only for GL
+    for s in solids.iter() {
+        // emitter areas and sampling assume a uniform scale; a stretched sphere
+        // has no closed form area
+        let k = s.gfx.scale;
+        if (k.x - k.y).abs() > 1e-4 * k.x.abs() || (k.x - k.z).abs() > 1e-4 * k.x.abs() {
+            return Err(format!("solid {} scaled non-uniformly: {:?}", s.id, k));
+        }
+        accel.add_primitive(s.primitive, s.gfx.mat_model(&s.phys));
+    }
+
+    accel.build();
+
+    let mut materials: std::vec::Vec<crate::gfx::material::Material> = cubes.iter().map(|c| c.gfx.material.clone()).collect();
+    materials.extend(solids.iter().map(|s| s.gfx.material.clone()));
+    let mut scales: std::vec::Vec<na::Vector3<f32>> = cubes.iter().map(|c| c.gfx.scale).collect();
+    scales.extend(solids.iter().map(|s| s.gfx.scale));
+    let mut groups: std::vec::Vec<i32> = cubes.iter().map(|c| c.id).collect();
+    groups.extend(solids.iter().map(|s| s.id));
+
+    let mut scene = Scene{
+        accel,
+        materials,
+        scales,
+        uvs,
+        groups,
+        sky: crate::trace::BACKGROUND,
+        environment: None,
+        emitters: vec![],
+        lights: vec![],
+        media: vec![],
+    };
+
+    for i in 0..scene.materials.len() {
+        scene.set_material(i, scene.materials[i].clone());
+    }
+
+    Ok(scene)
+}
+
+// the cube moves on with its current velocities, for motion blur
+fn motion(cube: &crate::shapes::cube::Cube) -> accel::Motion {
+    accel::Motion{ phys: cube.phys, local: cube.gfx.mat_scale() }
+}
+
+impl Scene {
+    // cubes must be the same list, in the same order, the scene was built from;
+    // solids stay where they were
+    pub fn update(&mut self, cubes: &[crate::shapes::cube::Cube]) {
+        for (i, c) in cubes.iter().enumerate() {
+            self.accel.set_motion(i, motion(c));
+        }
+        self.accel.update();
+
+        for i in 0..self.emitters.len() {
+            self.emitters[i].cdf = self.areas(self.emitters[i].instance);
+        }
+    }
+
+    // the interval camera rays are cast over, see gfx::camera::Camera::shutter_open
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.accel.set_shutter(open, close);
+        self.accel.update();
+    }
+
+    // overrides the material the instance was built with, just for the tracer
+    pub fn set_material(&mut self, instance: usize, material: crate::gfx::material::Material) {
+        let radiance = material.emission();
+        self.materials[instance] = material;
+
+        self.emitters.retain(|e| e.instance != instance);
+        if radiance.iter().any(|&c| c > 0.0) {
+            let cdf = self.areas(instance);
+            self.emitters.push(Emitter{ instance, radiance, cdf });
+        }
+    }
+
+    // tabulates the environment for importance sampling, so it's best set once
+    pub fn set_environment(&mut self, environment: Option<std::sync::Arc<crate::gfx::environment::Environment>>) {
+        self.environment = environment.map(crate::trace::environment::new);
+    }
+
+    // what a ray leaving the scene along dir sees
+    pub fn background(&self, dir: &na::Vector3<f32>) -> na::Vector3<f32> {
+        match &self.environment {
+            Some(e) => e.radiance(dir),
+            None => crate::trace::rgb(self.sky),
+        }
+    }
+
+    // average of the background over all directions
+    pub fn ambient(&self) -> na::Vector3<f32> {
+        match &self.environment {
+            Some(e) => e.average,
+            None => crate::trace::rgb(self.sky),
+        }
+    }
+
+    pub fn emitter(&self, instance: usize) -> Option<&Emitter> {
+        self.emitters.iter().find(|e| e.instance == instance)
+    }
+
+    fn areas(&self, instance: usize) -> std::vec::Vec<f32> {
+        let i = &self.accel.instances[instance];
+        let mesh = match i.shape {
+            accel::Shape::Mesh(mesh) => mesh,
+            accel::Shape::Primitive(p) => {
+                // new checks solids are scaled uniformly, so areas go with its square
+                let scale = i.transform.model.transform_vector(&na::Vector3::x()).magnitude();
+                return vec![p.area() * scale * scale];
+            },
+        };
+        let mut total = 0.0;
+
+        self.accel.meshes[mesh].triangles.iter().map(|t| {
+            let a = i.transform.point_to_world(&t[0]);
+            let b = i.transform.point_to_world(&t[1]);
+            let c = i.transform.point_to_world(&t[2]);
+            total += 0.5 * (b - a).cross(&(c - a)).magnitude();
+            total
+        }).collect()
+    }
+
+    // uniform point by area on an emitter, in its pose at the given time;
+    // returns the point and its normal. Moving emitters only turn and
+    // translate, so the areas summed at time 0 still hold
+    pub fn sample_emitter(&self, emitter: &Emitter, u: f32, v: f32, w: f32, time: f32) -> (na::Point3<f32>, na::Vector3<f32>) {
+        let i = &self.accel.instances[emitter.instance];
+        let model = self.accel.model(emitter.instance, time);
+        let mesh = match i.shape {
+            accel::Shape::Mesh(mesh) => mesh,
+            accel::Shape::Primitive(p) => {
+                let (q, n) = p.sample(u, v);
+                let moved;
+                let transform = match i.motion {
+                    Some(_) if time != 0.0 => {
+                        moved = crate::gfx::ray::transform(model);
+                        &moved
+                    },
+                    _ => &i.transform,
+                };
+                return (transform.point_to_world(&q), transform.normal_to_world(&n));
+            },
+        };
+
+        let target = u * emitter.area();
+        let k = match emitter.cdf.binary_search_by(|a| a.partial_cmp(&target).unwrap_or(std::cmp::Ordering::Less)) {
+            Ok(k) | Err(k) => k.min(emitter.cdf.len() - 1),
+        };
+
+        let t = &self.accel.meshes[mesh].triangles[k];
+        let a = model.transform_point(&t[0]);
+        let b = model.transform_point(&t[1]);
+        let c = model.transform_point(&t[2]);
+
+        let (b1, b2) = crate::trace::sampling::uniform_triangle(v, w);
+        let p = a + (b - a) * b1 + (c - a) * b2;
+        (p, (b - a).cross(&(c - a)).normalize())
+    }
+
+    // nearest area light the ray crosses before ray.t_max; area lights only
+    // emit, they don't occlude or reflect
+    pub fn hit_light(&self, ray: &crate::gfx::ray::Ray) -> Option<(usize, f32)> {
+        let mut ray = *ray;
+        let mut closest = None;
+        for (i, l) in self.lights.iter().enumerate() {
+            if let Some(t) = l.intersect(&ray) {
+                ray.t_max = t;
+                closest = Some((i, t));
+            }
+        }
+        closest
+    }
+
+    // whether anything blocks the segment from p (on a surface with normal n)
+    // to dist along wi, at the given time
+    pub fn occluded(&self, p: &na::Point3<f32>, n: &na::Vector3<f32>, wi: &na::Vector3<f32>, dist: f32, time: f32) -> bool {
+        let mut shadow = crate::gfx::ray::new(crate::trace::spawn(p, n, wi), *wi);
+        shadow.time = time;
+        // stop just short of the light so an emitter doesn't occlude itself
+        shadow.t_max = dist * (1.0 - 1e-3);
+        self.accel.any_hit(&shadow)
+    }
+
+    // fraction of light getting from p to dist along wi through the media;
+    // 0 if anything blocks it. n is zero for a point in a medium
+    pub fn transmittance<R: rand::Rng>(&self, p: &na::Point3<f32>, n: &na::Vector3<f32>, wi: &na::Vector3<f32>, dist: f32, time: f32, rng: &mut R) -> f32 {
+        if self.occluded(p, n, wi, dist, time) {
+            return 0.0;
+        }
+        if self.media.is_empty() {
+            return 1.0;
+        }
+
+        let mut segment = crate::gfx::ray::new(crate::trace::spawn(p, n, wi), *wi);
+        segment.t_max = dist;
+        crate::trace::medium::transmittance(&self.media, &segment, rng)
+    }
+
+    // entering is whether the ray arrived from outside the surface (against its
+    // normal); with a wavelength, colors and the index of refraction are taken
+    // at it (see spectrum::lift)
+    pub fn bsdf(&self, instance: usize, hit: &crate::gfx::ray::Hit, entering: bool, wavelength: Option<f32>) -> crate::trace::bsdf::Bsdf {
+        use crate::gfx::material::Material;
+        use crate::trace::bsdf::Bsdf;
+
+        let color = || crate::trace::spectrum::lift(&crate::trace::rgb(self.color(instance, hit)), wavelength);
+        match self.materials[instance] {
+            Material::Lambertian{ .. } => Bsdf::Lambert{ albedo: color() },
+            Material::Metal{ roughness, .. } if roughness < MIRROR_ROUGHNESS => Bsdf::Mirror{ color: color() },
+            Material::Metal{ roughness, .. } => Bsdf::Metal{ color: color(), alpha: roughness * roughness },
+            Material::Dielectric{ ior } => {
+                let n = wavelength.map(|l| ior.at(l)).unwrap_or_else(|| ior.d());
+                Bsdf::Glass{ eta: if entering { n } else { 1.0 / n } }
+            },
+            // emitters don't reflect anything
+            Material::Emissive{ .. } => Bsdf::Lambert{ albedo: na::Vector3::zeros() },
+        }
+    }
+
+    // the material's albedo interpolated across the triangle, looked up at the
+    // interpolated uv (or the primitive's own), or evaluated at the hit point;
+    // vertices are numbered the same way the registry's vertex buffers number them
+    pub fn color(&self, instance: usize, hit: &crate::gfx::ray::Hit) -> crate::gfx::Color {
+        let albedo = self.materials[instance].albedo();
+        let w = 1.0 - hit.u - hit.v;
+
+        if let Some(texture) = albedo.texture() {
+            let p = match (hit.uv, self.accel.instances[instance].shape) {
+                (Some(uv), _) => uv,
+                (None, accel::Shape::Mesh(mesh)) => {
+                    let uv = &self.uvs[mesh][hit.triangle];
+                    na::Point2::from(uv[0].coords * w + uv[1].coords * hit.u + uv[2].coords * hit.v)
+                },
+                (None, accel::Shape::Primitive(_)) => na::Point2::origin(),
+            };
+            return texture.sample(&p);
+        }
+        if let Some(pattern) = albedo.pattern() {
+            return pattern.eval(&na::Point3::from(hit.local.coords.component_mul(&self.scales[instance])));
+        }
+
+        let first = hit.triangle as i32 * 3;
+        let c = [albedo.at(first), albedo.at(first + 1), albedo.at(first + 2)];
+
+        let mut interpolated = [0.0; 3];
+        for k in 0..3 {
+            interpolated[k] = c[0][k] * w + c[1][k] * hit.u + c[2][k] * hit.v;
+        }
+        interpolated
+    }
+}
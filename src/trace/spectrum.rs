@@ -0,0 +1,142 @@
+use nalgebra as na;
+
+// Spectral rendering: each path carries a single wavelength instead of red,
+// green and blue, which lets glass bend every wavelength by its own index of
+// refraction and split white light into colors. RGB albedos and emitters are
+// turned into spectra on the fly (Smits' method), and what a path brings back
+// is turned into RGB again through the CIE matching functions.
+
+// the visible range wavelengths are drawn from, in nanometers
+pub const MIN: f32 = 380.0;
+pub const MAX: f32 = 720.0;
+
+// Smits' spectra for the RGB primaries and their complements, in ten equal bins
+// over MIN..MAX; mixing them reproduces any RGB color with a smooth spectrum
+const WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// linear sRGB from CIE XYZ, D65 white
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266, 1.876_010_8, 0.041_556],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2],
+];
+
+// XYZ_TO_RGB * xyz integrated over MIN..MAX: the RGB of a spectrum that's 1 at
+// every wavelength. Dividing by it white balances, so a white (1, 1, 1) surface
+// or emitter comes out white, just like the RGB tracer
+const WHITE_RGB: [f32; 3] = [128.359, 101.528, 97.066];
+
+// a wavelength for u in [0, 1), and its density; uniform over the visible range
+pub fn sample_wavelength(u: f32) -> (f32, f32) {
+    (MIN + (MAX - MIN) * u, 1.0 / (MAX - MIN))
+}
+
+// a lobe of Wyman, Sloan and Shirley's fit, wider on one side than the other
+fn lobe(wavelength: f32, mean: f32, below: f32, above: f32) -> f32 {
+    let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+    (-0.5 * t * t).exp()
+}
+
+// the CIE 1931 color matching functions, fit by a sum of lobes
+pub fn xyz(wavelength: f32) -> na::Vector3<f32> {
+    let l = wavelength;
+    na::Vector3::new(
+        1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7) - 0.065 * lobe(l, 501.1, 20.4, 26.2),
+        0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1),
+        1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8),
+    )
+}
+
+// one of Smits' spectra, interpolated between bin centers
+fn bin(table: &[f32; 10], wavelength: f32) -> f32 {
+    let n = table.len();
+    let x = ((wavelength - MIN) / (MAX - MIN) * n as f32 - 0.5).clamp(0.0, (n - 1) as f32);
+    let i = (x as usize).min(n - 2);
+    table[i] + (table[i + 1] - table[i]) * (x - i as f32)
+}
+
+// value at the wavelength of a smooth spectrum with the given RGB color: white
+// for the smallest channel, plus the complement for the middle one, plus the
+// primary for the largest
+pub fn upsample(c: &na::Vector3<f32>, wavelength: f32) -> f32 {
+    let (r, g, b) = (c.x, c.y, c.z);
+    let at = |table: &[f32; 10]| bin(table, wavelength);
+
+    if r <= g && r <= b {
+        r * at(&WHITE) + if g <= b {
+            (g - r) * at(&CYAN) + (b - g) * at(&BLUE)
+        } else {
+            (b - r) * at(&CYAN) + (g - b) * at(&GREEN)
+        }
+    } else if g <= r && g <= b {
+        g * at(&WHITE) + if r <= b {
+            (r - g) * at(&MAGENTA) + (b - r) * at(&BLUE)
+        } else {
+            (b - g) * at(&MAGENTA) + (r - b) * at(&RED)
+        }
+    } else {
+        b * at(&WHITE) + if r <= g {
+            (r - b) * at(&YELLOW) + (g - r) * at(&GREEN)
+        } else {
+            (g - b) * at(&YELLOW) + (r - g) * at(&RED)
+        }
+    }
+}
+
+// An RGB color as the path tracer should see it: unchanged without a
+// wavelength, otherwise its spectrum's value at the wavelength, in every channel
+pub fn lift(c: &na::Vector3<f32>, wavelength: Option<f32>) -> na::Vector3<f32> {
+    match wavelength {
+        Some(l) => {
+            let v = upsample(c, l);
+            na::Vector3::new(v, v, v)
+        },
+        None => *c,
+    }
+}
+
+// linear RGB contributed by radiance at one wavelength, drawn with density pdf.
+// Averaged over many wavelengths this converges to the spectrum's color; a
+// single one can come out negative in a channel, since no RGB color is as
+// saturated as a pure wavelength
+pub fn to_rgb(radiance: f32, wavelength: f32, pdf: f32) -> na::Vector3<f32> {
+    let xyz = xyz(wavelength) * (radiance / pdf);
+    let m = &XYZ_TO_RGB;
+    na::Vector3::new(
+        (m[0][0] * xyz.x + m[0][1] * xyz.y + m[0][2] * xyz.z) / WHITE_RGB[0],
+        (m[1][0] * xyz.x + m[1][1] * xyz.y + m[1][2] * xyz.z) / WHITE_RGB[1],
+        (m[2][0] * xyz.x + m[2][1] * xyz.y + m[2][2] * xyz.z) / WHITE_RGB[2],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn white_upsamples_to_one_everywhere() {
+        let white = na::Vector3::new(1.0, 1.0, 1.0);
+        for i in 0..=100 {
+            let l = MIN + (MAX - MIN) * i as f32 / 100.0;
+            assert!((upsample(&white, l) - 1.0).abs() < 1e-3, "{} at {}", upsample(&white, l), l);
+        }
+    }
+
+    #[test]
+    fn white_averages_back_to_white() {
+        let n = 10000;
+        let mut sum = na::Vector3::zeros();
+        for i in 0..n {
+            let (l, pdf) = sample_wavelength((i as f32 + 0.5) / n as f32);
+            sum += to_rgb(upsample(&na::Vector3::new(1.0, 1.0, 1.0), l), l, pdf);
+        }
+        let rgb = sum / n as f32;
+        assert!((rgb - na::Vector3::new(1.0, 1.0, 1.0)).amax() < 0.01, "{:?}", rgb);
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Splits a frame into square tiles and renders them on every core. Each thread
+// starts with its own share of the tiles and steals from the others once it
+// runs out, so a few expensive tiles (glass, lights) don't leave cores idle.
+// Samples only depend on their pixel and the seed (see trace::sample), and tiles
+// are combined in a fixed order, so the image doesn't change with the thread
+// count or the order tiles finish in.
+
+pub const TILE_SIZE: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct Tile {
+    // position in tiles(), row major
+    pub index: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// the edge tiles are cut short to fit
+pub fn tiles(width: usize, height: usize, size: usize) -> std::vec::Vec<Tile> {
+    let size = size.max(1);
+    let mut v = vec![];
+
+    for y in (0..height).step_by(size) {
+        for x in (0..width).step_by(size) {
+            v.push(Tile{
+                index: v.len(),
+                x,
+                y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            });
+        }
+    }
+
+    v
+}
+
+// shared between whoever starts a render and whoever wants to stop it, ie when
+// the camera moves
+#[derive(Clone)]
+pub struct Cancel {
+    flag: std::sync::Arc<AtomicBool>,
+}
+
+pub fn cancel() -> Cancel {
+    Cancel{ flag: std::sync::Arc::new(AtomicBool::new(false)) }
+}
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+// passed to the progress callback as each tile finishes
+pub struct Progress {
+    pub tile: Tile,
+    // tiles finished so far, including this one
+    pub done: usize,
+    pub total: usize,
+}
+
+pub struct Scheduler {
+    pub threads: usize,
+    pub tile_size: usize,
+}
+
+// one thread per core
+pub fn new() -> Scheduler {
+    Scheduler{
+        threads: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        tile_size: TILE_SIZE,
+    }
+}
+
+// next tile for worker `id`: the front of its own queue, or else the back of
+// someone else's
+fn next(queues: &[std::sync::Mutex<std::collections::VecDeque<Tile>>], id: usize) -> Option<Tile> {
+    if let Some(t) = queues[id].lock().unwrap().pop_front() {
+        return Some(t);
+    }
+
+    for k in 1..queues.len() {
+        if let Some(t) = queues[(id + k) % queues.len()].lock().unwrap().pop_back() {
+            return Some(t);
+        }
+    }
+
+    None
+}
+
+impl Scheduler {
+    // calls work for every tile and returns the results in tile order, or None
+    // if the render was cancelled before it finished; work should check cancel
+    // itself if its tiles take a while. progress is called from the worker threads
+    pub fn run<T, F, P>(&self, width: usize, height: usize, cancel: &Cancel, progress: P, work: F) -> Option<std::vec::Vec<T>>
+    where
+        T: Send,
+        F: Fn(&Tile) -> T + Sync,
+        P: Fn(&Progress) + Sync,
+    {
+        let tiles = tiles(width, height, self.tile_size);
+        let threads = self.threads.max(1).min(tiles.len().max(1));
+
+        // dealt round robin, so neighbouring (similarly expensive) tiles start
+        // out on different threads
+        let queues: std::vec::Vec<_> = (0..threads).map(|_| std::sync::Mutex::new(std::collections::VecDeque::new())).collect();
+        for (i, t) in tiles.iter().enumerate() {
+            queues[i % threads].lock().unwrap().push_back(*t);
+        }
+
+        let done = AtomicUsize::new(0);
+        let total = tiles.len();
+
+        let mut finished: std::vec::Vec<(usize, T)> = std::thread::scope(|s| {
+            let workers: std::vec::Vec<_> = (0..threads).map(|id| {
+                let (queues, done, work, progress) = (&queues, &done, &work, &progress);
+
+                s.spawn(move || {
+                    let mut rendered = vec![];
+
+                    while let Some(tile) = next(queues, id) {
+                        if cancel.is_cancelled() {
+                            break;
+                        }
+                        let result = work(&tile);
+
+                        let d = done.fetch_add(1, Ordering::Relaxed) + 1;
+                        progress(&Progress{ tile, done: d, total });
+                        rendered.push((tile.index, result));
+                    }
+
+                    rendered
+                })
+            }).collect();
+
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+        });
+
+        if cancel.is_cancelled() {
+            return None;
+        }
+
+        // whichever thread rendered them, callers combine tiles in the same order
+        finished.sort_by_key(|(index, _)| *index);
+        Some(finished.into_iter().map(|(_, result)| result).collect())
+    }
+
+}
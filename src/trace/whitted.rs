@@ -0,0 +1,196 @@
+use nalgebra as na;
+use crate::trace::bsdf;
+use crate::trace::sampling;
+use crate::trace::scene;
+
+// Whitted style recursive tracer: no randomness, so one sample per pixel gives a
+// noise free preview. Diffuse surfaces see each light as a point (hard shadows),
+// area lights through a fixed grid of points (soft shadows), plus the sky as
+// flat ambient; mirrors and glass recurse until depth runs out. Fog and smoke
+// are left to the path tracer.
+
+// area lights are sampled at the centers of an AREA_GRID x AREA_GRID grid
+const AREA_GRID: usize = 4;
+
+// light arriving at p from the emitter, treating it as an isotropic point at
+// its center at the given time. A lambertian emitter gives off pi * radiance *
+// area in total.
+fn emitter_light(scene: &scene::Scene, e: &scene::Emitter, p: &na::Point3<f32>, n: &na::Vector3<f32>, time: f32) -> na::Vector3<f32> {
+    let bounds = scene.accel.shape_bounds(&scene.accel.instances[e.instance].shape);
+    let center = scene.accel.model(e.instance, time).transform_point(&bounds.centroid());
+    let d = center - p;
+    let dist2 = d.magnitude_squared();
+    let wi = d / dist2.sqrt();
+
+    let cos = n.dot(&wi);
+    if cos <= 0.0 {
+        return na::Vector3::zeros();
+    }
+
+    // the center is inside the emitter, so it's visible if the emitter is the first thing hit
+    let mut ray = crate::gfx::ray::new(crate::trace::spawn(p, n, &wi), wi);
+    ray.time = time;
+    match scene.accel.closest_hit(&ray) {
+        Some((instance, _)) if instance == e.instance => {
+            let intensity = crate::trace::rgb(e.radiance) * (e.area() / 4.0);
+            intensity * (cos / dist2)
+        },
+        _ => na::Vector3::zeros(),
+    }
+}
+
+// irradiance at p from one of the scene lights
+fn light(scene: &scene::Scene, light: &crate::gfx::light::Light, p: &na::Point3<f32>, n: &na::Vector3<f32>, time: f32) -> na::Vector3<f32> {
+    let (grid, weight) = if light.is_delta() { (1, 1.0) } else { (AREA_GRID, 1.0 / (AREA_GRID * AREA_GRID) as f32) };
+    let mut e = na::Vector3::zeros();
+
+    for i in 0..grid {
+        for j in 0..grid {
+            let u = (i as f32 + 0.5) / grid as f32;
+            let v = (j as f32 + 0.5) / grid as f32;
+
+            if let Some(incident) = light.sample(p, u, v) {
+                let cos = n.dot(&incident.wi);
+                if cos > 0.0 && !scene.occluded(p, n, &incident.wi, incident.dist, time) {
+                    e += incident.radiance * (cos * weight / incident.pdf);
+                }
+            }
+        }
+    }
+
+    e
+}
+
+pub fn radiance(scene: &scene::Scene, ray: &crate::gfx::ray::Ray, depth: u32) -> na::Vector3<f32> {
+    let closest = scene.accel.closest_hit(ray);
+
+    let mut to_surface = *ray;
+    to_surface.t_max = closest.as_ref().map(|(_, h)| h.t).unwrap_or(std::f32::INFINITY);
+    if let Some((i, _)) = scene.hit_light(&to_surface) {
+        return scene.lights[i].power();
+    }
+
+    let (instance, hit) = match closest {
+        Some(h) => h,
+        None => return scene.background(&ray.dir.normalize()),
+    };
+
+    if let Some(e) = scene.emitter(instance) {
+        return crate::trace::rgb(e.radiance);
+    }
+
+    let p = ray.at(hit.t);
+    let entering = hit.normal.dot(&ray.dir) < 0.0;
+    let n = if entering { hit.normal } else { -hit.normal };
+    let frame = sampling::frame(&n);
+    let wo = frame.to_local(&-ray.dir.normalize());
+
+    // continues the recursion along a local direction
+    let follow = |wi: &na::Vector3<f32>| {
+        if depth == 0 {
+            return na::Vector3::zeros();
+        }
+        let dir = frame.to_world(wi);
+        radiance(scene, &ray.secondary(crate::trace::spawn(&p, &n, &dir), dir), depth - 1)
+    };
+
+    match scene.bsdf(instance, &hit, entering, None) {
+        bsdf::Bsdf::Lambert{ albedo } => {
+            let mut l = albedo.component_mul(&scene.ambient());
+            for e in scene.emitters.iter() {
+                l += albedo.component_mul(&emitter_light(scene, e, &p, &n, ray.time)) * std::f32::consts::FRAC_1_PI;
+            }
+            for li in scene.lights.iter() {
+                l += albedo.component_mul(&light(scene, li, &p, &n, ray.time)) * std::f32::consts::FRAC_1_PI;
+            }
+            l
+        },
+        // rough metals preview as mirrors
+        bsdf::Bsdf::Mirror{ color } | bsdf::Bsdf::Metal{ color, .. } => color.component_mul(&follow(&bsdf::reflect(&wo))),
+        bsdf::Bsdf::Glass{ eta } => {
+            let f = bsdf::fresnel(wo.z, eta);
+            let reflected = follow(&bsdf::reflect(&wo)) * f;
+
+            match bsdf::refract(&wo, eta) {
+                Some(wi) => reflected + follow(&wi) * ((1.0 - f) / (eta * eta)),
+                None => reflected,
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::material::{Albedo, Ior, Material};
+
+    const WHITE: Material = Material::Lambertian{ albedo: Albedo::Solid([1.0, 1.0, 1.0]) };
+
+    fn cube(registry: &mut crate::gfx::geometry::Registry, at: (f32, f32, f32), size: (f32, f32, f32), material: Material) -> crate::shapes::cube::Cube {
+        crate::shapes::cube::new(registry, 0, at.0, at.1, at.2, size.0, size.1, size.2, material)
+    }
+
+    fn sky(scene: &scene::Scene) -> na::Vector3<f32> {
+        crate::trace::rgb(scene.sky)
+    }
+
+    fn assert_close(a: &na::Vector3<f32>, b: &na::Vector3<f32>) {
+        assert!((a - b).amax() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn misses_see_the_sky() {
+        let mut registry = crate::gfx::geometry::new();
+        let cubes = [cube(&mut registry, (0.0, 0.0, 0.0), (10.0, 10.0, 10.0), WHITE)];
+        let scene = scene::new(&registry, &cubes, &[]).unwrap();
+        let ray = crate::gfx::ray::new(na::Point3::new(0.0, 0.0, -50.0), -na::Vector3::z());
+        assert_eq!(radiance(&scene, &ray, 4), sky(&scene));
+    }
+
+    #[test]
+    fn mirrors_reflect_tinted_until_depth_runs_out() {
+        let mirror = Material::Metal{ albedo: Albedo::Solid([1.0, 0.0, 0.0]), roughness: 0.0 };
+        let mut registry = crate::gfx::geometry::new();
+        let cubes = [cube(&mut registry, (0.0, 0.0, 0.0), (10.0, 10.0, 10.0), mirror)];
+        let scene = scene::new(&registry, &cubes, &[]).unwrap();
+
+        // straight back out, off center so it doesn't graze the faces' diagonals
+        let ray = crate::gfx::ray::new(na::Point3::new(1.0, 2.0, -50.0), na::Vector3::z());
+        let s = sky(&scene);
+        assert_close(&radiance(&scene, &ray, 1), &na::Vector3::new(s.x, 0.0, 0.0));
+        assert_eq!(radiance(&scene, &ray, 0), na::Vector3::zeros());
+    }
+
+    #[test]
+    fn glass_passes_the_sky_through_at_normal_incidence() {
+        let mut registry = crate::gfx::geometry::new();
+        let cubes = [cube(&mut registry, (0.0, 0.0, 0.0), (10.0, 10.0, 10.0), Material::Dielectric{ ior: Ior::Constant(1.5) })];
+        let scene = scene::new(&registry, &cubes, &[]).unwrap();
+
+        // reflected and transmitted parts add back up to all of it, since the
+        // sky is the same on both sides
+        let ray = crate::gfx::ray::new(na::Point3::new(1.0, 2.0, -50.0), na::Vector3::z());
+        assert_close(&radiance(&scene, &ray, 8), &sky(&scene));
+    }
+
+    #[test]
+    fn blockers_cast_hard_shadows() {
+        let emissive = Material::Emissive{ color: [1.0, 1.0, 1.0], strength: 100.0 };
+        let mut registry = crate::gfx::geometry::new();
+        let floor = cube(&mut registry, (0.0, -1.0, 0.0), (100.0, 2.0, 100.0), WHITE);
+        let emitter = cube(&mut registry, (0.0, 40.0, 0.0), (4.0, 4.0, 4.0), emissive);
+        let blocker = cube(&mut registry, (0.0, 20.0, 0.0), (20.0, 2.0, 20.0), WHITE);
+
+        // looking straight down at the floor from under the blocker
+        let ray = crate::gfx::ray::new(na::Point3::new(1.0, 10.0, 2.0), -na::Vector3::y());
+
+        let mut cubes = vec![floor, emitter];
+        let lit = scene::new(&registry, &cubes, &[]).unwrap();
+        let ambient = sky(&lit);
+        assert!(radiance(&lit, &ray, 4).x > ambient.x + 0.1);
+
+        cubes.push(blocker);
+        let shadowed = scene::new(&registry, &cubes, &[]).unwrap();
+        assert_close(&radiance(&shadowed, &ray, 4), &ambient);
+    }
+}